@@ -1,13 +1,52 @@
-use std::{path::PathBuf, sync::LazyLock};
+//! # Configuration
+//! The server is configured by `orpheus.toml`, the first of these that is set
+//! or exists:
+//! 1. the path given with `--config <path>`, see [set_path],
+//! 2. the path in the `ORPHEUS_CONFIG` environment variable,
+//! 3. `orpheus/orpheus.toml` in the user's config directory
+//!    (`$XDG_CONFIG_HOME`, usually `~/.config`),
+//! 4. `orpheus/orpheus.toml` in each of `$XDG_CONFIG_DIRS` (`/etc/xdg`),
+//! 5. `orpheus.toml` in the current working directory.
+//!
+//! Any key can then be overridden with an environment variable named after
+//! its path, in upper case, with sections separated by `__`: for example,
+//! `ORPHEUS_SERVER__BIND_ADDRESS=0.0.0.0:8080` or
+//! `ORPHEUS_STORAGE__BACKEND=sqlite`. Values are read as TOML if they parse,
+//! as a string otherwise.
 
+use std::{
+    path::{Path, PathBuf},
+    sync::{LazyLock, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use chrono::TimeDelta;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
+use tracing_subscriber::filter::LevelFilter;
+
+mod check;
+mod comments;
+
+pub use check::{check_bind_address, ConfigError, Location, Problem, MAX_BACKUPS};
+
+/// Prefix of the environment variables read by the config.
+const ENV_PREFIX: &str = "ORPHEUS_";
+/// Separates the sections of a key in an override, see the module docs.
+const ENV_SEPARATOR: &str = "__";
 
 /// Rust representation of the TOML config for Orpheus.
 /// Example can be found in `orpheus-EXAMPLE.toml`.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Config {
     server: ServerConfig,
+    #[serde(default)]
+    storage: StorageConfig,
+    #[serde(default)]
+    library: LibraryConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
 }
 
 impl Config {
@@ -19,51 +58,600 @@ impl Config {
     pub fn server_mut(&mut self) -> &mut ServerConfig {
         &mut self.server
     }
+
+    pub fn storage(&self) -> &StorageConfig {
+        &self.storage
+    }
+
+    pub fn library(&self) -> &LibraryConfig {
+        &self.library
+    }
+
+    pub fn library_mut(&mut self) -> &mut LibraryConfig {
+        &mut self.library
+    }
+
+    pub fn logging(&self) -> &LoggingConfig {
+        &self.logging
+    }
 }
 
 impl Config {
+    // Constructors
+    /// Reads and validates the config file at `path`, applying the overrides
+    /// set in the environment.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::read(path, false)
+    }
+
+    /// Like [Config::load], but also checks that the server can use what's
+    /// configured, like whether `data_dir` can be written to. That means
+    /// creating files, so it's only for startup and `check-config`.
+    pub fn load_checked(path: &Path) -> Result<Self, ConfigError> {
+        Self::read(path, true)
+    }
+
+    fn read(path: &Path, probe: bool) -> Result<Self, ConfigError> {
+        let text: String = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            path: path.to_owned(),
+            problems: vec![Problem {
+                location: Location::Unknown,
+                key: String::new(),
+                message: format!("failed to read: {e}"),
+            }],
+        })?;
+        check::check(&text, std::env::vars(), probe).map_err(|problems| ConfigError {
+            path: path.to_owned(),
+            problems,
+        })
+    }
+
+    /// Parses and validates a config file, applying the overrides among
+    /// `vars`. Returns every problem found rather than just the first.
+    pub fn parse(
+        text: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Vec<Problem>> {
+        check::check(text, vars, false)
+    }
+
     // Methods
+    /// Writes the config back to the file it was loaded from.
     pub fn save(&self) {
-        let path: PathBuf = std::env::current_dir()
-            .expect("Failed to access current directory!")
-            .join("orpheus.toml");
-        std::fs::write(
-            &path,
-            toml::to_string_pretty(&self).expect("Failed to serialize Config!"),
-        )
-        .expect("Failed to write to `orpheus.toml`!");
+        let path: PathBuf = path();
+        self.save_to(&path)
+            .unwrap_or_else(|e| panic!("Failed to write to {}: {e}", path.display()));
+    }
+
+    /// Writes the config to `path`, creating its directory if needed.
+    pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.output())
+    }
+
+    /// Takes the settings from `new` that can change while the server runs,
+    /// see [crate::service::reload]. Everything else keeps its current value,
+    /// so the config always describes what the server is actually doing.
+    pub fn apply_live(&mut self, new: Config) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        let (server, new_server) = (&mut self.server, new.server);
+        for (key, changed) in [
+            ("server.data_dir", server.data_dir != new_server.data_dir),
+            (
+                "server.account_data_path",
+                server.account_data_path != new_server.account_data_path,
+            ),
+            (
+                "server.bind_address",
+                server.bind_address != new_server.bind_address,
+            ),
+            (
+                "server.account_backups",
+                server.account_backups != new_server.account_backups,
+            ),
+            ("server.tls", server.tls != new_server.tls),
+            ("storage", self.storage != new.storage),
+        ] {
+            if changed {
+                changes.restart.push(key);
+            }
+        }
+
+        for (key, changed) in [
+            (
+                "server.session_expiry_hours",
+                server.session_expiry_hours != new_server.session_expiry_hours,
+            ),
+            (
+                "server.rate_limit",
+                server.rate_limit != new_server.rate_limit,
+            ),
+            (
+                "server.cors_origins",
+                server.cors_origins != new_server.cors_origins,
+            ),
+            ("library", self.library != new.library),
+            ("logging", self.logging != new.logging),
+        ] {
+            if changed {
+                changes.live.push(key);
+            }
+        }
+        server.session_expiry_hours = new_server.session_expiry_hours;
+        server.rate_limit = new_server.rate_limit;
+        server.cors_origins = new_server.cors_origins;
+        self.library = new.library;
+        self.logging = new.logging;
+        changes
     }
 
+    /// The config as TOML, with every key explained, see [comments].
     pub fn output(&self) -> String {
-        toml::to_string_pretty(&self).expect("Failed to serialize Config!")
+        comments::annotate(&toml::to_string_pretty(&self).expect("Failed to serialize Config!"))
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ServerConfig {
-    account_data_path: String,
+    /// where everything the server writes is kept, see [crate::service::data_dir]
+    #[serde(default = "default_data_dir")]
+    data_dir: String,
+    /// deprecated, data found here is moved into `data_dir` on startup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    account_data_path: Option<String>,
     bind_address: String,
+    /// how many previous generations of each table to keep, at most [MAX_BACKUPS]
+    #[serde(default = "default_account_backups")]
+    account_backups: usize,
+    /// how long a login lasts
+    #[serde(default = "default_session_expiry_hours")]
+    session_expiry_hours: u32,
+    /// requests allowed per minute from a single address, 0 for no limit
+    #[serde(default)]
+    rate_limit: u32,
+    /// origins allowed to make cross-origin requests, any if empty
+    #[serde(default)]
+    cors_origins: Vec<String>,
+    /// serves HTTPS instead of HTTP when present, see [crate::service::tls]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsConfig>,
+}
+
+/// Which settings a reload changed, see [Config::apply_live].
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    /// dotted paths of the settings that were applied
+    pub live: Vec<&'static str>,
+    /// dotted paths of the settings that only apply after a restart
+    pub restart: Vec<&'static str>,
+}
+
+fn default_data_dir() -> String {
+    dirs::data_dir()
+        .expect("Unsupported operating system!")
+        .join("orpheus")
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn default_account_backups() -> usize {
+    3
+}
+
+fn default_session_expiry_hours() -> u32 {
+    6
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0:31078".to_owned()
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: default_data_dir(),
+            account_data_path: None,
+            bind_address: default_bind_address(),
+            account_backups: default_account_backups(),
+            session_expiry_hours: default_session_expiry_hours(),
+            rate_limit: 0,
+            cors_origins: Vec::new(),
+            tls: None,
+        }
+    }
 }
 
 impl ServerConfig {
-    pub fn account_data_path(&self) -> &str {
-        &self.account_data_path
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    pub fn set_data_dir(&mut self, data_dir: String) {
+        self.data_dir = data_dir;
     }
 
-    pub fn set_account_data_path(&mut self, data_path: String) {
-        self.account_data_path = data_path;
+    pub fn account_data_path(&self) -> Option<&str> {
+        self.account_data_path.as_deref()
     }
 
     pub fn bind_address(&self) -> &str {
         &self.bind_address
     }
+
+    pub fn set_bind_address(&mut self, bind_address: String) {
+        self.bind_address = bind_address;
+    }
+
+    pub fn account_backups(&self) -> usize {
+        self.account_backups
+    }
+
+    pub fn session_expiry(&self) -> TimeDelta {
+        TimeDelta::hours(self.session_expiry_hours.into())
+    }
+
+    pub fn rate_limit(&self) -> u32 {
+        self.rate_limit
+    }
+
+    pub fn cors_origins(&self) -> &[String] {
+        &self.cors_origins
+    }
+
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+}
+
+/// The certificate to serve HTTPS with. Both files are PEM encoded, and are
+/// reloaded whenever they change.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// certificate chain, starting with the server's own certificate
+    cert_file: String,
+    /// private key of the certificate
+    key_file: String,
+}
+
+impl TlsConfig {
+    pub fn cert_file(&self) -> &str {
+        &self.cert_file
+    }
+
+    pub fn key_file(&self) -> &str {
+        &self.key_file
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct StorageConfig {
+    /// which [crate::service::storage::StorageBackend] persists server data
+    #[serde(default)]
+    backend: BackendKind,
+    /// encrypts persisted data when present, see [crate::service::crypto]
+    #[serde(default)]
+    encryption: Option<EncryptionConfig>,
+}
+
+impl StorageConfig {
+    pub fn backend(&self) -> BackendKind {
+        self.backend
+    }
+
+    pub fn encryption(&self) -> Option<&EncryptionConfig> {
+        self.encryption.as_ref()
+    }
+}
+
+/// Where the key to encrypt persisted data with comes from. Exactly one of
+/// the two should be set.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// file holding the key as 64 hex digits
+    key_file: Option<String>,
+    /// environment variable holding the key as 64 hex digits
+    key_env: Option<String>,
+}
+
+impl EncryptionConfig {
+    pub fn key_file(&self) -> Option<&str> {
+        self.key_file.as_deref()
+    }
+
+    pub fn key_env(&self) -> Option<&str> {
+        self.key_env.as_deref()
+    }
+}
+
+/// What the scanner indexes, see [crate::service::library].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LibraryConfig {
+    /// directories the music library is scanned from
+    #[serde(default)]
+    roots: Vec<String>,
+    /// file extensions scanned, without the dot
+    #[serde(default = "default_extensions")]
+    extensions: Vec<String>,
+    /// file extensions never scanned, even if listed in `extensions`
+    #[serde(default)]
+    exclude_extensions: Vec<String>,
+    /// glob patterns relative to a root that files must match, any if empty
+    #[serde(default)]
+    include: Vec<String>,
+    /// glob patterns relative to a root of files and directories to skip
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// whether symbolic links to files and directories are followed
+    #[serde(default)]
+    follow_symlinks: bool,
+    /// minutes between full rescans, 0 to only scan on startup
+    #[serde(default = "default_scan_interval_minutes")]
+    scan_interval_minutes: u32,
+    /// whether changes to the roots are picked up as they happen
+    #[serde(default = "default_watch")]
+    watch: bool,
+}
+
+fn default_extensions() -> Vec<String> {
+    ["mp3", "flac", "ogg", "opus", "m4a", "aac", "wav", "aiff"]
+        .map(str::to_owned)
+        .to_vec()
+}
+
+fn default_scan_interval_minutes() -> u32 {
+    24 * 60
+}
+
+fn default_watch() -> bool {
+    true
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            extensions: default_extensions(),
+            exclude_extensions: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            follow_symlinks: false,
+            scan_interval_minutes: default_scan_interval_minutes(),
+            watch: default_watch(),
+        }
+    }
+}
+
+impl LibraryConfig {
+    pub fn roots(&self) -> &[String] {
+        &self.roots
+    }
+
+    pub fn set_roots(&mut self, roots: Vec<String>) {
+        self.roots = roots;
+    }
+
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    pub fn exclude_extensions(&self) -> &[String] {
+        &self.exclude_extensions
+    }
+
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Time between full rescans, if they're scheduled at all.
+    pub fn scan_interval(&self) -> Option<Duration> {
+        match self.scan_interval_minutes {
+            0 => None,
+            minutes => Some(Duration::from_secs(u64::from(minutes) * 60)),
+        }
+    }
+
+    pub fn watch(&self) -> bool {
+        self.watch
+    }
+}
+
+/// What the server logs and where, see [crate::service::logging].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct LoggingConfig {
+    /// most verbose level logged: off, error, warn, info, debug or trace,
+    /// release builds never log above info
+    #[serde(default = "default_log_level")]
+    level: String,
+    /// per-module overrides of `level`, like `orpheus::service::library=debug`
+    #[serde(default)]
+    directives: Vec<String>,
+    /// how each event is written out
+    #[serde(default)]
+    format: LogFormat,
+    /// whether events are also written to files in `<data_dir>/logs`
+    #[serde(default)]
+    file: bool,
+    /// how often a new log file is started
+    #[serde(default)]
+    rotation: LogRotation,
+    /// how many log files are kept, the oldest are deleted, 0 to keep them all
+    #[serde(default = "default_max_log_files")]
+    max_files: usize,
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_max_log_files() -> usize {
+    7
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            directives: Vec::new(),
+            format: LogFormat::default(),
+            file: false,
+            rotation: LogRotation::default(),
+            max_files: default_max_log_files(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn level(&self) -> LevelFilter {
+        self.level.parse().unwrap_or(LevelFilter::TRACE) // validated on load
+    }
+
+    pub fn directives(&self) -> &[String] {
+        &self.directives
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    pub fn file(&self) -> bool {
+        self.file
+    }
+
+    pub fn rotation(&self) -> LogRotation {
+        self.rotation
+    }
+
+    pub fn max_files(&self) -> usize {
+        self.max_files
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// a human-readable line per event, colored in a terminal
+    #[default]
+    Pretty,
+    /// a JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Weekly,
+    /// a single file that grows forever
+    Never,
+}
+
+impl From<LogRotation> for tracing_appender::rolling::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => Self::HOURLY,
+            LogRotation::Daily => Self::DAILY,
+            LogRotation::Weekly => Self::WEEKLY,
+            LogRotation::Never => Self::NEVER,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// pot snapshots plus write-ahead logs, one per table
+    #[default]
+    Pot,
+    /// a single embedded SQLite database, better suited to large libraries
+    Sqlite,
+    /// nothing is persisted, meant for tests
+    Memory,
+}
+
+/// Sets `table[path]` to `value`, creating the sections along the way.
+fn set_key(table: &mut toml::Table, path: &[String], value: toml::Value) -> Result<()> {
+    let (key, sections) = path.split_last().unwrap();
+    let mut table: &mut toml::Table = table;
+    for section in sections {
+        let entry = table
+            .entry(section.as_str())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let Some(inner) = entry.as_table_mut() else {
+            bail!("{section} isn't a section");
+        };
+        table = inner;
+    }
+    table.insert(key.clone(), value);
+    Ok(())
+}
+
+/// Reads an override as a TOML value, so numbers and booleans keep their
+/// type, falling back to a plain string.
+fn parse_env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()))
+}
+
+/// Path given with `--config`, see [set_path].
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes [CONFIG] read `path`, taking precedence over everything else. Must
+/// be called before the config is first accessed to have any effect.
+pub fn set_path(path: PathBuf) {
+    let _ = PATH.set(path);
+}
+
+/// The config file in use, see the module docs for the order they're tried in.
+/// If none of the candidates exist, that's the one in the current directory.
+pub fn path() -> PathBuf {
+    if let Some(path) = PATH.get() {
+        return path.clone();
+    }
+    if let Some(path) = std::env::var_os("ORPHEUS_CONFIG").filter(|path| !path.is_empty()) {
+        return path.into();
+    }
+    search_paths()
+        .into_iter()
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from("orpheus.toml"))
+}
+
+/// Where a config file is looked for when none is given explicitly, in order.
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    if let Some(dir) = dirs::config_dir() {
+        paths.push(dir.join("orpheus/orpheus.toml"));
+    }
+    if cfg!(unix) {
+        let dirs = std::env::var("XDG_CONFIG_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/etc/xdg".to_owned());
+        paths.extend(
+            std::env::split_paths(&dirs)
+                .filter(|dir| dir.is_absolute()) // relative entries are invalid per the spec
+                .map(|dir| dir.join("orpheus/orpheus.toml")),
+        );
+    }
+    paths.push(PathBuf::from("orpheus.toml"));
+    paths
 }
 
-// Global config store from file
-pub static CONFIG: LazyLock<RwLock<Config>> = LazyLock::new(|| {
-    let path: PathBuf = std::env::current_dir()
-        .expect("Failed to access current directory!")
-        .join("orpheus.toml");
-    let toml_file: String = std::fs::read_to_string(&path).expect("Failed to read `orpheus.toml`!");
-    RwLock::new(toml::from_str(&toml_file).expect("`orpheus.toml` format error!"))
-});
+// Global config store from file. The server checks the config with
+// [Config::load_checked] before anything reads it, so loading it shouldn't fail here.
+pub static CONFIG: LazyLock<RwLock<Config>> =
+    LazyLock::new(|| RwLock::new(Config::load(&path()).unwrap_or_else(|e| panic!("{e}"))));
@@ -0,0 +1,382 @@
+//! Validation of `orpheus.toml`. Rather than stopping at the first mistake,
+//! every problem that can be found is collected along with where it was
+//! made: a line and column in the file, or the environment variable that
+//! overrode the key.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use chrono::TimeDelta;
+use toml_edit::ImDocument;
+use tracing_subscriber::filter::{Directive, LevelFilter};
+
+use super::{parse_env_value, set_key, Config, ENV_PREFIX, ENV_SEPARATOR};
+
+/// Most previous generations of a table worth keeping, see
+/// [super::ServerConfig::account_backups].
+pub const MAX_BACKUPS: usize = 100;
+
+/// Where a [Problem] was found.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    /// 1-based line and column in the config file
+    File { line: usize, column: usize },
+    /// the environment variable overriding the key
+    Env(String),
+    /// the key wasn't set, or it's about the file as a whole
+    Unknown,
+}
+
+/// A single mistake in the config.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub location: Location,
+    /// dotted path of the key, like `server.bind_address`, empty for the root
+    pub key: String,
+    pub message: String,
+}
+
+/// Every [Problem] found in a config file.
+#[derive(thiserror::Error, Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.problems.len();
+        let plural = if count == 1 { "" } else { "s" };
+        write!(f, "{count} problem{plural} in {}:", self.path.display())?;
+        for problem in &self.problems {
+            match &problem.location {
+                Location::File { line, column } => {
+                    write!(f, "\n  {}:{line}:{column}: ", self.path.display())?
+                }
+                Location::Env(var) => write!(f, "\n  ${var}: ")?,
+                Location::Unknown => write!(f, "\n  {}: ", self.path.display())?,
+            }
+            if !problem.key.is_empty() {
+                write!(f, "{}: ", problem.key)?;
+            }
+            write!(f, "{}", problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses and validates a config file, see [Config::parse]. With `probe`,
+/// also checks what can only be found out by trying, see [Config::load_checked].
+pub(super) fn check(
+    text: &str,
+    vars: impl IntoIterator<Item = (String, String)>,
+    probe: bool,
+) -> Result<Config, Vec<Problem>> {
+    let document = match ImDocument::parse(text) {
+        Ok(document) => document,
+        Err(e) => {
+            return Err(vec![Problem {
+                location: e
+                    .span()
+                    .map_or(Location::Unknown, |span| locate(text, span)),
+                key: String::new(),
+                message: e.message().trim().replace('\n', ", "),
+            }])
+        }
+    };
+    let mut checker = Checker {
+        text,
+        document,
+        overrides: HashMap::new(),
+        problems: Vec::new(),
+    };
+
+    let mut table: toml::Table = toml::from_str(text).map_err(|e| {
+        vec![Problem {
+            location: Location::Unknown,
+            key: String::new(),
+            message: e.message().to_owned(),
+        }]
+    })?;
+    for (var, value) in vars {
+        let Some(key) = var.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        // `ORPHEUS_CONFIG` and the like aren't keys, every key is in a section
+        if !key.contains(ENV_SEPARATOR) {
+            continue;
+        }
+        let path: Vec<String> = key.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+        match set_key(&mut table, &path, parse_env_value(&value)) {
+            Ok(()) => {
+                checker.overrides.insert(path.join("."), var);
+            }
+            Err(e) => checker.problems.push(Problem {
+                location: Location::Env(var),
+                key: path.join("."),
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    let mut unknown: Vec<String> = Vec::new();
+    let mut ignored = |path: serde_ignored::Path| unknown.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(toml::Value::Table(table), &mut ignored);
+    let config: Result<Config, _> = serde_path_to_error::deserialize(deserializer);
+    for key in unknown {
+        let location = checker.find(&key, true);
+        checker.problems.push(Problem {
+            location,
+            key,
+            message: "unknown key".to_owned(),
+        });
+    }
+    match config {
+        Ok(config) => {
+            checker.check_values(&config);
+            if probe {
+                checker.probe(&config);
+            }
+            checker.finish(config)
+        }
+        Err(e) => {
+            let key: String = e.path().to_string();
+            let key: &str = if key == "." { "" } else { &key };
+            checker.report(key, e.inner().message().to_owned());
+            Err(checker.problems)
+        }
+    }
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    document: ImDocument<&'a str>,
+    /// the environment variable that set each overridden key
+    overrides: HashMap<String, String>,
+    problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+    /// Records a problem with the value under `key`.
+    fn report(&mut self, key: &str, message: String) {
+        let location = match self.overrides.get(key) {
+            Some(var) => Location::Env(var.clone()),
+            None => self.find(key, false),
+        };
+        self.problems.push(Problem {
+            location,
+            key: key.to_owned(),
+            message,
+        });
+    }
+
+    /// Where `key` is set in the file, pointing at its value if it has one
+    /// unless `at_key` is set.
+    fn find(&self, key: &str, at_key: bool) -> Location {
+        let mut item: &toml_edit::Item = self.document.as_item();
+        let mut span: Option<Range<usize>> = None;
+        for segment in key.split('.').filter(|segment| !segment.is_empty()) {
+            // entries of arrays are numbered, see `server.cors_origins`
+            let element = item
+                .as_array()
+                .zip(segment.parse::<usize>().ok())
+                .and_then(|(array, i)| array.get(i));
+            if let Some(element) = element {
+                span = element.span().or(span);
+                break;
+            }
+            let Some((key, value)) = item
+                .as_table_like()
+                .and_then(|table| table.get_key_value(segment))
+            else {
+                break; // the deepest key that is set is the best guess
+            };
+            let (first, second) = match at_key {
+                true => (key.span(), value.span()),
+                false => (value.span(), key.span()),
+            };
+            span = first.or(second).or(span);
+            item = value;
+        }
+        span.map_or(Location::Unknown, |span| locate(self.text, span))
+    }
+
+    /// Checks the values that deserialized fine, but can't be used.
+    fn check_values(&mut self, config: &Config) {
+        let server = config.server();
+        if let Err(message) = check_bind_address(server.bind_address()) {
+            self.report("server.bind_address", message);
+        }
+        if server.account_backups() > MAX_BACKUPS {
+            self.report(
+                "server.account_backups",
+                format!("must be at most {MAX_BACKUPS}"),
+            );
+        }
+        if server.session_expiry() < TimeDelta::hours(1) {
+            self.report(
+                "server.session_expiry_hours",
+                "must be at least 1".to_owned(),
+            );
+        }
+        for (i, origin) in server.cors_origins().iter().enumerate() {
+            let valid = origin == "*"
+                || ["http://", "https://"]
+                    .iter()
+                    .any(|scheme| origin.len() > scheme.len() && origin.starts_with(scheme));
+            if !valid || origin.ends_with('/') {
+                self.report(
+                    &format!("server.cors_origins.{i}"),
+                    format!("{origin:?} isn't an origin like \"https://example.com\" or \"*\""),
+                );
+            }
+        }
+        if let Some(tls) = server.tls() {
+            for (key, path) in [
+                ("server.tls.cert_file", tls.cert_file()),
+                ("server.tls.key_file", tls.key_file()),
+            ] {
+                if !Path::new(path).is_file() {
+                    self.report(key, format!("{path} doesn't exist"));
+                }
+            }
+            if Path::new(tls.cert_file()).is_file() && Path::new(tls.key_file()).is_file() {
+                if let Err(e) = crate::service::tls::load_certified_key(tls) {
+                    self.report("server.tls", format!("{e:#}"));
+                }
+            }
+        }
+        let library = config.library();
+        for (i, root) in library.roots().iter().enumerate() {
+            // a missing root is only skipped, it may be a drive that isn't mounted
+            let root_path = Path::new(root);
+            if root_path.exists() && !root_path.is_dir() {
+                self.report(
+                    &format!("library.roots.{i}"),
+                    format!("{root} isn't a directory"),
+                );
+            }
+        }
+        for (key, patterns) in [
+            ("library.include", library.include()),
+            ("library.exclude", library.exclude()),
+        ] {
+            for (i, pattern) in patterns.iter().enumerate() {
+                if let Err(e) = globset::Glob::new(pattern) {
+                    self.report(&format!("{key}.{i}"), e.kind().to_string());
+                }
+            }
+        }
+        if config.logging().level.parse::<LevelFilter>().is_err() {
+            self.report(
+                "logging.level",
+                "must be one of off, error, warn, info, debug or trace".to_owned(),
+            );
+        }
+        for (i, directive) in config.logging().directives().iter().enumerate() {
+            if let Err(e) = directive.parse::<Directive>() {
+                self.report(
+                    &format!("logging.directives.{i}"),
+                    format!("{e}, expected something like \"orpheus::service::library=debug\""),
+                );
+            }
+        }
+        if let Some(encryption) = config.storage().encryption() {
+            match (encryption.key_file(), encryption.key_env()) {
+                (Some(path), None) => {
+                    if !Path::new(path).is_file() {
+                        self.report(
+                            "storage.encryption.key_file",
+                            format!("{path} doesn't exist, create one with `orpheus rotate-key {path} --generate`"),
+                        );
+                    }
+                }
+                (None, Some(_)) => {}
+                _ => self.report(
+                    "storage.encryption",
+                    "needs exactly one of key_file or key_env".to_owned(),
+                ),
+            }
+        }
+    }
+
+    /// Checks the values that can only be found out to be unusable by trying
+    /// them, which touches the filesystem.
+    fn probe(&mut self, config: &Config) {
+        if let Err(message) = check_writable_dir(Path::new(config.server().data_dir())) {
+            self.report("server.data_dir", message);
+        }
+    }
+
+    fn finish(mut self, config: Config) -> Result<Config, Vec<Problem>> {
+        if self.problems.is_empty() {
+            return Ok(config);
+        }
+        self.problems
+            .sort_by(|a, b| a.location.cmp(&b.location).then(a.key.cmp(&b.key)));
+        Err(self.problems)
+    }
+}
+
+/// Turns a byte offset into the file into a line and column.
+fn locate(text: &str, span: Range<usize>) -> Location {
+    let before: &str = &text[..span.start.min(text.len())];
+    let line_start: usize = before.rfind('\n').map_or(0, |i| i + 1);
+    Location::File {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    }
+}
+
+/// Accepts `<ip>:<port>` as well as `<host name>:<port>`, without resolving
+/// the host name.
+pub fn check_bind_address(address: &str) -> Result<(), String> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return Err(format!(
+            "{address:?} isn't an address, expected <host>:<port>"
+        ));
+    };
+    if port.parse::<u16>().is_err() {
+        return Err(format!("{port:?} isn't a port, expected 0 to 65535"));
+    }
+    let valid_host = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if !valid_host {
+        return Err(format!("{host:?} isn't an IP address or host name"));
+    }
+    Ok(())
+}
+
+/// Checks that `dir`, or the closest parent that exists if it doesn't yet,
+/// is a directory files can be created in.
+fn check_writable_dir(dir: &Path) -> Result<(), String> {
+    let Some(existing) = dir.ancestors().find(|ancestor| ancestor.exists()) else {
+        return Ok(()); // relative to a working directory that doesn't exist, nothing to check
+    };
+    if !existing.is_dir() {
+        return Err(format!("{} isn't a directory", existing.display()));
+    }
+    // permission bits don't tell the whole story, so actually try
+    let probe: PathBuf = existing.join(format!(".orpheus-check-{}", std::process::id()));
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) => Err(format!("{} isn't writable: {e}", existing.display())),
+    }
+}
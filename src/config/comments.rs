@@ -0,0 +1,202 @@
+//! The comments written into `orpheus.toml` by [super::Config::output], so
+//! a generated file documents itself.
+
+use toml_edit::{DocumentMut, Item};
+
+/// Written at the top of the file.
+const HEADER: &str = "\
+Orpheus configuration, see orpheus-EXAMPLE.toml for an annotated example.
+Looked for at --config, $ORPHEUS_CONFIG, ~/.config/orpheus/orpheus.toml,
+/etc/xdg/orpheus/orpheus.toml, then ./orpheus.toml. Any key can be overridden
+with an environment variable like ORPHEUS_SERVER__BIND_ADDRESS=0.0.0.0:8080.
+Run `orpheus check-config` after editing. A running server picks up changes
+on its own, and logs which of them need a restart.";
+
+/// Written above each section and key, by dotted path.
+const COMMENTS: &[(&str, &str)] = &[
+    ("server", "Networking and where data is kept."),
+    (
+        "server.data_dir",
+        "Everything the server writes: databases, caches, artwork and logs.",
+    ),
+    (
+        "server.account_data_path",
+        "Deprecated, data found here is moved into data_dir on startup.",
+    ),
+    (
+        "server.bind_address",
+        "Address and port to listen on, 0.0.0.0 for every interface.",
+    ),
+    (
+        "server.account_backups",
+        "Previous versions of each table kept as <data_dir>/db/<table>.1, .2, ...",
+    ),
+    (
+        "server.session_expiry_hours",
+        "How long a login lasts before signing in again.",
+    ),
+    (
+        "server.rate_limit",
+        "Requests allowed per minute from a single address, 0 for no limit.",
+    ),
+    (
+        "server.cors_origins",
+        "Origins browsers may call the API from, like \"https://example.com\".\n\
+         Any origin is allowed if empty.",
+    ),
+    (
+        "server.tls",
+        "Serves HTTPS instead of plain HTTP. Both files are PEM encoded, and are\n\
+         reloaded when they change, so renewed certificates apply right away.",
+    ),
+    (
+        "server.tls.cert_file",
+        "Certificate chain, starting with the server's own certificate.",
+    ),
+    ("server.tls.key_file", "Private key of the certificate."),
+    ("storage", "How server data is persisted."),
+    (
+        "storage.backend",
+        "\"pot\" (one file per table in <data_dir>/db), \"sqlite\"\n\
+         (<data_dir>/db/orpheus.sqlite) or \"memory\" (nothing is saved!)",
+    ),
+    (
+        "storage.encryption",
+        "Encrypts everything written to disk with the key in key_file, or in the\n\
+         environment variable named by key_env instead. Rotate it with\n\
+         `orpheus rotate-key <new key file> [--generate]`.",
+    ),
+    (
+        "storage.encryption.key_file",
+        "File holding the key as 64 hex digits.",
+    ),
+    (
+        "storage.encryption.key_env",
+        "Environment variable holding the key as 64 hex digits.",
+    ),
+    ("library", "The music library."),
+    ("library.roots", "Directories music is scanned from."),
+    (
+        "library.extensions",
+        "File extensions scanned, case doesn't matter.",
+    ),
+    (
+        "library.exclude_extensions",
+        "File extensions never scanned, even if listed in extensions.",
+    ),
+    (
+        "library.include",
+        "Glob patterns relative to a root, like \"Albums/**\", that files have to\n\
+         match to be scanned. Every file is scanned if empty.",
+    ),
+    (
+        "library.exclude",
+        "Glob patterns relative to a root of files and directories to skip, like\n\
+         \"**/Podcasts/**\" or \"**/*.part\".",
+    ),
+    (
+        "library.follow_symlinks",
+        "Whether symbolic links to files and directories are followed.",
+    ),
+    (
+        "library.scan_interval_minutes",
+        "Minutes between full rescans, 0 to only scan on startup.",
+    ),
+    (
+        "library.watch",
+        "Pick up changes to the roots as they happen, not just on rescans.",
+    ),
+    ("logging", "What the server logs."),
+    (
+        "logging.level",
+        "Most verbose level logged: off, error, warn, info, debug or trace.\n\
+         Release builds never log above info.",
+    ),
+    (
+        "logging.directives",
+        "Per-module overrides of level, like \"orpheus::service::library=debug\"\n\
+         or \"tower_http=warn\".",
+    ),
+    (
+        "logging.format",
+        "\"pretty\" (human-readable lines) or \"json\" (one object per line).",
+    ),
+    (
+        "logging.file",
+        "Also write everything to files in <data_dir>/logs.",
+    ),
+    (
+        "logging.rotation",
+        "How often a new log file is started: hourly, daily, weekly or never.",
+    ),
+    (
+        "logging.max_files",
+        "Log files kept before the oldest is deleted, 0 to keep them all.",
+    ),
+];
+
+/// Sections that are left out while unset, written commented out instead.
+const OPTIONAL: &[(&str, &str)] = &[
+    (
+        "server.tls",
+        "[server.tls]\n\
+         cert_file = \"/etc/letsencrypt/live/example.com/fullchain.pem\"\n\
+         key_file = \"/etc/letsencrypt/live/example.com/privkey.pem\"",
+    ),
+    (
+        "storage.encryption",
+        "[storage.encryption]\n\
+         key_file = \"/path/to/key\"  # create one with `orpheus rotate-key /path/to/key --generate`",
+    ),
+];
+
+/// Adds [COMMENTS] to `toml`, as serialized from a config.
+pub fn annotate(toml: &str) -> String {
+    let mut document: DocumentMut = toml.parse().expect("Serialized config isn't valid TOML!");
+    for (path, comment) in COMMENTS {
+        let comment: String = comment_lines(comment);
+        let (parent, key) = match path.rsplit_once('.') {
+            Some((parent, key)) => (Some(parent), key),
+            None => (None, *path),
+        };
+        let table = parent
+            .into_iter()
+            .flat_map(|parent| parent.split('.'))
+            .try_fold(document.as_table_mut(), |table, section| {
+                table.get_mut(section).and_then(Item::as_table_mut)
+            });
+        let Some(table) = table else {
+            continue; // unset optional section
+        };
+        if let Some(section) = table.get_mut(key).and_then(Item::as_table_mut) {
+            section.decor_mut().set_prefix(format!("\n{comment}"));
+        } else if let Some(mut key) = table.key_mut(key) {
+            key.leaf_decor_mut().set_prefix(comment);
+        }
+    }
+
+    let mut text: String = format!("{}{document}", comment_lines(HEADER));
+    for (path, example) in OPTIONAL {
+        let set: bool = path
+            .split('.')
+            .try_fold(document.as_item(), |item, section| item.get(section))
+            .is_some();
+        if !set {
+            let comment: &str = COMMENTS
+                .iter()
+                .find(|(commented, _)| commented == path)
+                .map_or("", |(_, comment)| comment);
+            text.push_str(&format!(
+                "\n{}{}",
+                comment_lines(comment),
+                comment_lines(example)
+            ));
+        }
+    }
+    text
+}
+
+/// Turns `text` into TOML comment lines.
+fn comment_lines(text: &str) -> String {
+    text.lines().map(|line| format!("# {line}\n")).collect()
+}
@@ -1,42 +1,111 @@
 mod create_account;
+pub mod docs;
+mod error;
+pub mod events;
+pub mod health;
 mod login;
-use axum::{http::StatusCode, response::IntoResponse};
+pub mod middleware;
+mod scrobble;
+pub mod subsonic;
+mod subsonic_password;
+pub mod wire;
+use std::sync::Arc;
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    routing::get,
+    Router,
+};
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
 
 // exports
 pub use create_account::create_account;
+pub use error::ApiError;
+pub use events::{event_stream, send_notice};
 pub use login::login;
+pub use scrobble::{scrobble, set_scrobble_targets};
+pub use subsonic_password::reset_subsonic_password;
+
+use crate::{
+    service::auth::{AccountSession, Token},
+    services::{AccountService, SessionService},
+    try_header,
+    types::AccountRecord,
+};
 
-// A custom error type that will return bad request when returned.
-pub struct BadRequestError(StatusCode);
+/// Where the current version of the API is served from.
+pub const API_V1: &str = "/api/v1";
+
+/// Every endpoint of the API, nested under [API_V1], plus its documentation
+/// (see [docs]), the event stream (see [events]), the probes (see [health])
+/// and the Subsonic compatibility layer (see [subsonic]).
+/// Bodies are JSON or pot, see [wire].
+pub fn router() -> Router {
+    let (api, openapi) = api_router();
+    Router::new()
+        .route("/", get(root_responder)) // mostly to test logging and firewalls
+        .route(events::EVENTS_PATH, get(events::event_stream))
+        .route(health::HEALTH_PATH, get(health::health))
+        .route(health::READY_PATH, get(health::ready))
+        .route(health::METRICS_PATH, get(health::metrics))
+        .merge(api)
+        .merge(SwaggerUi::new(docs::DOCS_PATH).url(docs::OPENAPI_PATH, openapi))
+        .merge(subsonic::router())
+}
 
-impl Default for BadRequestError {
-    fn default() -> Self {
-        Self(StatusCode::BAD_REQUEST)
-    }
+/// The endpoints of the API along with the OpenAPI document describing them,
+/// generated from the same list so neither can miss a route.
+fn api_router() -> (Router, utoipa::openapi::OpenApi) {
+    let v1 = OpenApiRouter::new()
+        .routes(routes!(create_account::create_account))
+        .routes(routes!(login::login))
+        .routes(routes!(scrobble::scrobble))
+        .routes(routes!(scrobble::set_scrobble_targets))
+        .routes(routes!(subsonic_password::reset_subsonic_password))
+        .routes(routes!(events::send_notice));
+    let (api, mut openapi) = OpenApiRouter::with_openapi(docs::ApiDoc::openapi())
+        .nest(API_V1, v1)
+        .split_for_parts();
+    docs::complete(&mut openapi);
+    (api, openapi)
 }
 
-impl IntoResponse for BadRequestError {
-    fn into_response(self) -> axum::response::Response {
-        self.0.into_response()
-    }
+/// Authenticates the request headers and returns the caller's current
+/// account record. The session's own record isn't used as it is a snapshot
+/// from login time and may hold stale user data.
+fn authenticate(headers: &HeaderMap) -> Result<Arc<AccountRecord>, ApiError> {
+    let session: Arc<AccountSession> = session(headers)?;
+    AccountService
+        .get(session.record().username())
+        .ok_or(ApiError::Unauthenticated)
+}
+
+/// Returns the session the request headers authenticate with, for when
+/// more than the account is needed, like when it expires.
+fn session(headers: &HeaderMap) -> Result<Arc<AccountSession>, ApiError> {
+    let username: &str = try_header!(headers["username"]);
+    let token: Token = Token::try_from(try_header!(headers["auth-token"]))
+        .map_err(|_| ApiError::InvalidHeader("auth-token"))?;
+
+    SessionService
+        .auth_get_session(username, token)
+        .ok_or(ApiError::Unauthenticated)
 }
 
-impl<E> From<E> for BadRequestError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(_: E) -> Self {
-        Self(StatusCode::BAD_REQUEST)
-    }
+async fn root_responder() -> Result<(), StatusCode> {
+    tracing::debug!("root response");
+    Ok(())
 }
 
 /// Simple macro to reduce boilerplate of trying to get a header value as a [&str].
-/// Note that the calling function must return [BadRequestError] or [anyhow::Error]
-/// as the macro makes two separate try calls.
+/// Note that the calling function must return [ApiError], as a missing header
+/// becomes [ApiError::MissingHeader] and a non-ASCII one [ApiError::InvalidHeader].
 ///
 /// Example:
 /// ```rs
-/// pub async fn resp(headers: HeaderMap) -> Result<(), BadRequestError> {
+/// pub async fn resp(headers: HeaderMap) -> Result<(), ApiError> {
 ///     let username: &str = try_header!(headers["username"]);
 ///     todo!();
 /// }
@@ -44,6 +113,9 @@ where
 #[macro_export]
 macro_rules! try_header {
     ($i:ident[$h:literal]) => {
-        $i.get($h).ok_or(BadRequestError::default())?.to_str()?
+        $i.get($h)
+            .ok_or($crate::endpoints::ApiError::MissingHeader($h))?
+            .to_str()
+            .map_err(|_| $crate::endpoints::ApiError::InvalidHeader($h))?
     };
 }
@@ -1,8 +1,4 @@
-use axum::{
-    body::Bytes,
-    http::{HeaderMap, StatusCode},
-};
-use serde::Deserialize;
+use axum::http::{HeaderMap, StatusCode};
 use tracing::debug;
 
 use crate::{
@@ -11,40 +7,54 @@ use crate::{
     try_header,
 };
 
-use super::BadRequestError;
-
-/// A data struct to represent the account information sent by the binary file.
-/// Any information received by this endpoint is expected to be encoded using
-/// the `pot` library in this specific struct format.
-#[derive(Deserialize)]
-struct CreateAccount {
-    username: String,
-    password: String,
-    is_admin: bool,
-}
+use super::{
+    wire::{AccountInfo, CreateAccountRequest, ErrorResponse, Reply, ResponseFormat, Wire},
+    ApiError,
+};
 
-/// The handler function for the `/create-account` endpoint.
-pub async fn create_account(headers: HeaderMap, bytes: Bytes) -> Result<(), BadRequestError> {
-    let request_info: CreateAccount = pot::from_slice(&bytes)?;
+/// The handler function for the `/create-account` endpoint. Responds with
+/// the new account as an [AccountInfo].
+#[utoipa::path(
+    post,
+    path = "/create-account",
+    tag = "accounts",
+    summary = "Create an account",
+    description = "Registers a new account. Only admins may create accounts.",
+    request_body = CreateAccountRequest,
+    security(("username" = [], "auth-token" = [])),
+    responses(
+        (status = CREATED, description = "The account was created", body = AccountInfo),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The caller isn't an admin", body = ErrorResponse),
+        (status = CONFLICT, description = "The username is taken", body = ErrorResponse),
+    )
+)]
+pub async fn create_account(
+    headers: HeaderMap,
+    ResponseFormat(format): ResponseFormat,
+    Wire(request_info): Wire<CreateAccountRequest>,
+) -> Result<Reply<AccountInfo>, ApiError> {
     let username: &str = try_header!(headers["username"]);
-    let token: Token = Token::try_from(try_header!(headers["auth-token"]))?;
+    let token: Token = Token::try_from(try_header!(headers["auth-token"]))
+        .map_err(|_| ApiError::InvalidHeader("auth-token"))?;
 
     if let Some(session) = SessionService.auth_get_session(username, token) {
         if *session.record().is_admin() {
-            debug!(
-                "creating account {{ username: {}, password: {} }}",
-                &request_info.username, &request_info.password
-            );
+            debug!("creating account {:?}", request_info);
+            let info = AccountInfo {
+                username: request_info.username.clone(),
+                is_admin: request_info.is_admin,
+            };
             AccountService.register(
                 request_info.username,
                 request_info.password,
                 request_info.is_admin,
             )?;
-            Ok(())
+            Ok(format.reply(info).with_status(StatusCode::CREATED))
         } else {
-            Err(BadRequestError(StatusCode::UNAUTHORIZED))
+            Err(ApiError::Forbidden)
         }
     } else {
-        Err(BadRequestError(StatusCode::NOT_FOUND))
+        Err(ApiError::Unauthenticated)
     }
 }
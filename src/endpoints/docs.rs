@@ -0,0 +1,142 @@
+//! # API Documentation
+//! The OpenAPI document describing every endpoint, served at
+//! [OPENAPI_PATH] with an interactive page at [DOCS_PATH]. It's built from
+//! the `#[utoipa::path]` attribute on each handler and the wire types they
+//! use, while [super::router] registers those same handlers, so the two
+//! can't disagree. The checked-in `openapi.json` is compared against it in
+//! tests, regenerate it with `orpheus openapi > openapi.json`.
+//!
+//! The attributes only list JSON bodies and the errors particular to an
+//! endpoint, [complete] fills in the rest for every operation.
+
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        Content, OpenApi as Document, PathItem, Ref, RefOr, Response,
+    },
+    OpenApi,
+};
+
+use super::wire::{self, ErrorResponse};
+
+/// Where the OpenAPI document is served.
+pub const OPENAPI_PATH: &str = "/api/openapi.json";
+/// Where the interactive documentation is served.
+pub const DOCS_PATH: &str = "/api/docs";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Orpheus",
+        description = "A self-hosted music server. Request bodies are JSON (`application/json`) or \
+                       pot (`application/x-pot`, the default without a `Content-Type`), responses \
+                       follow `Accept`. Errors have a stable `code` to branch on."
+    ),
+    tags(
+        (name = "accounts", description = "Logging in and managing accounts"),
+        (name = "scrobbling", description = "Recording plays and forwarding them to ListenBrainz"),
+        (name = "events", description = "Live updates, pushed to clients over the WebSocket at `/ws`"),
+    ),
+    components(schemas(ErrorResponse))
+)]
+pub struct ApiDoc;
+
+/// Adds what every operation has in common to `openapi`, once the routes
+/// have added theirs.
+pub(super) fn complete(openapi: &mut Document) {
+    openapi.info.license = None; // taken from Cargo.toml, which has none
+    session_headers(openapi);
+    pot_bodies(openapi);
+    common_errors(openapi);
+}
+
+/// Declares the `username` and `auth-token` headers logged-in requests
+/// carry, see `/api/v1/login`.
+fn session_headers(openapi: &mut Document) {
+    let components = openapi.components.get_or_insert_with(Default::default);
+    components.add_security_scheme(
+        "username",
+        SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+            "username",
+            "Name of the logged in account",
+        ))),
+    );
+    components.add_security_scheme(
+        "auth-token",
+        SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+            "auth-token",
+            "Token returned by /api/v1/login",
+        ))),
+    );
+}
+
+/// Offers every JSON body as pot as well, see [wire].
+fn pot_bodies(openapi: &mut Document) {
+    for operation in openapi.paths.paths.values_mut().flat_map(operations) {
+        if let Some(body) = &mut operation.request_body {
+            let pot = pot_of(body.content.iter());
+            body.content.extend(pot);
+        }
+        for response in operation.responses.responses.values_mut() {
+            if let RefOr::T(response) = response {
+                let pot = pot_of(response.content.iter());
+                response.content.extend(pot);
+            }
+        }
+    }
+}
+
+/// Adds the errors any endpoint can answer with to every operation.
+fn common_errors(openapi: &mut Document) {
+    for operation in openapi.paths.paths.values_mut().flat_map(operations) {
+        let mut errors = vec![("429", "Too many requests from this address")];
+        if operation.request_body.is_some() {
+            errors.push(("400", "The body or a header is malformed"));
+            errors.push(("415", "The body is neither JSON nor pot"));
+        }
+        for (status, description) in errors {
+            operation
+                .responses
+                .responses
+                .entry(status.to_owned())
+                .or_insert_with(|| RefOr::T(error_response(description)));
+        }
+    }
+}
+
+fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut utoipa::openapi::path::Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.patch,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// The pot counterpart of the JSON entry among `content`, if there is one.
+fn pot_of<'a>(
+    mut content: impl Iterator<Item = (&'a String, &'a Content)>,
+) -> Option<(String, Content)> {
+    content
+        .find(|(content_type, _)| *content_type == wire::JSON)
+        .map(|(_, json)| (wire::POT.to_owned(), json.clone()))
+}
+
+fn error_response(description: &str) -> Response {
+    let mut response = Response::new(description);
+    let content = Content::new(Some(Ref::from_schema_name("ErrorResponse")));
+    for content_type in [wire::JSON, wire::POT] {
+        response
+            .content
+            .insert(content_type.to_owned(), content.clone());
+    }
+    response
+}
+
+/// The OpenAPI document of the API, as served at [OPENAPI_PATH].
+pub fn openapi() -> Document {
+    super::api_router().1
+}
@@ -0,0 +1,136 @@
+//! # API Errors
+//! Everything an endpoint can fail with. Each [ApiError] answers with its own
+//! HTTP status and an [ErrorResponse] body holding a stable, machine-readable
+//! code (see [ApiError::code]) next to a message meant for people. Clients
+//! should branch on the code, the message may be reworded.
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use super::wire::{ErrorResponse, WireFormat};
+use crate::types::AccountError;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("missing header `{0}`")]
+    MissingHeader(&'static str),
+    #[error("header `{0}` is malformed")]
+    InvalidHeader(&'static str),
+    #[error("unsupported Content-Type, bodies must be application/json or application/x-pot")]
+    UnsupportedMediaType,
+    /// Holds why the body couldn't be read or decoded.
+    #[error("malformed request body: {0}")]
+    MalformedBody(String),
+    /// Holds which query parameter is wrong and why.
+    #[error("malformed query string: {0}")]
+    MalformedQuery(String),
+    #[error("not logged in, or the session has expired")]
+    Unauthenticated,
+    #[error("wrong username or password")]
+    InvalidCredentials,
+    #[error("only admins can do this")]
+    Forbidden,
+    #[error("account {0} already exists")]
+    AccountExists(String),
+    #[error("account {0} does not exist")]
+    AccountNotFound(String),
+    #[error("too many requests, try again in a minute")]
+    RateLimited,
+    /// Anything that isn't the client's fault. The error is only logged, the
+    /// client just gets a generic message.
+    #[error("internal server error")]
+    Internal(#[source] anyhow::Error),
+}
+
+impl ApiError {
+    /// The machine-readable code sent to clients, which never changes for a
+    /// given variant.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingHeader(_) => "missing_header",
+            ApiError::InvalidHeader(_) => "invalid_header",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::MalformedBody(_) => "malformed_body",
+            ApiError::MalformedQuery(_) => "malformed_query",
+            ApiError::Unauthenticated => "unauthenticated",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Forbidden => "forbidden",
+            ApiError::AccountExists(_) => "account_exists",
+            ApiError::AccountNotFound(_) => "account_not_found",
+            ApiError::RateLimited => "rate_limited",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::MissingHeader(_)
+            | ApiError::InvalidHeader(_)
+            | ApiError::MalformedBody(_)
+            | ApiError::MalformedQuery(_) => StatusCode::BAD_REQUEST,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unauthenticated | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::AccountExists(_) => StatusCode::CONFLICT,
+            ApiError::AccountNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The body sent for this error.
+    pub fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            code: self.code().to_owned(),
+            message: self.to_string(),
+        }
+    }
+}
+
+impl From<AccountError> for ApiError {
+    fn from(error: AccountError) -> Self {
+        match error {
+            AccountError::Exists(username) => ApiError::AccountExists(username),
+            AccountError::NotFound(username) => ApiError::AccountNotFound(username),
+        }
+    }
+}
+
+/// Keeps the errors services attach to their [anyhow::Error]s, anything else
+/// becomes [ApiError::Internal].
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<AccountError>() {
+            Ok(error) => error.into(),
+            Err(error) => ApiError::Internal(error),
+        }
+    }
+}
+
+/// Answers with a JSON body. The body is also put in the response's
+/// extensions, for `middleware::error_format` to re-encode it in the format
+/// the client asked for.
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(error) = &self {
+            tracing::error!("Request failed: {error:#}");
+        }
+        let body: ErrorResponse = self.body();
+        let mut response = match WireFormat::Json.encode(&body) {
+            Ok(bytes) => (
+                self.status(),
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(super::wire::JSON),
+                )],
+                bytes,
+            )
+                .into_response(),
+            Err(_) => self.status().into_response(),
+        };
+        response.extensions_mut().insert(body);
+        response
+    }
+}
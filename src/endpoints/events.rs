@@ -0,0 +1,244 @@
+//! # Event Stream
+//! `GET /ws` upgrades to a WebSocket pushing the [events](crate::service::events)
+//! the caller may see as they're published, so clients don't have to poll.
+//! It's authenticated with the same `username` and `auth-token` headers as
+//! the rest of the API, and closed once the session expires.
+//!
+//! Every message is a JSON text message with a `type`. Events also have an
+//! `id`, a `time` and a `topic`, and the rest depends on the type, see
+//! [EventKind](crate::service::events::EventKind). Two more types are sent:
+//! - `resync`: events were missed, either because the server no longer has
+//!   those the client asked for or because the client fell behind, so it
+//!   should fetch everything it shows again,
+//! - `error`: a message from the client couldn't be read.
+//!
+//! The query string picks what to get: `topics` is a comma-separated list
+//! of topics, all of them if it's missing, and `since` is the id of the last
+//! event the client saw, which makes the server send everything after it
+//! first. Clients change topics with `{"type": "subscribe", "topics": [...]}`
+//! and `{"type": "unsubscribe", "topics": [...]}`.
+
+use std::{collections::HashSet, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::{
+        rejection::QueryRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{
+    authenticate, session,
+    wire::{ErrorResponse, NoticeRequest, Wire},
+    ApiError,
+};
+use crate::{
+    service::{
+        auth::AUDIT,
+        events::{Event, EventKind, Subscription, Topic},
+    },
+    services::EventService,
+};
+
+/// Where the event stream is served.
+pub const EVENTS_PATH: &str = "/ws";
+
+/// How often the connection is pinged and the session checked.
+const KEEPALIVE: Duration = Duration::from_secs(30);
+/// Close code for a session that ended, from the range left to applications.
+pub const SESSION_ENDED: u16 = 4001;
+
+/// The query string of `GET /ws`, see the module docs.
+#[derive(Deserialize, Debug)]
+pub struct StreamQuery {
+    topics: Option<String>,
+    since: Option<u64>,
+}
+
+/// What clients send over the socket.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { topics: Vec<Topic> },
+    Unsubscribe { topics: Vec<Topic> },
+}
+
+/// The handler function for the `/ws` endpoint, see the module docs.
+pub async fn event_stream(
+    headers: HeaderMap,
+    query: Result<Query<StreamQuery>, QueryRejection>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let session = session(&headers)?;
+    let Query(query) = query.map_err(|e| ApiError::MalformedQuery(e.body_text()))?;
+    let topics: HashSet<Topic> = match &query.topics {
+        None => Topic::ALL.into(),
+        Some(topics) => topics
+            .split(',')
+            .filter(|topic| !topic.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map_err(ApiError::MalformedQuery)?,
+    };
+    // subscribed before the upgrade, so nothing published meanwhile is lost
+    let subscription: Subscription = EventService.subscribe(query.since);
+    let username: String = session.record().username().clone();
+    let expires: DateTime<Utc> = session.expires();
+    Ok(upgrade
+        .on_upgrade(move |socket| stream(socket, headers, username, expires, topics, subscription)))
+}
+
+/// The handler function for the `/notices` endpoint. Sends a notice to
+/// every connected client.
+#[utoipa::path(
+    post,
+    path = "/notices",
+    tag = "events",
+    summary = "Send a notice",
+    description = "Pushes a message to every client connected to the event stream at `/ws`, \
+                   like a heads-up about a restart. Only admins may send notices.",
+    request_body = NoticeRequest,
+    security(("username" = [], "auth-token" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The notice was sent"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The caller isn't an admin", body = ErrorResponse),
+    )
+)]
+pub async fn send_notice(
+    headers: HeaderMap,
+    Wire(notice): Wire<NoticeRequest>,
+) -> Result<StatusCode, ApiError> {
+    let record = authenticate(&headers)?;
+    if !*record.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+    tracing::info!(target: AUDIT, username = record.username(), message = notice.message, "notice sent");
+    EventService.publish(
+        None,
+        EventKind::Notice {
+            message: notice.message,
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends events to `socket` until either side hangs up, the session ends at
+/// `expires` or earlier, or the server shuts down.
+async fn stream(
+    mut socket: WebSocket,
+    headers: HeaderMap,
+    username: String,
+    expires: DateTime<Utc>,
+    mut topics: HashSet<Topic>,
+    subscription: Subscription,
+) {
+    let Subscription {
+        missed,
+        mut receiver,
+    } = subscription;
+    let sent = match missed {
+        Some(events) => {
+            let mut sent = Ok(());
+            for event in events {
+                sent = send(&mut socket, &event, &username, &topics).await;
+                if sent.is_err() {
+                    break;
+                }
+            }
+            sent
+        }
+        None => socket.send(resync()).await,
+    };
+    if sent.is_err() {
+        return;
+    }
+
+    let mut keepalive = tokio::time::interval(KEEPALIVE);
+    keepalive.tick().await; // the first tick is right away
+    let expiry = tokio::time::sleep((expires - Utc::now()).to_std().unwrap_or_default());
+    tokio::pin!(expiry);
+    loop {
+        let sent = tokio::select! {
+            // events first, so the notice about shutting down still goes out
+            biased;
+            received = receiver.recv() => match received {
+                Ok(event) => send(&mut socket, &event, &username, &topics).await,
+                Err(RecvError::Lagged(_)) => socket.send(resync()).await,
+                Err(RecvError::Closed) => break,
+            },
+            _ = EventService.closed() => {
+                close(socket, close_code::AWAY, "server shutting down").await;
+                return;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Subscribe { topics: added }) => {
+                        topics.extend(added);
+                        Ok(())
+                    }
+                    Ok(ClientMessage::Unsubscribe { topics: removed }) => {
+                        topics.retain(|topic| !removed.contains(topic));
+                        Ok(())
+                    }
+                    Err(e) => {
+                        let error = json!({ "type": "error", "message": e.to_string() });
+                        socket.send(Message::text(error.to_string())).await
+                    }
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                // pings are answered by axum, anything else is ignored
+                Some(Ok(_)) => Ok(()),
+            },
+            _ = &mut expiry => {
+                close(socket, SESSION_ENDED, "session ended").await;
+                return;
+            }
+            // catches accounts that are gone
+            _ = keepalive.tick() => match authenticate(&headers) {
+                Ok(_) => socket.send(Message::Ping(Bytes::new())).await,
+                Err(_) => {
+                    close(socket, SESSION_ENDED, "session ended").await;
+                    return;
+                }
+            },
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends `event` if it's meant for `username` and on one of `topics`.
+async fn send(
+    socket: &mut WebSocket,
+    event: &Event,
+    username: &str,
+    topics: &HashSet<Topic>,
+) -> Result<(), axum::Error> {
+    if !event.is_for(username) || !topics.contains(&event.topic) {
+        return Ok(());
+    }
+    let text: String = serde_json::to_string(event).map_err(axum::Error::new)?;
+    socket.send(Message::text(text)).await
+}
+
+fn resync() -> Message {
+    Message::text(json!({ "type": "resync" }).to_string())
+}
+
+async fn close(mut socket: WebSocket, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
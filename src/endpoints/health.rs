@@ -0,0 +1,92 @@
+//! # Probes
+//! Endpoints for whatever runs the server, like a container orchestrator or
+//! a Prometheus scraper, rather than for clients. None of them need a login.
+//! - `/healthz` answers as long as the server can answer at all,
+//! - `/readyz` answers 503 Service Unavailable until the accounts are
+//!   loaded and the library was scanned once, so requests aren't sent to a
+//!   server that can't log anyone in or is missing music,
+//! - `/metrics` serves [crate::service::metrics] in the Prometheus text
+//!   format.
+
+use std::sync::LazyLock;
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::{
+    service::metrics::Gauge,
+    services::{AccountService, LibraryService, MetricsService, SessionService},
+};
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const READY_PATH: &str = "/readyz";
+pub const METRICS_PATH: &str = "/metrics";
+
+/// `Content-Type` of the Prometheus text format.
+const PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The handler function for the `/healthz` endpoint.
+pub async fn health() -> &'static str {
+    "ok"
+}
+
+/// The handler function for the `/readyz` endpoint, responding with what's
+/// still being waited for, if anything.
+pub async fn ready() -> (StatusCode, String) {
+    let mut pending: Vec<&str> = Vec::new();
+    if LazyLock::get(&AccountService).is_none() {
+        pending.push("accounts");
+    }
+    if !LibraryService.has_scanned() {
+        pending.push("library scan");
+    }
+    match pending.is_empty() {
+        true => (StatusCode::OK, "ready".to_owned()),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("waiting for: {}", pending.join(", ")),
+        ),
+    }
+}
+
+/// The handler function for the `/metrics` endpoint.
+pub async fn metrics() -> impl IntoResponse {
+    // services still loading aren't waited for, nor loaded by a scrape
+    let accounts: Option<u64> =
+        LazyLock::get(&AccountService).map(|accounts| accounts.len() as u64);
+    let sessions: Option<u64> =
+        LazyLock::get(&SessionService).map(|sessions| sessions.active_sessions() as u64);
+    let gauges: Vec<Gauge> = [
+        ("orpheus_accounts", "Registered accounts", accounts),
+        (
+            "orpheus_sessions_active",
+            "Sessions that haven't expired",
+            sessions,
+        ),
+        (
+            "orpheus_library_tracks",
+            "Tracks in the library",
+            Some(LibraryService.len() as u64),
+        ),
+        (
+            "orpheus_ready",
+            "Whether the server is ready, see /readyz",
+            Some(u64::from(ready().await.0 == StatusCode::OK)),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, help, value)| {
+        Some(Gauge {
+            name,
+            help,
+            value: value?,
+        })
+    })
+    .collect();
+    (
+        [(header::CONTENT_TYPE, PROMETHEUS)],
+        MetricsService.render(&gauges),
+    )
+}
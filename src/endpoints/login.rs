@@ -1,22 +1,66 @@
-use super::BadRequestError;
-use crate::{service::auth::Token, services::SessionService, try_header, types::AuthCode};
+use super::{
+    wire::{ErrorResponse, LoginRequest, LoginResponse, Reply, ResponseFormat, Wire},
+    ApiError,
+};
+use crate::{
+    service::auth::AUDIT,
+    services::{Config, SessionService},
+    types::AuthCode,
+};
 
-use axum::http::{HeaderMap, StatusCode};
+use std::net::SocketAddr;
 
-/// The handler function for the `/login` endpoint.
-pub async fn login(headers: HeaderMap) -> Result<Token, BadRequestError> {
-    let username: &str = try_header!(headers["username"]);
-    let password: &str = try_header!(headers["password"]);
+use axum::extract::ConnectInfo;
 
-    match SessionService.login(username, password) {
-        AuthCode::Success(session) => {
-            tracing::debug!(
-                "Successfully logged in {username}:{password} with token {}",
-                session.token().0.to_string()
-            );
-            Ok(session.token())
+/// The handler function for the `/login` endpoint. Credentials are only
+/// accepted in the body, as headers tend to end up in the logs of proxies
+/// along the way. A wrong password and an unknown username get the same
+/// response, only the audit log tells them apart, so the endpoint can't be
+/// used to find out who has an account.
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "accounts",
+    summary = "Log in",
+    description = "Starts a session. Send the username and the returned token in the \
+                   `username` and `auth-token` headers of later requests. A wrong password \
+                   and an unknown username get the same response.",
+    request_body = LoginRequest,
+    responses(
+        (status = OK, description = "A new session", body = LoginResponse),
+        (status = UNAUTHORIZED, description = "Wrong username or password", body = ErrorResponse),
+    )
+)]
+pub async fn login(
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    ResponseFormat(format): ResponseFormat,
+    Wire(credentials): Wire<LoginRequest>,
+) -> Result<Reply<LoginResponse>, ApiError> {
+    let expiry = Config.read().await.server().session_expiry();
+    // checking the password runs scrypt, which would stall the runtime's workers
+    let (code, credentials) = tokio::task::spawn_blocking(move || {
+        let code = SessionService.login(&credentials.username, &credentials.password, expiry);
+        (code, credentials)
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+    let username: &str = &credentials.username;
+
+    match code {
+        AuthCode::Success(session, token) => {
+            tracing::info!(target: AUDIT, %address, username, "login succeeded");
+            Ok(format.reply(LoginResponse {
+                token: token.to_string(),
+                expires: session.expires().timestamp(),
+            }))
+        }
+        AuthCode::InvalidPassword => {
+            tracing::warn!(target: AUDIT, %address, username, "login failed: wrong password");
+            Err(ApiError::InvalidCredentials)
+        }
+        AuthCode::AccountNotFound => {
+            tracing::warn!(target: AUDIT, %address, username, "login failed: no such account");
+            Err(ApiError::InvalidCredentials)
         }
-        AuthCode::InvalidPassword => Err(BadRequestError(StatusCode::UNAUTHORIZED)),
-        AuthCode::AccountNotFound => Err(BadRequestError::default()),
     }
 }
@@ -0,0 +1,119 @@
+//! Layers wrapped around every endpoint. Their settings are read from the
+//! config on every request, so they follow config reloads.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use super::{
+    wire::{ErrorResponse, WireFormat},
+    ApiError,
+};
+use crate::services::{Config, MetricsService};
+
+/// Allows cross-origin requests from `server.cors_origins`, or from anywhere
+/// if none are set.
+pub fn cors() -> CorsLayer {
+    CorsLayer::permissive().allow_origin(AllowOrigin::async_predicate(
+        |origin: HeaderValue, _| async move {
+            let config = Config.read().await;
+            let allowed: &[String] = config.server().cors_origins();
+            allowed.is_empty()
+                || allowed
+                    .iter()
+                    .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes())
+        },
+    ))
+}
+
+/// Requests counted per address in the current minute, see [rate_limit].
+static REQUESTS: LazyLock<Mutex<RequestCounts>> = LazyLock::new(Mutex::default);
+
+#[derive(Default)]
+struct RequestCounts {
+    /// minutes since the epoch the counts are for
+    minute: i64,
+    counts: HashMap<IpAddr, u32>,
+}
+
+/// Answers 429 Too Many Requests once an address has made `server.rate_limit`
+/// requests in the current minute.
+pub async fn rate_limit(
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit: u32 = Config.read().await.server().rate_limit();
+    if limit > 0 {
+        let minute: i64 = Utc::now().timestamp() / 60;
+        let mut requests = REQUESTS.lock().unwrap();
+        if requests.minute != minute {
+            // a fresh window, which also forgets every address from the last one
+            *requests = RequestCounts {
+                minute,
+                counts: HashMap::new(),
+            };
+        }
+        let count = requests.counts.entry(address.ip()).or_default();
+        *count += 1;
+        if *count > limit {
+            tracing::debug!("rate limiting {}", address.ip());
+            return ApiError::RateLimited.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Re-encodes the body of [ApiError] responses in the format the client
+/// accepts, as errors are encoded as JSON without knowing the request.
+pub async fn error_format(request: Request, next: Next) -> Response {
+    let format: WireFormat = WireFormat::accepted(request.headers());
+    let mut response: Response = next.run(request).await;
+    if format == WireFormat::Json {
+        return response;
+    }
+    let Some(error) = response.extensions().get::<ErrorResponse>() else {
+        return response;
+    };
+    if let Ok(bytes) = format.encode(error) {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(format.content_type()),
+        );
+        response.headers_mut().remove(header::CONTENT_LENGTH);
+        *response.body_mut() = bytes.into();
+    }
+    response
+}
+
+/// Counts every request and how long it took to answer, by route, for
+/// `/metrics`. Requests no route matched are counted together.
+pub async fn metrics(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let route: String = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_owned();
+    let response: Response = next.run(request).await;
+    MetricsService.record_request(
+        method.as_str(),
+        &route,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    response
+}
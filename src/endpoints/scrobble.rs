@@ -0,0 +1,65 @@
+use axum::http::{HeaderMap, StatusCode};
+
+use crate::services::{AccountService, ScrobbleService};
+
+use super::{
+    authenticate,
+    wire::{ErrorResponse, Listen, Reply, ResponseFormat, ScrobbleTarget, Wire},
+    ApiError,
+};
+
+/// The handler function for the `/scrobble` endpoint. Records a play
+/// (a [Listen]) and queues it for every forwarding target of the user.
+#[utoipa::path(
+    post,
+    path = "/scrobble",
+    tag = "scrobbling",
+    summary = "Record a listen",
+    description = "Records a play and queues it for every forwarding target of the user.",
+    request_body = Listen,
+    security(("username" = [], "auth-token" = [])),
+    responses(
+        (status = ACCEPTED, description = "The listen is queued for forwarding"),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ErrorResponse),
+    )
+)]
+pub async fn scrobble(
+    headers: HeaderMap,
+    Wire(listen): Wire<Listen>,
+) -> Result<StatusCode, ApiError> {
+    let record = authenticate(&headers)?;
+    ScrobbleService.enqueue(record.data().scrobble_targets(), listen);
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// The handler function for the `/scrobble-targets` endpoint. Replaces the
+/// user's forwarding targets with the list in the body, and responds with
+/// the targets now stored.
+#[utoipa::path(
+    post,
+    path = "/scrobble-targets",
+    tag = "scrobbling",
+    summary = "Set forwarding targets",
+    description = "Replaces the ListenBrainz-compatible servers the user's listens are \
+                   forwarded to.",
+    request_body = Vec<ScrobbleTarget>,
+    security(("username" = [], "auth-token" = [])),
+    responses(
+        (status = OK, description = "The targets now stored", body = Vec<ScrobbleTarget>),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ErrorResponse),
+    )
+)]
+pub async fn set_scrobble_targets(
+    headers: HeaderMap,
+    ResponseFormat(format): ResponseFormat,
+    Wire(targets): Wire<Vec<ScrobbleTarget>>,
+) -> Result<Reply<Vec<ScrobbleTarget>>, ApiError> {
+    let record = authenticate(&headers)?;
+    AccountService.update_data(record.username(), |data| {
+        *data.scrobble_targets_mut() = targets;
+    })?;
+    let record = AccountService
+        .get(record.username())
+        .ok_or_else(|| ApiError::AccountNotFound(record.username().clone()))?;
+    Ok(format.reply(record.data().scrobble_targets().clone()))
+}
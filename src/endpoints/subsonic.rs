@@ -0,0 +1,265 @@
+//! # Subsonic Compatibility
+//! Serves the [Subsonic API](http://www.subsonic.org/pages/api.jsp) under
+//! `/rest/<method>.view` (the `.view` is optional), so the many existing
+//! Subsonic and OpenSubsonic clients can browse and play the library.
+//!
+//! Parameters come from the query string or a form-encoded POST body, and
+//! responses are XML unless `f=json` (or `f=jsonp` with a `callback`) asks
+//! otherwise. As the protocol demands, failures are still 200 OK responses,
+//! with an `error` element holding one of the codes of [SubsonicError].
+//!
+//! Clients log in with the account's username and its Subsonic password,
+//! which is set with `POST /api/v1/subsonic-password`. It's separate from
+//! the account password, as clients either send it as-is (`p`) or as the
+//! MD5 of it and a salt (`t` and `s`), so the server has to know it in the
+//! clear.
+//!
+//! Artists and albums come from the tags of the tracks in the library, see
+//! [catalog]. Music folders are the library roots, numbered from 1 in the
+//! order of the config. Files are streamed as they are, without transcoding.
+
+mod annotation;
+mod browse;
+mod catalog;
+mod media;
+mod playlists;
+mod queue;
+mod response;
+
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::Path,
+    http::{header, HeaderMap, Uri},
+    response::Response,
+    routing::get,
+    Router,
+};
+use md5::{Digest, Md5};
+use serde_json::{Map, Value};
+
+use crate::{service::auth::AUDIT, services::AccountService, types::AccountRecord};
+use response::Format;
+
+/// Version of the Subsonic API implemented.
+pub const API_VERSION: &str = "1.16.1";
+
+/// The routes of the compatibility layer.
+pub fn router() -> Router {
+    Router::new().route("/rest/{method}", get(dispatch).post(dispatch))
+}
+
+/// The ways a Subsonic request can fail, each with the error code the
+/// protocol assigns it.
+#[derive(thiserror::Error, Debug)]
+pub enum SubsonicError {
+    #[error("{0}")]
+    Generic(String),
+    #[error("required parameter `{0}` is missing")]
+    MissingParameter(&'static str),
+    #[error("wrong username or password")]
+    WrongCredentials,
+    #[error("provided authentication mechanism not supported")]
+    UnsupportedAuth,
+    #[error("multiple conflicting authentication mechanisms provided")]
+    ConflictingAuth,
+    #[error("user is not authorized for the given operation")]
+    NotAuthorized,
+    #[error("{0} not found")]
+    NotFound(&'static str),
+}
+
+impl SubsonicError {
+    pub fn code(&self) -> u32 {
+        match self {
+            SubsonicError::Generic(_) => 0,
+            SubsonicError::MissingParameter(_) => 10,
+            SubsonicError::WrongCredentials => 40,
+            SubsonicError::UnsupportedAuth => 42,
+            SubsonicError::ConflictingAuth => 43,
+            SubsonicError::NotAuthorized => 50,
+            SubsonicError::NotFound(_) => 70,
+        }
+    }
+}
+
+impl From<anyhow::Error> for SubsonicError {
+    fn from(error: anyhow::Error) -> Self {
+        tracing::error!("Subsonic request failed: {error:#}");
+        SubsonicError::Generic("internal server error".to_owned())
+    }
+}
+
+/// What a method responds with: fields of the `subsonic-response` element,
+/// or a file for `stream` and `getCoverArt`.
+enum Payload {
+    Fields(Map<String, Value>),
+    File(Response),
+}
+
+/// The parameters of a request, in the order they were sent.
+pub(super) struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of a parameter that may be repeated, like `id`.
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn require(&self, name: &'static str) -> Result<&str, SubsonicError> {
+        self.get(name).ok_or(SubsonicError::MissingParameter(name))
+    }
+
+    /// Parses the value of `name`, if it was sent.
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, SubsonicError> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| SubsonicError::Generic(format!("invalid value for `{name}`")))
+            })
+            .transpose()
+    }
+}
+
+/// The logged in user and what they asked for, handed to every method.
+pub(super) struct Request {
+    pub params: Params,
+    pub user: Arc<AccountRecord>,
+    pub headers: HeaderMap,
+}
+
+async fn dispatch(
+    Path(method): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut params: Vec<(String, String)> =
+        serde_urlencoded::from_str(uri.query().unwrap_or_default()).unwrap_or_default();
+    let is_form: bool = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        params.extend(
+            serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap_or_default(),
+        );
+    }
+    let params = Params(params);
+    let format = Format::of(&params);
+    let method: &str = method.strip_suffix(".view").unwrap_or(&method);
+
+    let result = match authenticate(&params) {
+        Ok(user) => {
+            let request = Request {
+                params,
+                user,
+                headers,
+            };
+            call(method, &request).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(Payload::Fields(fields)) => format.respond(Ok(fields)),
+        Ok(Payload::File(response)) => response,
+        Err(e) => format.respond(Err(e)),
+    }
+}
+
+/// Runs the method called `method`.
+async fn call(method: &str, request: &Request) -> Result<Payload, SubsonicError> {
+    let fields = match method {
+        "ping" => Map::new(),
+        "getLicense" => browse::get_license(),
+        "getOpenSubsonicExtensions" => browse::get_open_subsonic_extensions(),
+        "getMusicFolders" => browse::get_music_folders().await,
+        "getIndexes" => browse::get_indexes(request).await?,
+        "getArtists" => browse::get_artists(request).await?,
+        "getArtist" => browse::get_artist(request).await?,
+        "getMusicDirectory" => browse::get_music_directory(request).await?,
+        "getAlbum" => browse::get_album(request).await?,
+        "getSong" => browse::get_song(request).await?,
+        "search3" => browse::search3(request).await?,
+        "stream" | "download" => return media::stream(request).await.map(Payload::File),
+        "getCoverArt" => return media::get_cover_art(request).await.map(Payload::File),
+        "getPlaylists" => playlists::get_playlists(request).await?,
+        "getPlaylist" => playlists::get_playlist(request).await?,
+        "createPlaylist" => playlists::create_playlist(request).await?,
+        "updatePlaylist" => playlists::update_playlist(request)?,
+        "deletePlaylist" => playlists::delete_playlist(request)?,
+        "scrobble" => annotation::scrobble(request)?,
+        "star" => annotation::star(request, true)?,
+        "unstar" => annotation::star(request, false)?,
+        "getStarred" => annotation::get_starred(request, "starred").await?,
+        "getStarred2" => annotation::get_starred(request, "starred2").await?,
+        "getPlayQueue" => queue::get_play_queue(request).await?,
+        "savePlayQueue" => queue::save_play_queue(request)?,
+        _ => {
+            return Err(SubsonicError::Generic(format!(
+                "method {method} isn't supported"
+            )))
+        }
+    };
+    Ok(Payload::Fields(fields))
+}
+
+/// Checks the credentials in `params` against the Subsonic password of the
+/// account, see the module docs.
+fn authenticate(params: &Params) -> Result<Arc<AccountRecord>, SubsonicError> {
+    let username: &str = params.require("u")?;
+    let record: Option<Arc<AccountRecord>> = AccountService.get(username);
+    let expected: Option<&str> = record
+        .as_ref()
+        .and_then(|record| record.data().subsonic_password().as_ref())
+        .map(|password| password.expose());
+
+    let matches: bool = match (params.get("t"), params.get("s"), params.get("p")) {
+        _ if params.get("apiKey").is_some() => return Err(SubsonicError::UnsupportedAuth),
+        (Some(token), Some(salt), None) => expected.is_some_and(|expected| {
+            let digest = Md5::digest(format!("{expected}{salt}").as_bytes());
+            constant_time_eq(&hex::encode(digest), &token.to_ascii_lowercase())
+        }),
+        (None, None, Some(password)) => {
+            let password: String = match password.strip_prefix("enc:") {
+                Some(encoded) => hex::decode(encoded)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .unwrap_or_default(),
+                None => password.to_owned(),
+            };
+            expected.is_some_and(|expected| constant_time_eq(expected, &password))
+        }
+        (None, None, None) => return Err(SubsonicError::MissingParameter("t")),
+        _ => return Err(SubsonicError::ConflictingAuth),
+    };
+    match record {
+        Some(record) if matches => Ok(record),
+        _ => {
+            tracing::warn!(target: AUDIT, username, "subsonic login failed");
+            Err(SubsonicError::WrongCredentials)
+        }
+    }
+}
+
+/// Compares secrets without returning early, so the time taken doesn't
+/// tell how much of a guess was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,116 @@
+//! Methods recording what the user listens to and likes.
+
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+
+use super::{browse::fields, catalog::Catalog, Request, SubsonicError};
+use crate::{
+    service::scrobble::Listen,
+    services::{LibraryService, ScrobbleService, UserDataService},
+};
+
+/// Forwards plays of the tracks in `id` to the user's scrobble targets, each
+/// at the matching `time` in milliseconds, or now. `submission=false` only
+/// announces what's playing, which the targets have no use for.
+pub(super) fn scrobble(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    if params.parse::<bool>("submission")? == Some(false) {
+        return Ok(Map::new());
+    }
+    let ids: Vec<&str> = params.all("id").collect();
+    if ids.is_empty() {
+        return Err(SubsonicError::MissingParameter("id"));
+    }
+    let times: Vec<i64> = params
+        .all("time")
+        .map(|time| {
+            time.parse()
+                .map_err(|_| SubsonicError::Generic("invalid value for `time`".into()))
+        })
+        .collect::<Result<_, _>>()?;
+    // every id is resolved before anything is queued, so a client retrying
+    // after an unknown id doesn't submit the other plays twice
+    let listens: Vec<Listen> = ids
+        .into_iter()
+        .enumerate()
+        .map(|(i, id)| {
+            let track = LibraryService
+                .get(id)
+                .ok_or(SubsonicError::NotFound("song"))?;
+            Ok(Listen {
+                listened_at: times
+                    .get(i)
+                    .map_or_else(|| Utc::now().timestamp(), |ms| ms / 1000),
+                artist: track
+                    .artist
+                    .clone()
+                    .or_else(|| track.album_artist.clone())
+                    .unwrap_or_else(|| "Unknown Artist".to_owned()),
+                track: track.title.clone(),
+                release: track.album.clone(),
+                duration_secs: track.duration_secs,
+            })
+        })
+        .collect::<Result<_, SubsonicError>>()?;
+    let data = request.user.data();
+    for listen in listens {
+        ScrobbleService.enqueue(data.scrobble_targets(), listen);
+    }
+    Ok(Map::new())
+}
+
+/// Stars, or unstars, the tracks in `id`, albums in `albumId` and artists in
+/// `artistId`.
+pub(super) fn star(request: &Request, starred: bool) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    let ids: Vec<String> = ["id", "albumId", "artistId"]
+        .into_iter()
+        .flat_map(|name| params.all(name))
+        .map(str::to_owned)
+        .collect();
+    if ids.is_empty() {
+        return Err(SubsonicError::MissingParameter("id"));
+    }
+    let now: i64 = Utc::now().timestamp();
+    UserDataService.update(request.user.username(), |data| {
+        for id in ids {
+            if starred {
+                data.starred.entry(id).or_insert(now);
+            } else {
+                data.starred.remove(&id);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(Map::new())
+}
+
+/// Everything the user starred, under `key`: `starred` for the folder-based
+/// `getStarred`, `starred2` for `getStarred2`. Starred things no longer in
+/// the library are left out.
+pub(super) async fn get_starred(
+    request: &Request,
+    key: &str,
+) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(request.params.parse("musicFolderId")?).await;
+    let user = UserDataService.get(request.user.username());
+    let mut artists: Vec<Value> = Vec::new();
+    let mut albums: Vec<Value> = Vec::new();
+    let mut songs: Vec<Value> = Vec::new();
+    for id in user.starred.keys() {
+        if let Some(artist) = catalog.artists.get(id) {
+            artists.push(catalog.artist(artist, &user));
+        } else if let Some(album) = catalog.albums.get(id) {
+            albums.push(match key {
+                "starred" => catalog.album_directory(album, &user),
+                _ => catalog.album(album, &user),
+            });
+        } else if let Some(track) = catalog.tracks.get(id) {
+            songs.push(catalog.song(track, &user));
+        }
+    }
+    Ok(fields(
+        key,
+        json!({ "artist": artists, "album": albums, "song": songs }),
+    ))
+}
@@ -0,0 +1,277 @@
+//! Methods for browsing and searching the library.
+
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use serde_json::{json, Map, Value};
+
+use super::{
+    catalog::{Artist, Catalog},
+    Request, SubsonicError,
+};
+use crate::{
+    service::{library::Track, user_data::UserData},
+    services::UserDataService,
+};
+
+/// Leading words left out when sorting and indexing artists.
+const IGNORED_ARTICLES: &str = "The El La Los Las Le Les";
+
+/// Turns `value` into the fields of a response, under `name`.
+pub(super) fn fields(name: &str, value: Value) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert(name.to_owned(), value);
+    fields
+}
+
+pub(super) fn get_license() -> Map<String, Value> {
+    fields("license", json!({ "valid": true }))
+}
+
+pub(super) fn get_open_subsonic_extensions() -> Map<String, Value> {
+    fields("openSubsonicExtensions", json!([]))
+}
+
+pub(super) async fn get_music_folders() -> Map<String, Value> {
+    let catalog = Catalog::load(None).await;
+    let folders: Vec<Value> = catalog
+        .folders
+        .iter()
+        .enumerate()
+        .map(|(i, root)| json!({ "id": i + 1, "name": folder_name(root) }))
+        .collect();
+    fields("musicFolders", json!({ "musicFolder": folders }))
+}
+
+pub(super) async fn get_indexes(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(request.params.parse("musicFolderId")?).await;
+    let user = UserDataService.get(request.user.username());
+    let last_modified: i64 = catalog
+        .tracks
+        .values()
+        .map(|track| track.modified * 1000)
+        .max()
+        .unwrap_or_default();
+    let unchanged: bool = request
+        .params
+        .parse::<i64>("ifModifiedSince")?
+        .is_some_and(|since| since >= last_modified);
+    let index: Vec<Value> = match unchanged {
+        true => Vec::new(),
+        false => index(&catalog, |artist| {
+            json!({
+                "id": artist.id,
+                "name": artist.name,
+                "starred": catalog.artist(artist, &user)["starred"],
+            })
+        }),
+    };
+    Ok(fields(
+        "indexes",
+        json!({
+            "ignoredArticles": IGNORED_ARTICLES,
+            "lastModified": last_modified,
+            "index": index,
+        }),
+    ))
+}
+
+pub(super) async fn get_artists(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(request.params.parse("musicFolderId")?).await;
+    let user = UserDataService.get(request.user.username());
+    let index: Vec<Value> = index(&catalog, |artist| catalog.artist(artist, &user));
+    Ok(fields(
+        "artists",
+        json!({ "ignoredArticles": IGNORED_ARTICLES, "index": index }),
+    ))
+}
+
+pub(super) async fn get_artist(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(None).await;
+    let user = UserDataService.get(request.user.username());
+    let artist = catalog
+        .artists
+        .get(request.params.require("id")?)
+        .ok_or(SubsonicError::NotFound("artist"))?;
+    let mut value: Value = catalog.artist(artist, &user);
+    value["album"] = artist
+        .albums
+        .iter()
+        .map(|id| catalog.album(&catalog.albums[id], &user))
+        .collect();
+    Ok(fields("artist", value))
+}
+
+/// Artists hold their albums, and albums their songs.
+pub(super) async fn get_music_directory(
+    request: &Request,
+) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(None).await;
+    let user = UserDataService.get(request.user.username());
+    let id: &str = request.params.require("id")?;
+    let directory: Value = if let Some(artist) = catalog.artists.get(id) {
+        let children: Vec<Value> = artist
+            .albums
+            .iter()
+            .map(|id| catalog.album_directory(&catalog.albums[id], &user))
+            .collect();
+        json!({
+            "id": artist.id,
+            "name": artist.name,
+            "starred": catalog.artist(artist, &user)["starred"],
+            "child": children,
+        })
+    } else if let Some(album) = catalog.albums.get(id) {
+        json!({
+            "id": album.id,
+            "parent": album.artist_id,
+            "name": album.name,
+            "starred": catalog.album(album, &user)["starred"],
+            "child": songs(&catalog, &album.tracks, &user),
+        })
+    } else {
+        return Err(SubsonicError::NotFound("directory"));
+    };
+    Ok(fields("directory", directory))
+}
+
+pub(super) async fn get_album(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(None).await;
+    let user = UserDataService.get(request.user.username());
+    let album = catalog
+        .albums
+        .get(request.params.require("id")?)
+        .ok_or(SubsonicError::NotFound("album"))?;
+    let mut value: Value = catalog.album(album, &user);
+    value["song"] = songs(&catalog, &album.tracks, &user).into();
+    Ok(fields("album", value))
+}
+
+pub(super) async fn get_song(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let catalog = Catalog::load(None).await;
+    let user = UserDataService.get(request.user.username());
+    let track = catalog
+        .tracks
+        .get(request.params.require("id")?)
+        .ok_or(SubsonicError::NotFound("song"))?;
+    Ok(fields("song", catalog.song(track, &user)))
+}
+
+/// Finds artists, albums and songs whose names hold every word of `query`.
+/// An empty query matches everything, which clients use to sync the whole
+/// library page by page.
+pub(super) async fn search3(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    let catalog = Catalog::load(params.parse("musicFolderId")?).await;
+    let user = UserDataService.get(request.user.username());
+    let query: String = params.require("query")?.trim_matches('"').to_lowercase();
+    let words: Vec<&str> = query.split_whitespace().collect();
+    let matches = |text: String| {
+        let text: String = text.to_lowercase();
+        words.iter().all(|word| text.contains(word))
+    };
+    let page = |kind: &str| -> Result<(usize, usize), SubsonicError> {
+        Ok((
+            params.parse(&format!("{kind}Offset"))?.unwrap_or(0),
+            params.parse(&format!("{kind}Count"))?.unwrap_or(20),
+        ))
+    };
+
+    let (offset, count) = page("artist")?;
+    let mut artists: Vec<&Artist> = catalog
+        .artists
+        .values()
+        .filter(|artist| matches(artist.name.clone()))
+        .collect();
+    artists.sort_by_key(|artist| sort_name(&artist.name).to_lowercase());
+    let artists: Vec<Value> = artists
+        .into_iter()
+        .skip(offset)
+        .take(count)
+        .map(|artist| catalog.artist(artist, &user))
+        .collect();
+
+    let (offset, count) = page("album")?;
+    let albums: Vec<Value> = catalog
+        .albums
+        .values()
+        .filter(|album| matches(format!("{} {}", album.name, album.artist)))
+        .skip(offset)
+        .take(count)
+        .map(|album| catalog.album(album, &user))
+        .collect();
+
+    let (offset, count) = page("song")?;
+    let mut tracks: Vec<&Arc<Track>> = catalog
+        .tracks
+        .values()
+        .filter(|track| {
+            matches(format!(
+                "{} {} {}",
+                track.title,
+                track.artist.as_deref().unwrap_or_default(),
+                track.album.as_deref().unwrap_or_default()
+            ))
+        })
+        .collect();
+    tracks.sort_by(|a, b| a.path.cmp(&b.path)); // stable across pages
+    let tracks: Vec<Arc<Track>> = tracks
+        .into_iter()
+        .skip(offset)
+        .take(count)
+        .cloned()
+        .collect();
+
+    Ok(fields(
+        "searchResult3",
+        json!({
+            "artist": artists,
+            "album": albums,
+            "song": songs(&catalog, &tracks, &user),
+        }),
+    ))
+}
+
+pub(super) fn songs(catalog: &Catalog, tracks: &[Arc<Track>], user: &UserData) -> Vec<Value> {
+    tracks
+        .iter()
+        .map(|track| catalog.song(track, user))
+        .collect()
+}
+
+/// Every artist, grouped by the first letter of their name once ignored
+/// articles are left out, as `index` elements.
+fn index(catalog: &Catalog, artist: impl Fn(&Artist) -> Value) -> Vec<Value> {
+    let mut groups: BTreeMap<String, Vec<&Artist>> = BTreeMap::new();
+    for entry in catalog.artists.values() {
+        let letter: String = match sort_name(&entry.name).chars().next() {
+            Some(first) if first.is_alphabetic() => first.to_uppercase().collect(),
+            _ => "#".to_owned(),
+        };
+        groups.entry(letter).or_default().push(entry);
+    }
+    groups
+        .into_iter()
+        .map(|(letter, mut artists)| {
+            artists.sort_by_key(|entry| sort_name(&entry.name).to_lowercase());
+            let artists: Vec<Value> = artists.into_iter().map(&artist).collect();
+            json!({ "name": letter, "artist": artists })
+        })
+        .collect()
+}
+
+/// `name` without a leading article, like "Beatles" for "The Beatles".
+fn sort_name(name: &str) -> &str {
+    IGNORED_ARTICLES
+        .split(' ')
+        .find_map(|article| {
+            name.strip_prefix(article)
+                .and_then(|rest| rest.strip_prefix(' '))
+        })
+        .unwrap_or(name)
+}
+
+fn folder_name(root: &Path) -> String {
+    root.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| root.display().to_string())
+}
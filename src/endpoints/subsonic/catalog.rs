@@ -0,0 +1,269 @@
+//! The artists and albums Subsonic clients browse, grouped from the tags of
+//! the tracks in the library on every request, so they're never out of date
+//! with a scan. Tracks are grouped into albums by album name and album
+//! artist (or artist, if there's none), and albums into artists by the
+//! latter. Their ids are hashes of those names, so they stay the same as
+//! long as the tags do.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use chrono::{DateTime, SecondsFormat};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    service::{library::Track, user_data::UserData},
+    services::{Config, LibraryService},
+};
+
+const UNKNOWN_ARTIST: &str = "Unknown Artist";
+const UNKNOWN_ALBUM: &str = "Unknown Album";
+
+pub(super) struct Artist {
+    pub id: String,
+    pub name: String,
+    /// album ids, oldest first
+    pub albums: Vec<String>,
+}
+
+pub(super) struct Album {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub artist_id: String,
+    /// in disc and track order
+    pub tracks: Vec<Arc<Track>>,
+}
+
+impl Album {
+    fn year(&self) -> Option<i32> {
+        self.tracks.iter().find_map(|track| track.year)
+    }
+
+    fn genre(&self) -> Option<&str> {
+        self.tracks.iter().find_map(|track| track.genre.as_deref())
+    }
+
+    fn duration(&self) -> u32 {
+        self.tracks
+            .iter()
+            .filter_map(|track| track.duration_secs)
+            .sum()
+    }
+
+    /// When the newest of its files was last changed.
+    fn created(&self) -> i64 {
+        self.tracks
+            .iter()
+            .map(|track| track.modified)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+pub(super) struct Catalog {
+    /// the library roots, music folder `n` is `folders[n - 1]`
+    pub folders: Vec<PathBuf>,
+    pub artists: BTreeMap<String, Artist>,
+    pub albums: BTreeMap<String, Album>,
+    pub tracks: HashMap<String, Arc<Track>>,
+}
+
+impl Catalog {
+    /// Groups the tracks of the library, only those in music folder `folder`
+    /// if it's set.
+    pub async fn load(folder: Option<usize>) -> Self {
+        let folders: Vec<PathBuf> = Config
+            .read()
+            .await
+            .library()
+            .roots()
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let root: Option<&PathBuf> = folder.and_then(|folder| folders.get(folder.checked_sub(1)?));
+        let tracks: Vec<Arc<Track>> = LibraryService
+            .tracks()
+            .into_iter()
+            .filter(|track| folder.is_none() || root == Some(&track.root))
+            .collect();
+
+        let mut artists: BTreeMap<String, Artist> = BTreeMap::new();
+        let mut albums: BTreeMap<String, Album> = BTreeMap::new();
+        for track in &tracks {
+            let artist_name: &str = track.album_artist_or_artist().unwrap_or(UNKNOWN_ARTIST);
+            let album_name: &str = track.album.as_deref().unwrap_or(UNKNOWN_ALBUM);
+            let artist_id: String = id("ar", &[artist_name]);
+            let album_id: String = id("al", &[artist_name, album_name]);
+            let artist = artists.entry(artist_id.clone()).or_insert_with(|| Artist {
+                id: artist_id.clone(),
+                name: artist_name.to_owned(),
+                albums: Vec::new(),
+            });
+            if !artist.albums.contains(&album_id) {
+                artist.albums.push(album_id.clone());
+            }
+            albums
+                .entry(album_id.clone())
+                .or_insert_with(|| Album {
+                    id: album_id,
+                    name: album_name.to_owned(),
+                    artist: artist_name.to_owned(),
+                    artist_id,
+                    tracks: Vec::new(),
+                })
+                .tracks
+                .push(track.clone());
+        }
+        for album in albums.values_mut() {
+            album.tracks.sort_by(|a, b| {
+                (a.disc_number, a.track_number, &a.title).cmp(&(
+                    b.disc_number,
+                    b.track_number,
+                    &b.title,
+                ))
+            });
+        }
+        for artist in artists.values_mut() {
+            artist
+                .albums
+                .sort_by_key(|id| (albums[id].year(), albums[id].name.clone()));
+        }
+
+        Self {
+            folders,
+            artists,
+            albums,
+            tracks: tracks
+                .into_iter()
+                .map(|track| (track.id.clone(), track))
+                .collect(),
+        }
+    }
+
+    /// The album `track` was grouped into.
+    pub fn album_of(&self, track: &Track) -> Option<&Album> {
+        let artist_name: &str = track.album_artist_or_artist().unwrap_or(UNKNOWN_ARTIST);
+        let album_name: &str = track.album.as_deref().unwrap_or(UNKNOWN_ALBUM);
+        self.albums.get(&id("al", &[artist_name, album_name]))
+    }
+
+    /// A track as a Subsonic `Child`.
+    pub fn song(&self, track: &Track, user: &UserData) -> Value {
+        let album: Option<&Album> = self.album_of(track);
+        let suffix: String = track
+            .path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let path: String = track
+            .path
+            .strip_prefix(&track.root)
+            .unwrap_or(&track.path)
+            .to_string_lossy()
+            .into_owned();
+        json!({
+            "id": track.id,
+            "parent": album.map(|album| &album.id),
+            "isDir": false,
+            "title": track.title,
+            "album": track.album,
+            "artist": track.artist.as_deref().or(album.map(|album| album.artist.as_str())),
+            "track": track.track_number,
+            "discNumber": track.disc_number,
+            "year": track.year,
+            "genre": track.genre,
+            "coverArt": album.map(|album| &album.id),
+            "size": track.size,
+            "contentType": content_type(&suffix),
+            "suffix": suffix,
+            "duration": track.duration_secs,
+            "path": path,
+            "created": timestamp(track.modified),
+            "albumId": album.map(|album| &album.id),
+            "artistId": album.map(|album| &album.artist_id),
+            "type": "music",
+            "mediaType": "song",
+            "starred": starred(user, &track.id),
+        })
+    }
+
+    /// An album as a Subsonic `AlbumID3`.
+    pub fn album(&self, album: &Album, user: &UserData) -> Value {
+        json!({
+            "id": album.id,
+            "name": album.name,
+            "artist": album.artist,
+            "artistId": album.artist_id,
+            "coverArt": album.id,
+            "songCount": album.tracks.len(),
+            "duration": album.duration(),
+            "created": timestamp(album.created()),
+            "year": album.year(),
+            "genre": album.genre(),
+            "starred": starred(user, &album.id),
+        })
+    }
+
+    /// An album as a directory `Child`, for the folder-based methods.
+    pub fn album_directory(&self, album: &Album, user: &UserData) -> Value {
+        json!({
+            "id": album.id,
+            "parent": album.artist_id,
+            "isDir": true,
+            "title": album.name,
+            "album": album.name,
+            "artist": album.artist,
+            "year": album.year(),
+            "genre": album.genre(),
+            "coverArt": album.id,
+            "created": timestamp(album.created()),
+            "starred": starred(user, &album.id),
+        })
+    }
+
+    /// An artist as a Subsonic `ArtistID3`.
+    pub fn artist(&self, artist: &Artist, user: &UserData) -> Value {
+        json!({
+            "id": artist.id,
+            "name": artist.name,
+            "albumCount": artist.albums.len(),
+            "coverArt": artist.albums.first(),
+            "starred": starred(user, &artist.id),
+        })
+    }
+}
+
+/// An id for the names in `parts`, starting with `prefix` so ids of
+/// artists, albums and tracks never collide.
+fn id(prefix: &str, parts: &[&str]) -> String {
+    let digest = Sha256::digest(parts.join("\0").to_lowercase().as_bytes());
+    format!("{prefix}-{}", hex::encode(&digest[..8]))
+}
+
+/// A unix timestamp the way Subsonic writes dates.
+pub(super) fn timestamp(secs: i64) -> Option<String> {
+    DateTime::from_timestamp(secs, 0).map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn starred(user: &UserData, id: &str) -> Option<String> {
+    user.starred.get(id).and_then(|&secs| timestamp(secs))
+}
+
+/// The MIME type of audio files ending in `suffix`.
+pub(super) fn content_type(suffix: &str) -> &'static str {
+    match suffix {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "aif" | "aiff" => "audio/aiff",
+        "wma" => "audio/x-ms-wma",
+        _ => "application/octet-stream",
+    }
+}
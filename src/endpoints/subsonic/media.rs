@@ -0,0 +1,101 @@
+//! Methods sending files rather than a `subsonic-response`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use tower_http::services::ServeFile;
+
+use super::{catalog::Catalog, Request, SubsonicError};
+use crate::{
+    service::library::Track,
+    services::{LibraryService, MetricsService},
+};
+
+/// File names, without extension, looked for next to a track as its cover.
+const COVER_NAMES: [&str; 5] = ["cover", "folder", "front", "album", "albumart"];
+const COVER_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Sends the file of a track as it is, honoring `Range` so clients can seek.
+pub(super) async fn stream(request: &Request) -> Result<Response, SubsonicError> {
+    let track = LibraryService
+        .get(request.params.require("id")?)
+        .ok_or(SubsonicError::NotFound("song"))?;
+    let response: Response = serve_file(&track.path, request).await?;
+    if response.status().is_success() {
+        let length: Option<u64> = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse().ok());
+        MetricsService.add_stream_bytes(length.unwrap_or_default());
+    }
+    Ok(response)
+}
+
+/// Sends the cover of a track, or of the first track of an album or
+/// artist: an image like `cover.jpg` next to it, else the one in its tags.
+pub(super) async fn get_cover_art(request: &Request) -> Result<Response, SubsonicError> {
+    let id: &str = request.params.require("id")?;
+    let track: Arc<Track> = match LibraryService.get(id) {
+        Some(track) => track,
+        None => {
+            let catalog = Catalog::load(None).await;
+            let album = match catalog.artists.get(id) {
+                Some(artist) => artist.albums.first().and_then(|id| catalog.albums.get(id)),
+                None => catalog.albums.get(id),
+            };
+            album
+                .and_then(|album| album.tracks.first().cloned())
+                .ok_or(SubsonicError::NotFound("cover art"))?
+        }
+    };
+
+    if let Some(cover) = cover_file(&track.path).await {
+        return serve_file(&cover, request).await;
+    }
+    let path: PathBuf = track.path.clone();
+    let embedded = tokio::task::spawn_blocking(move || {
+        let tag = id3::Tag::read_from_path(path).ok()?;
+        let picture = tag.pictures().next()?;
+        Some((picture.mime_type.clone(), picture.data.clone()))
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+    let (mime_type, data) = embedded.ok_or(SubsonicError::NotFound("cover art"))?;
+    let content_type = HeaderValue::try_from(mime_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    Ok(([(header::CONTENT_TYPE, content_type)], data).into_response())
+}
+
+/// An image in the directory of `track` named like a cover.
+async fn cover_file(track: &Path) -> Option<PathBuf> {
+    let mut entries = tokio::fs::read_dir(track.parent()?).await.ok()?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path: PathBuf = entry.path();
+        let (Some(stem), Some(extension)) = (path.file_stem(), path.extension()) else {
+            continue;
+        };
+        let stem: String = stem.to_string_lossy().to_lowercase();
+        let extension: String = extension.to_string_lossy().to_lowercase();
+        if COVER_NAMES.contains(&stem.as_str()) && COVER_EXTENSIONS.contains(&extension.as_str()) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+async fn serve_file(path: &Path, request: &Request) -> Result<Response, SubsonicError> {
+    let mut file_request = axum::http::Request::new(Body::empty());
+    *file_request.headers_mut() = request.headers.clone();
+    let response = ServeFile::new(path)
+        .try_call(file_request)
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(response.map(Body::new))
+}
@@ -0,0 +1,223 @@
+//! Playlist methods. Playlists belong to the user who made them, who alone
+//! may change them, and are visible to everyone else once made public.
+
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+
+use super::{
+    browse::{fields, songs},
+    catalog::{timestamp, Catalog},
+    Request, SubsonicError,
+};
+use crate::{
+    service::{events::EventKind, user_data::Playlist},
+    services::{EventService, LibraryService, UserDataService},
+};
+
+pub(super) async fn get_playlists(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let username: &str = request.user.username();
+    let catalog = Catalog::load(None).await;
+    let mut playlists: Vec<(String, Playlist)> = UserDataService
+        .all()
+        .into_iter()
+        .flat_map(|(owner, data)| {
+            data.playlists
+                .iter()
+                .filter(|playlist| owner == username || playlist.public)
+                .map(|playlist| (owner.clone(), playlist.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    playlists.sort_by(|(_, a), (_, b)| (a.created, &a.id).cmp(&(b.created, &b.id)));
+    let playlists: Vec<Value> = playlists
+        .iter()
+        .map(|(owner, playlist)| summary(&catalog, owner, playlist))
+        .collect();
+    Ok(fields("playlists", json!({ "playlist": playlists })))
+}
+
+pub(super) async fn get_playlist(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let (owner, playlist) = find(request.params.require("id")?)?;
+    if owner != *request.user.username() && !playlist.public {
+        return Err(SubsonicError::NotFound("playlist"));
+    }
+    Ok(fields(
+        "playlist",
+        with_entries(&owner, &playlist, request).await,
+    ))
+}
+
+/// Makes a playlist called `name`, or replaces the tracks of `playlistId`,
+/// with the tracks in `songId`.
+pub(super) async fn create_playlist(
+    request: &Request,
+) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    let username: &str = request.user.username();
+    let tracks: Vec<String> = track_ids(params.all("songId"))?;
+    let playlist: Playlist = match params.get("playlistId") {
+        Some(id) => {
+            owned(id, username)?;
+            UserDataService.update(username, |data| {
+                let playlist = playlist_mut(&mut data.playlists, id)?;
+                playlist.tracks = tracks;
+                playlist.changed = Utc::now().timestamp();
+                Ok(playlist.clone())
+            })?
+        }
+        None => {
+            let mut playlist = Playlist::new(params.require("name")?.to_owned());
+            playlist.tracks = tracks;
+            UserDataService.update(username, |data| {
+                data.playlists.push(playlist.clone());
+                Ok(playlist)
+            })?
+        }
+    };
+    announce(username, &playlist.id, playlist.public, false);
+    Ok(fields(
+        "playlist",
+        with_entries(username, &playlist, request).await,
+    ))
+}
+
+/// Renames `playlistId`, changes its comment or visibility, and removes the
+/// tracks at `songIndexToRemove` before adding those in `songIdToAdd`.
+pub(super) fn update_playlist(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    let username: &str = request.user.username();
+    let id: &str = params.require("playlistId")?;
+    let was_public: bool = owned(id, username)?.public;
+    let added: Vec<String> = track_ids(params.all("songIdToAdd"))?;
+    let mut removed: Vec<usize> = params
+        .all("songIndexToRemove")
+        .map(|index| {
+            index
+                .parse()
+                .map_err(|_| SubsonicError::Generic("invalid value for `songIndexToRemove`".into()))
+        })
+        .collect::<Result<_, _>>()?;
+    removed.sort_unstable();
+    removed.dedup();
+    let make_public: Option<bool> = params.parse("public")?;
+
+    let public: bool = UserDataService.update(username, |data| {
+        let playlist = playlist_mut(&mut data.playlists, id)?;
+        if let Some(name) = params.get("name") {
+            playlist.name = name.to_owned();
+        }
+        if let Some(comment) = params.get("comment") {
+            playlist.comment = Some(comment.to_owned()).filter(|comment| !comment.is_empty());
+        }
+        if let Some(public) = make_public {
+            playlist.public = public;
+        }
+        // from the back, so the indices of those left to remove don't shift
+        for &index in removed.iter().rev() {
+            if index < playlist.tracks.len() {
+                playlist.tracks.remove(index);
+            }
+        }
+        playlist.tracks.extend(added);
+        playlist.changed = Utc::now().timestamp();
+        Ok(playlist.public)
+    })?;
+    announce(username, id, was_public || public, false);
+    Ok(Map::new())
+}
+
+pub(super) fn delete_playlist(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let username: &str = request.user.username();
+    let id: &str = request.params.require("id")?;
+    let playlist: Playlist = owned(id, username)?;
+    UserDataService.update(username, |data| {
+        data.playlists.retain(|playlist| playlist.id != id);
+        Ok(())
+    })?;
+    announce(username, id, playlist.public, true);
+    Ok(Map::new())
+}
+
+/// The playlist with `id` and the name of its owner.
+fn find(id: &str) -> Result<(String, Playlist), SubsonicError> {
+    UserDataService
+        .all()
+        .into_iter()
+        .find_map(|(owner, data)| {
+            let playlist = data.playlists.iter().find(|playlist| playlist.id == id)?;
+            Some((owner, playlist.clone()))
+        })
+        .ok_or(SubsonicError::NotFound("playlist"))
+}
+
+/// The playlist with `id`, unless `username` doesn't own it.
+fn owned(id: &str, username: &str) -> Result<Playlist, SubsonicError> {
+    let (owner, playlist) = find(id)?;
+    match owner == username {
+        true => Ok(playlist),
+        false if playlist.public => Err(SubsonicError::NotAuthorized),
+        false => Err(SubsonicError::NotFound("playlist")),
+    }
+}
+
+/// Tells whoever can see the playlist with `id` that it changed: everyone
+/// if it's `public`, or was before the change, else only its owner.
+fn announce(owner: &str, id: &str, public: bool, deleted: bool) {
+    let (playlist, owner) = (id.to_owned(), owner.to_owned());
+    let audience: Option<String> = (!public).then(|| owner.clone());
+    let kind = match deleted {
+        true => EventKind::PlaylistDeleted { playlist, owner },
+        false => EventKind::PlaylistChanged { playlist, owner },
+    };
+    EventService.publish(audience.as_deref(), kind);
+}
+
+fn playlist_mut<'a>(playlists: &'a mut [Playlist], id: &str) -> anyhow::Result<&'a mut Playlist> {
+    playlists
+        .iter_mut()
+        .find(|playlist| playlist.id == id)
+        .ok_or_else(|| anyhow::anyhow!("playlist {id} is gone"))
+}
+
+/// Checks that every id in `ids` is a track in the library.
+fn track_ids<'a>(ids: impl Iterator<Item = &'a str>) -> Result<Vec<String>, SubsonicError> {
+    ids.map(|id| match LibraryService.get(id) {
+        Some(_) => Ok(id.to_owned()),
+        None => Err(SubsonicError::NotFound("song")),
+    })
+    .collect()
+}
+
+/// A playlist without its tracks, tracks missing from the library left out.
+fn summary(catalog: &Catalog, owner: &str, playlist: &Playlist) -> Value {
+    let tracks: Vec<_> = playlist
+        .tracks
+        .iter()
+        .filter_map(|id| catalog.tracks.get(id))
+        .collect();
+    json!({
+        "id": playlist.id,
+        "name": playlist.name,
+        "comment": playlist.comment,
+        "owner": owner,
+        "public": playlist.public,
+        "songCount": tracks.len(),
+        "duration": tracks.iter().filter_map(|track| track.duration_secs).sum::<u32>(),
+        "created": timestamp(playlist.created),
+        "changed": timestamp(playlist.changed),
+        "coverArt": tracks.first().and_then(|track| catalog.album_of(track)).map(|album| &album.id),
+    })
+}
+
+async fn with_entries(owner: &str, playlist: &Playlist, request: &Request) -> Value {
+    let catalog = Catalog::load(None).await;
+    let user = UserDataService.get(request.user.username());
+    let tracks: Vec<_> = playlist
+        .tracks
+        .iter()
+        .filter_map(|id| catalog.tracks.get(id).cloned())
+        .collect();
+    let mut value: Value = summary(&catalog, owner, playlist);
+    value["entry"] = songs(&catalog, &tracks, &user).into();
+    value
+}
@@ -0,0 +1,74 @@
+//! Methods keeping the play queue in sync across the devices of a user.
+
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+
+use super::{
+    browse::{fields, songs},
+    catalog::{timestamp, Catalog},
+    Request, SubsonicError,
+};
+use crate::{
+    service::{events::EventKind, user_data::PlayQueue},
+    services::{EventService, LibraryService, UserDataService},
+};
+
+pub(super) async fn get_play_queue(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let username: &str = request.user.username();
+    let user = UserDataService.get(username);
+    let Some(queue) = &user.play_queue else {
+        return Ok(Map::new());
+    };
+    let catalog = Catalog::load(None).await;
+    let tracks: Vec<_> = queue
+        .tracks
+        .iter()
+        .filter_map(|id| catalog.tracks.get(id).cloned())
+        .collect();
+    Ok(fields(
+        "playQueue",
+        json!({
+            "current": queue.current,
+            "position": queue.position_ms,
+            "username": username,
+            "changed": timestamp(queue.changed),
+            "changedBy": queue.changed_by,
+            "entry": songs(&catalog, &tracks, &user),
+        }),
+    ))
+}
+
+/// Replaces the queue with the tracks in `id`, playing `current` at
+/// `position` milliseconds in, or clears it if there are none. The user's
+/// other devices are told about it.
+pub(super) fn save_play_queue(request: &Request) -> Result<Map<String, Value>, SubsonicError> {
+    let params = &request.params;
+    let username: &str = request.user.username();
+    let tracks: Vec<String> = params
+        .all("id")
+        .map(|id| match LibraryService.get(id) {
+            Some(_) => Ok(id.to_owned()),
+            None => Err(SubsonicError::NotFound("song")),
+        })
+        .collect::<Result<_, _>>()?;
+    let client: Option<String> = params.get("c").map(str::to_owned);
+    let queue: Option<PlayQueue> = match tracks.is_empty() {
+        true => None,
+        false => Some(PlayQueue {
+            current: params
+                .get("current")
+                .filter(|current| tracks.iter().any(|id| id == current))
+                .map(str::to_owned),
+            position_ms: params.parse("position")?.unwrap_or(0),
+            tracks,
+            changed: Utc::now().timestamp(),
+            changed_by: client.clone(),
+        }),
+    };
+    UserDataService.update(username, |data| {
+        data.play_queue = queue;
+        Ok(())
+    })?;
+    EventService.publish(Some(username), EventKind::QueueChanged { client });
+    Ok(Map::new())
+}
@@ -0,0 +1,146 @@
+//! Renders the `subsonic-response` envelope. Methods build their fields as
+//! JSON values, which map onto XML the way the protocol does it: scalar
+//! fields become attributes, objects become child elements, and arrays
+//! become one child element per item, named after the field.
+
+use axum::{
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Map, Value};
+
+use super::{Params, SubsonicError, API_VERSION};
+
+const XMLNS: &str = "http://subsonic.org/restapi";
+
+/// The format asked for with the `f` parameter.
+pub(super) enum Format {
+    Xml,
+    Json,
+    /// JSON wrapped in a call to the named function
+    Jsonp(String),
+}
+
+impl Format {
+    pub fn of(params: &Params) -> Self {
+        match (params.get("f"), params.get("callback")) {
+            (Some("json"), _) => Format::Json,
+            (Some("jsonp"), Some(callback)) => Format::Jsonp(callback.to_owned()),
+            _ => Format::Xml,
+        }
+    }
+
+    /// The envelope holding `fields`, or describing the error.
+    pub fn respond(&self, result: Result<Map<String, Value>, SubsonicError>) -> Response {
+        let mut envelope: Map<String, Value> = Map::new();
+        let fields: Map<String, Value> = match result {
+            Ok(fields) => {
+                envelope.insert("status".into(), "ok".into());
+                fields
+            }
+            Err(error) => {
+                envelope.insert("status".into(), "failed".into());
+                let mut fields = Map::new();
+                fields.insert(
+                    "error".into(),
+                    json!({ "code": error.code(), "message": error.to_string() }),
+                );
+                fields
+            }
+        };
+        envelope.insert("version".into(), API_VERSION.into());
+        envelope.insert("type".into(), "orpheus".into());
+        envelope.insert("serverVersion".into(), env!("CARGO_PKG_VERSION").into());
+        envelope.insert("openSubsonic".into(), true.into());
+        envelope.extend(fields);
+        let envelope = Value::Object(envelope);
+
+        let (content_type, body): (&'static str, String) = match self {
+            Format::Xml => {
+                let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+                write_element(&mut xml, "subsonic-response", &envelope, Some(XMLNS));
+                ("application/xml; charset=utf-8", xml)
+            }
+            Format::Json => (
+                "application/json",
+                json!({ "subsonic-response": strip_nulls(envelope) }).to_string(),
+            ),
+            Format::Jsonp(callback) => (
+                "application/javascript",
+                format!(
+                    "{callback}({});",
+                    json!({ "subsonic-response": strip_nulls(envelope) })
+                ),
+            ),
+        };
+        (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Leaves out unset fields, which clients expect to be missing rather than
+/// null.
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, strip_nulls(value)))
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(strip_nulls).collect()),
+        value => value,
+    }
+}
+
+/// Writes `value` as an element called `name`, see the module docs.
+fn write_element(xml: &mut String, name: &str, value: &Value, xmlns: Option<&str>) {
+    xml.push('<');
+    xml.push_str(name);
+    if let Some(xmlns) = xmlns {
+        xml.push_str(&format!(r#" xmlns="{xmlns}""#));
+    }
+    let Value::Object(fields) = value else {
+        // a scalar item of an array, written as text
+        xml.push('>');
+        xml.push_str(&escape(&scalar(value)));
+        xml.push_str(&format!("</{name}>"));
+        return;
+    };
+    let mut children: Vec<(&str, &Value)> = Vec::new();
+    for (key, value) in fields {
+        match value {
+            Value::Null => {}
+            Value::Object(_) => children.push((key, value)),
+            Value::Array(items) => children.extend(items.iter().map(|item| (key.as_str(), item))),
+            value => xml.push_str(&format!(r#" {key}="{}""#, escape(&scalar(value)))),
+        }
+    }
+    if children.is_empty() {
+        xml.push_str("/>");
+        return;
+    }
+    xml.push('>');
+    for (key, child) in children {
+        write_element(xml, key, child, None);
+    }
+    xml.push_str(&format!("</{name}>"));
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
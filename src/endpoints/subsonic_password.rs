@@ -0,0 +1,40 @@
+use axum::http::HeaderMap;
+
+use crate::{services::AccountService, types::SecretString};
+
+use super::{
+    authenticate,
+    wire::{ErrorResponse, Reply, ResponseFormat, SubsonicPassword},
+    ApiError,
+};
+
+/// The handler function for the `/subsonic-password` endpoint. Generates a
+/// new password for the caller's Subsonic clients, replacing the previous
+/// one, and responds with it as a [SubsonicPassword]. It's only ever shown
+/// this once.
+#[utoipa::path(
+    post,
+    path = "/subsonic-password",
+    tag = "accounts",
+    summary = "Reset the Subsonic password",
+    description = "Generates a new password for Subsonic clients, which sign their requests \
+                   in a way that needs the password in the clear, so they can't use the \
+                   account password. Clients logged in with the previous one are signed out.",
+    security(("username" = [], "auth-token" = [])),
+    responses(
+        (status = OK, description = "The new password", body = SubsonicPassword),
+        (status = UNAUTHORIZED, description = "Not logged in", body = ErrorResponse),
+    )
+)]
+pub async fn reset_subsonic_password(
+    headers: HeaderMap,
+    ResponseFormat(format): ResponseFormat,
+) -> Result<Reply<SubsonicPassword>, ApiError> {
+    let record = authenticate(&headers)?;
+    let password = SecretString::from(uuid::Uuid::new_v4().simple().to_string());
+    AccountService.update_data(record.username(), |data| {
+        data.set_subsonic_password(Some(password.clone()));
+    })?;
+    tracing::info!(target: crate::service::auth::AUDIT, username = record.username(), "subsonic password reset");
+    Ok(format.reply(SubsonicPassword { password }))
+}
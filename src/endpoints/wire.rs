@@ -0,0 +1,244 @@
+//! # Wire Format
+//! Every request and response body of the `/api/v1` endpoints is one of the
+//! types in this module, encoded either as JSON or with `pot`:
+//! - requests are decoded as JSON if their `Content-Type` is
+//!   `application/json`, and with `pot` if it is `application/x-pot` or
+//!   missing, so native clients don't need to set it,
+//! - responses are encoded in whichever of the two the `Accept` header
+//!   prefers, falling back to the format of the request.
+//!
+//! The types are plain serde structs, so the web client can mirror them in
+//! TypeScript and native clients can depend on this crate for them. Field
+//! names are the same in both formats.
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::ApiError;
+use crate::types::SecretString;
+
+// the bodies of `/scrobble` and `/scrobble-targets`
+pub use crate::types::{Listen, ScrobbleTarget};
+
+/// `Content-Type` of JSON bodies.
+pub const JSON: &str = "application/json";
+/// `Content-Type` of `pot` bodies.
+pub const POT: &str = "application/x-pot";
+
+/// Body of `POST /api/v1/login`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct LoginRequest {
+    pub username: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+}
+
+/// Response to `POST /api/v1/login`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct LoginResponse {
+    /// sent back in the `auth-token` header, along with `username`, to
+    /// authenticate later requests
+    pub token: String,
+    /// unix timestamp of when the token stops working
+    pub expires: i64,
+}
+
+/// Body of `POST /api/v1/create-account`, only admins may create accounts.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CreateAccountRequest {
+    pub username: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+    /// whether the new account can manage the server
+    pub is_admin: bool,
+}
+
+/// An account as shown to clients, without anything secret.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct AccountInfo {
+    pub username: String,
+    pub is_admin: bool,
+}
+
+/// Response to `POST /api/v1/subsonic-password`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SubsonicPassword {
+    /// what Subsonic clients log in with, together with the username
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+}
+
+/// Body of `POST /api/v1/notices`, only admins may send notices.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct NoticeRequest {
+    /// shown to every connected client, like "Restarting for an update in 5 minutes"
+    pub message: String,
+}
+
+/// Body of every error response, see [ApiError].
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ErrorResponse {
+    /// stable identifier of the error, like `account_exists`
+    pub code: String,
+    /// explanation meant for people, which may change between versions
+    pub message: String,
+}
+
+/// One of the formats bodies can be encoded in, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Pot,
+}
+
+impl WireFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON,
+            WireFormat::Pot => POT,
+        }
+    }
+
+    /// The format of a media type like `application/json; charset=utf-8`.
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence: &str = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            JSON => Some(WireFormat::Json),
+            POT => Some(WireFormat::Pot),
+            _ => None,
+        }
+    }
+
+    /// The format of the body of a request with `headers`, `None` if it's
+    /// one that isn't supported.
+    pub fn of_request(headers: &HeaderMap) -> Option<Self> {
+        match headers.get(header::CONTENT_TYPE) {
+            None => Some(WireFormat::Pot),
+            Some(value) => value.to_str().ok().and_then(Self::from_media_type),
+        }
+    }
+
+    /// The format to respond to a request with `headers` in: the supported one
+    /// its `Accept` header ranks highest, else the format of its body.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        let mut best: Option<(Self, f32)> = None;
+        let ranges = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for range in ranges {
+            let Some(format) = Self::from_media_type(range) else {
+                continue;
+            };
+            let quality: f32 = range
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+            .or_else(|| Self::of_request(headers))
+            .unwrap_or(WireFormat::Pot)
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            WireFormat::Json => serde_json::to_vec(value)?,
+            WireFormat::Pot => pot::to_vec(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            WireFormat::Json => serde_json::from_slice(bytes)?,
+            WireFormat::Pot => pot::from_slice(bytes)?,
+        })
+    }
+
+    /// Encodes `value` in this format as a response.
+    pub fn reply<T: Serialize>(self, value: T) -> Reply<T> {
+        Reply {
+            format: self,
+            status: StatusCode::OK,
+            value,
+        }
+    }
+}
+
+/// Extracts a request body of type `T` in either format, see the module docs.
+pub struct Wire<T>(pub T);
+
+impl<T, S> FromRequest<S> for Wire<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let format =
+            WireFormat::of_request(request.headers()).ok_or(ApiError::UnsupportedMediaType)?;
+        let bytes: Bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(|rejection| ApiError::MalformedBody(rejection.body_text()))?;
+        let value: T = format
+            .decode(&bytes)
+            .map_err(|e| ApiError::MalformedBody(e.to_string()))?;
+        Ok(Self(value))
+    }
+}
+
+/// Extracts the format the client wants responses in, see
+/// [WireFormat::accepted].
+pub struct ResponseFormat(pub WireFormat);
+
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(WireFormat::accepted(&parts.headers)))
+    }
+}
+
+/// A response body, made with [WireFormat::reply].
+pub struct Reply<T> {
+    format: WireFormat,
+    status: StatusCode,
+    value: T,
+}
+
+impl<T> Reply<T> {
+    /// Responds with `status` rather than 200 OK.
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl<T: Serialize> IntoResponse for Reply<T> {
+    fn into_response(self) -> Response {
+        match self.format.encode(&self.value) {
+            Ok(body) => (
+                self.status,
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.format.content_type()),
+                )],
+                body,
+            )
+                .into_response(),
+            Err(e) => ApiError::Internal(e.context("failed to encode response")).into_response(),
+        }
+    }
+}
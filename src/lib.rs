@@ -9,13 +9,20 @@ pub mod service;
 // `lib` folder module tree
 // here we use custom paths because for some reason rust believes the
 // `lib.rs` file and the `src/lib/` directory are completely unrelated concepts.
+#[path = "lib/secret.rs"]
+mod secret;
 #[path = "lib/struct_utils.rs"]
 mod struct_utils;
 
 // re-export commonly used types closer to crate root
 pub mod types {
-    pub use crate::service::accounts::{AccountRecord, LoginCode};
+    pub use crate::secret::SecretString;
+    pub use crate::service::accounts::{
+        AccountData, AccountError, AccountExport, AccountRecord, ExportFormat, ImportMode,
+        ImportReport, LoginCode,
+    };
     pub use crate::service::auth::{AccountSession, AuthCode};
+    pub use crate::service::scrobble::{Listen, ScrobbleTarget};
 }
 
 // re-export all services for ease of use
@@ -23,16 +30,62 @@ pub mod services {
     pub use crate::config::CONFIG as Config;
     pub use crate::service::accounts::AccountService;
     pub use crate::service::auth::SESSIONS as SessionService;
+    pub use crate::service::crypto::CIPHER as CipherService;
+    pub use crate::service::data_dir::DATA_DIR as DataDir;
+    pub use crate::service::events::EVENTS as EventService;
+    pub use crate::service::library::LIBRARY as LibraryService;
+    pub use crate::service::metrics::METRICS as MetricsService;
+    pub use crate::service::scrobble::SCROBBLER as ScrobbleService;
+    pub use crate::service::storage::STORAGE as StorageService;
+    pub use crate::service::user_data::USER_DATA as UserDataService;
 }
 
 // unit testing
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{sync::Arc, time::Instant};
 
+    use crate::service::{
+        accounts::AccountsManager,
+        backup,
+        crypto::{self, Cipher, Key, UnsealError},
+        data_dir::DataDir,
+        fs::backup_path,
+        library::LibraryManager,
+        schema::{self, FileKind},
+        scrobble::ScrobbleQueue,
+        storage::{self, MemoryStorage, PotFileStorage, SqliteStorage, StorageBackend},
+    };
     use crate::services::AccountService;
+    use crate::types::{AccountRecord, ExportFormat, ImportMode, Listen, ScrobbleTarget};
     use toml::Table;
 
+    /// A directory of its own for a test, deleted when dropped so it's
+    /// cleaned up even if an assertion fails.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("orpheus-test-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
     #[test]
     pub fn dbg_example_toml() {
         let cfg_toml: Table = include_str!("../orpheus-EXAMPLE.toml")
@@ -41,25 +94,998 @@ mod tests {
         dbg!(cfg_toml);
     }
 
+    /// Points [crate::config::CONFIG] at a config of the tests' own, for the
+    /// tests that use the global services.
+    fn use_test_config() {
+        static FIXTURE: std::sync::Once = std::sync::Once::new();
+        FIXTURE.call_once(|| {
+            let dir = std::env::temp_dir().join("orpheus-test-config");
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("orpheus.toml");
+            let text = format!(
+                "[server]\ndata_dir = {:?}\nbind_address = \"127.0.0.1:0\"\n",
+                dir.join("data")
+            );
+            std::fs::write(&path, text).unwrap();
+            crate::config::set_path(path);
+        });
+    }
+
     #[test]
     pub fn test_read_config() {
+        use_test_config();
         let cfg = crate::config::CONFIG.blocking_read();
-        dbg!(cfg.server().account_data_path());
+        dbg!(cfg.server().data_dir());
         let _ = dbg!(cfg);
     }
 
     #[test]
     pub fn test_change_config() {
+        use_test_config();
         let mut cfg = crate::config::CONFIG.blocking_write();
-        cfg.server_mut().set_account_data_path("data_path".into());
-        std::fs::write("./orpheus-out.toml", cfg.output()).unwrap();
+        cfg.server_mut().set_data_dir("data_dir".into());
+        let dir = TempDir::new();
+        std::fs::write(dir.join("orpheus-out.toml"), cfg.output()).unwrap();
+    }
+
+    #[test]
+    pub fn test_config_env_overrides() {
+        let text = "[server]\nbind_address = \"127.0.0.1:8080\"\naccount_backups = 3";
+        let vars = [
+            ("ORPHEUS_SERVER__BIND_ADDRESS", "0.0.0.0:9000"),
+            ("ORPHEUS_SERVER__ACCOUNT_BACKUPS", "5"),
+            ("ORPHEUS_STORAGE__ENCRYPTION__KEY_ENV", "ORPHEUS_KEY"),
+            ("ORPHEUS_CONFIG", "ignored.toml"),
+            ("HOME", "/root"),
+        ]
+        .map(|(var, value)| (var.to_owned(), value.to_owned()));
+        let config = crate::config::Config::parse(text, vars).unwrap();
+        assert_eq!(config.server().bind_address(), "0.0.0.0:9000");
+        assert_eq!(config.server().account_backups(), 5);
+        let encryption = config.storage().encryption().unwrap();
+        assert_eq!(encryption.key_env(), Some("ORPHEUS_KEY"));
+
+        let vars = [(
+            "ORPHEUS_SERVER__BIND_ADDRESS__PORT".to_owned(),
+            "1".to_owned(),
+        )];
+        assert!(crate::config::Config::parse(text, vars).is_err());
+    }
+
+    #[test]
+    pub fn test_config_diagnostics() {
+        use crate::config::{Config, Location};
+
+        let text = "[server]\nbind_addres = \"127.0.0.1:8080\"\nbind_address = \"localhost:http\"\n\n[storage]\nbackend = \"pot\"\ncolour = true\n";
+        let problems = Config::parse(text, []).unwrap_err();
+        let found: Vec<(&str, &Location)> = problems
+            .iter()
+            .map(|problem| (problem.key.as_str(), &problem.location))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("server.bind_addres", &Location::File { line: 2, column: 1 }),
+                (
+                    "server.bind_address",
+                    &Location::File {
+                        line: 3,
+                        column: 16
+                    }
+                ),
+                ("storage.colour", &Location::File { line: 7, column: 1 }),
+            ]
+        );
+
+        let vars = [(
+            "ORPHEUS_SERVER__ACCOUNT_BACKUPS".to_owned(),
+            "1000".to_owned(),
+        )];
+        let problems = Config::parse("[server]\nbind_address = \"[::1]:80\"", vars).unwrap_err();
+        assert_eq!(
+            problems[0].location,
+            Location::Env("ORPHEUS_SERVER__ACCOUNT_BACKUPS".into())
+        );
+        let problems = Config::parse("[server]\nbind_address = 80", []).unwrap_err();
+        assert_eq!(
+            problems[0].location,
+            Location::File {
+                line: 2,
+                column: 16
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_generated_config_roundtrip() {
+        let mut config = crate::config::Config::default();
+        config.library_mut().set_roots(vec!["/srv/music".into()]);
+        let text = config.output();
+        assert!(text.contains("# Address and port to listen on"));
+        assert!(text.contains("# [storage.encryption]"));
+        let parsed = crate::config::Config::parse(&text, []).unwrap();
+        assert_eq!(parsed.server().bind_address(), "0.0.0.0:31078");
+        assert_eq!(parsed.library().roots(), ["/srv/music"]);
+        assert!(parsed.storage().encryption().is_none());
+    }
+
+    #[test]
+    pub fn test_config_live_reload() {
+        let text = "[server]\nbind_address = \"127.0.0.1:8080\"\n";
+        let mut running = crate::config::Config::parse(text, []).unwrap();
+        let text = "[server]\nbind_address = \"127.0.0.1:9090\"\nrate_limit = 60\n\n[logging]\nlevel = \"warn\"\n";
+        let changes = running.apply_live(crate::config::Config::parse(text, []).unwrap());
+        assert_eq!(changes.live, ["server.rate_limit", "logging"]);
+        assert_eq!(changes.restart, ["server.bind_address"]);
+        assert_eq!(running.server().rate_limit(), 60);
+        assert_eq!(running.server().bind_address(), "127.0.0.1:8080");
+        assert_eq!(
+            running.logging().level(),
+            tracing_subscriber::filter::LevelFilter::WARN
+        );
+    }
+
+    #[test]
+    pub fn test_library_scan() {
+        let root = TempDir::new();
+        std::fs::create_dir_all(root.join("skip")).unwrap();
+        for file in ["a.mp3", "b.FLAC", "c.txt", "skip/d.mp3"] {
+            std::fs::write(root.join(file), b"not really audio").unwrap();
+        }
+        let text = format!(
+            "[server]\nbind_address = \"127.0.0.1:8080\"\n\n[library]\nroots = [{:?}]\nexclude = [\"skip/**\"]\n",
+            root.display().to_string()
+        );
+        let config = crate::config::Config::parse(&text, []).unwrap();
+        let library = LibraryManager::from_storage(Arc::new(MemoryStorage::default()));
+
+        let report = library.scan(config.library()).unwrap();
+        assert_eq!((report.added, report.unchanged), (2, 0));
+        let mut titles: Vec<String> = library.tracks().iter().map(|t| t.title.clone()).collect();
+        titles.sort();
+        assert_eq!(titles, ["a", "b"]); // no tags, named after the file
+
+        let report = library.scan(config.library()).unwrap();
+        assert_eq!((report.added, report.unchanged), (0, 2));
+        std::fs::remove_file(root.join("a.mp3")).unwrap();
+        let report = library.scan(config.library()).unwrap();
+        assert_eq!((report.removed, report.unchanged), (1, 1));
     }
 
     #[test]
     pub fn bench_saving_accounts() {
+        use_test_config();
         std::sync::LazyLock::force(&AccountService);
         let t: Instant = Instant::now();
         AccountService.save();
         println!("{:?}", t.elapsed());
     }
+
+    /// Opens the pot file backend used by the server, with its tables in `dir`.
+    fn pot_accounts(dir: &std::path::Path, backups: usize) -> AccountsManager {
+        AccountsManager::from_storage(Arc::new(PotFileStorage::open(dir, backups, None)))
+    }
+
+    #[test]
+    pub fn test_account_backup_recovery() {
+        let dir = TempDir::new();
+        let path = dir.join("accounts");
+        let accounts = pot_accounts(&dir, 2);
+        for name in ["alice", "bob", "carol"] {
+            accounts
+                .register(name.into(), "password".into(), false)
+                .unwrap();
+            accounts.save(); // every save rotates the previous snapshot into the backups
+        }
+        drop(accounts);
+        assert!(backup_path(&path, 1).exists());
+        assert!(backup_path(&path, 2).exists());
+        assert!(!backup_path(&path, 3).exists());
+
+        // simulate a torn write of the primary file
+        std::fs::write(&path, b"not a pot file").unwrap();
+        let recovered = pot_accounts(&dir, 2);
+        assert!(recovered.get("alice").is_some());
+        drop(recovered);
+    }
+
+    #[test]
+    pub fn test_account_log_replay() {
+        let dir = TempDir::new();
+        let accounts = pot_accounts(&dir, 0);
+        accounts
+            .register("alice".into(), "password".into(), true)
+            .unwrap();
+        accounts
+            .update_data("alice", |data| {
+                data.scrobble_targets_mut().push(ScrobbleTarget::new(
+                    "http://localhost".into(),
+                    "token".into(),
+                ))
+            })
+            .unwrap();
+        assert!(accounts.is_dirty());
+        std::mem::forget(accounts); // crash: no snapshot gets written
+
+        let replayed = pot_accounts(&dir, 0);
+        let alice = replayed.get("alice").unwrap();
+        assert!(*alice.is_admin());
+        assert_eq!(alice.data().scrobble_targets().len(), 1);
+        assert!(!replayed.is_dirty()); // replayed changes are compacted on load
+        drop(replayed);
+    }
+
+    #[test]
+    pub fn test_legacy_account_file_migration() {
+        let dir = TempDir::new();
+        let path = dir.join("accounts");
+
+        // write a registry the way it was saved before versioning: a bare pot map of records
+        let accounts = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+        accounts
+            .register("alice".into(), "password".into(), false)
+            .unwrap();
+        let legacy: std::collections::HashMap<String, Arc<AccountRecord>> =
+            [("alice".to_owned(), accounts.get("alice").unwrap())].into();
+        let payload = pot::to_vec(&legacy).unwrap();
+        std::fs::write(&path, &payload).unwrap();
+
+        let kind = FileKind::Snapshot(storage::Table::Accounts);
+        let (version, pending) = schema::migrate_file(kind, &path, None, true).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), payload); // dry run leaves the file alone
+
+        let migrated = pot_accounts(&dir, 0);
+        assert!(migrated.get("alice").is_some());
+        drop(migrated);
+        assert!(std::fs::read(&path).unwrap().starts_with(&schema::MAGIC));
+    }
+
+    #[test]
+    pub fn test_sqlite_storage_roundtrip() {
+        let dir = TempDir::new();
+        let path = dir.join("orpheus.sqlite");
+        let accounts =
+            AccountsManager::from_storage(Arc::new(SqliteStorage::open(&path, None).unwrap()));
+        accounts
+            .register("alice".into(), "password".into(), true)
+            .unwrap();
+        drop(accounts);
+
+        let storage: Arc<dyn StorageBackend> = Arc::new(SqliteStorage::open(&path, None).unwrap());
+        let table = storage::Table::Accounts;
+        assert_eq!(
+            schema::migrate_records(storage.as_ref(), table, true).unwrap(),
+            0
+        );
+        let reopened = AccountsManager::from_storage(storage);
+        assert!(*reopened.get("alice").unwrap().is_admin());
+        drop(reopened);
+    }
+
+    #[test]
+    pub fn test_encrypted_storage_and_key_rotation() {
+        let dir = TempDir::new();
+        let path = dir.join("accounts");
+        let old = || Key::new([1; 32]);
+        let new = || Key::new([2; 32]);
+        let open = |cipher: Cipher| {
+            let storage = PotFileStorage::open(&dir, 1, Some(Arc::new(cipher)));
+            AccountsManager::from_storage(Arc::new(storage))
+        };
+
+        let accounts = open(Cipher::new(old()));
+        accounts
+            .register("alice".into(), "password".into(), false)
+            .unwrap();
+        accounts.save();
+        accounts
+            .register("bob".into(), "password".into(), false)
+            .unwrap();
+        std::mem::forget(accounts); // leave bob in the log only
+        let wal = crate::service::fs::with_suffix(&path, ".wal");
+        for (file, name) in [(&path, &b"alice"[..]), (&wal, &b"bob"[..])] {
+            let contents = std::fs::read(file).unwrap();
+            assert!(!contents.windows(name.len()).any(|w| w == name));
+        }
+        assert!(crypto::is_sealed(&std::fs::read(&path).unwrap()));
+
+        // rotate, keeping the old key around for anything not yet resealed
+        let ring = Arc::new(Cipher::new(new()).with_previous(old()));
+        for table in storage::Table::ALL {
+            let snapshot = storage::pot_file::table_path(&dir, table);
+            let log = crate::service::fs::with_suffix(&snapshot, ".wal");
+            assert!(schema::reseal_file(FileKind::Snapshot(table), &snapshot, &ring).unwrap());
+            let resealed = schema::reseal_file(FileKind::Log(table), &log, &ring).unwrap();
+            assert_eq!(resealed, table == storage::Table::Accounts); // only that log has entries
+            assert!(!schema::reseal_file(FileKind::Snapshot(table), &snapshot, &ring).unwrap());
+        }
+
+        let rotated = open(Cipher::new(new()));
+        assert!(rotated.get("alice").is_some());
+        assert!(rotated.get("bob").is_some());
+        rotated
+            .register("carol".into(), "password".into(), false)
+            .unwrap();
+        std::mem::forget(rotated); // leave carol in the log only
+        let aad = FileKind::Snapshot(storage::Table::Accounts).aad("");
+        let snapshot = std::fs::read(&path).unwrap();
+        let stale = crypto::unseal(Some(&Cipher::new(old())), &aad, &snapshot);
+        assert!(matches!(stale, Err(UnsealError::UnknownKey(_))));
+
+        // sealed data only opens where it was written, and plaintext not at all
+        let sessions = storage::pot_file::table_path(&dir, storage::Table::Sessions);
+        let moved = crypto::unseal(
+            Some(&Cipher::new(new())),
+            &aad,
+            &std::fs::read(sessions).unwrap(),
+        );
+        assert!(matches!(moved, Err(UnsealError::Tampered)));
+        let planted = crypto::unseal(Some(&Cipher::new(new())), &aad, &schema::MAGIC);
+        assert!(matches!(planted, Err(UnsealError::NotSealed)));
+
+        // a complete log entry that doesn't authenticate fails the log, which is left alone
+        let mut log = std::fs::read(&wal).unwrap();
+        *log.last_mut().unwrap() ^= 1;
+        std::fs::write(&wal, &log).unwrap();
+        let kind = FileKind::Log(storage::Table::Accounts);
+        let cipher = Some(Arc::new(Cipher::new(new())));
+        let opened =
+            crate::service::fs::WriteAheadLog::open::<serde::de::IgnoredAny>(kind, &wal, cipher);
+        assert!(opened.is_err());
+        assert_eq!(std::fs::read(&wal).unwrap(), log);
+    }
+
+    #[test]
+    pub fn test_data_dir_migration_and_lock() {
+        let dir = TempDir::new();
+        let old = dir.join("old");
+        std::fs::create_dir_all(&old).unwrap();
+        for name in [
+            "account-data",
+            "account-data.wal",
+            "account-data.2",
+            "account-data.sessions",
+        ] {
+            std::fs::write(old.join(name), name).unwrap();
+        }
+        std::fs::write(old.join("account-data.scrobbles"), "queue").unwrap();
+        let config: crate::config::Config = toml::from_str(&format!(
+            "[server]\ndata_dir = {:?}\naccount_data_path = {:?}\nbind_address = \"127.0.0.1:0\"",
+            dir.join("data"),
+            old.join("account-data"),
+        ))
+        .unwrap();
+
+        let data_dir = DataDir::from_config(&config);
+        let lock = data_dir.prepare(&config).unwrap();
+        let accounts = data_dir.table(storage::Table::Accounts);
+        assert_eq!(std::fs::read(&accounts).unwrap(), b"account-data");
+        assert!(crate::service::fs::with_suffix(&accounts, ".wal").exists());
+        assert!(backup_path(&accounts, 2).exists());
+        assert!(data_dir.table(storage::Table::Sessions).exists());
+        assert_eq!(std::fs::read(data_dir.scrobble_queue()).unwrap(), b"queue");
+        assert!(data_dir.artwork().is_dir());
+        assert_eq!(std::fs::read_dir(&old).unwrap().count(), 0);
+
+        // nothing in the data dir is readable by other users, whatever the umask
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &std::path::Path| {
+                std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+            };
+            assert_eq!(mode(data_dir.root()), 0o700);
+            assert_eq!(mode(&data_dir.db()), 0o700);
+            crate::service::fs::atomic_write(&data_dir.scrobble_queue(), b"queue", 0).unwrap();
+            assert_eq!(mode(&data_dir.scrobble_queue()), 0o600);
+        }
+
+        // a second instance can't use the directory until the first is gone
+        let err = data_dir.lock().unwrap_err();
+        assert!(err.to_string().contains("in use"));
+        drop(lock);
+        assert!(data_dir.lock().is_ok());
+    }
+
+    #[test]
+    pub fn test_account_export_import() {
+        let source = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+        source
+            .register("alice".into(), "password".into(), true)
+            .unwrap();
+        source
+            .register("bob".into(), "password".into(), false)
+            .unwrap();
+        for format in [ExportFormat::Json, ExportFormat::Toml] {
+            let text = format.encode(&source.export()).unwrap();
+            let export = format.decode(&text).unwrap();
+            assert_eq!(export.accounts.len(), 2);
+
+            // bob exists with a different password on the target, carol only on the target
+            let target = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+            target
+                .register("bob".into(), "other".into(), false)
+                .unwrap();
+            target
+                .register("carol".into(), "password".into(), false)
+                .unwrap();
+            let report = target.import(export, ImportMode::Merge, false).unwrap();
+            assert_eq!(report.added, ["alice"]);
+            assert_eq!(report.conflicts, ["bob"]);
+            assert!(*target.get("alice").unwrap().is_admin());
+            assert!(target.get("carol").is_some());
+
+            let export = format.decode(&text).unwrap();
+            let report = target.import(export, ImportMode::Replace, false).unwrap();
+            assert_eq!(report.replaced, ["bob"]);
+            assert_eq!(report.unchanged, ["alice"]);
+            assert_eq!(report.removed, ["carol"]);
+            assert_eq!(target.export().accounts, source.export().accounts);
+        }
+    }
+
+    #[test]
+    pub fn test_secrets_are_redacted() {
+        let accounts = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+        accounts
+            .register("alice".into(), "hunter2".into(), false)
+            .unwrap();
+        let record = accounts.get("alice").unwrap();
+        let hash: &str = record.password_hash().expose();
+        assert!(hash.starts_with("$scrypt$"));
+        let logged = format!("{record:?}");
+        assert!(logged.contains("[redacted]") && !logged.contains(hash));
+
+        // still stored as a plain string
+        let json = serde_json::to_value(record.as_ref()).unwrap();
+        assert_eq!(json["password_hash"], hash);
+
+        let target = ScrobbleTarget::new("http://localhost".into(), "lb-token".into());
+        assert!(!format!("{target:?}").contains("lb-token"));
+        assert_eq!(target.token().expose(), "lb-token");
+        let json = serde_json::to_value(&target).unwrap();
+        assert_eq!(json["token"], "lb-token");
+    }
+
+    #[test]
+    pub fn test_api_error_codes() {
+        use crate::endpoints::ApiError;
+        use axum::http::StatusCode;
+
+        let accounts = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+        accounts.register("bob".into(), "pw".into(), false).unwrap();
+        let error: ApiError = accounts
+            .register("bob".into(), "pw".into(), false)
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            (error.code(), error.status()),
+            ("account_exists", StatusCode::CONFLICT)
+        );
+        assert_eq!(error.body().message, "account bob already exists");
+
+        // anything unexpected is a 500 that doesn't say what went wrong
+        let error: ApiError = anyhow::anyhow!("disk on fire").into();
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!error.body().message.contains("disk"));
+    }
+
+    #[tokio::test]
+    pub async fn test_openapi_matches_router() {
+        use crate::endpoints::{self, docs};
+        use axum::http::StatusCode;
+        use std::net::SocketAddr;
+
+        let document = docs::openapi();
+        let generated = document.to_pretty_json().unwrap();
+        assert!(
+            generated.trim() == include_str!("../openapi.json").trim(),
+            "openapi.json is out of date, regenerate it with `cargo run -- openapi > openapi.json`"
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = endpoints::router().into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        // every documented operation is routed, so an empty body is turned
+        // down by the handler rather than by the router
+        for (path, item) in &document.paths.paths {
+            assert!(item.post.is_some(), "{path} isn't a POST");
+            let status = client
+                .post(format!("http://{addr}{path}"))
+                .send()
+                .await
+                .unwrap()
+                .status();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+        }
+
+        let served: serde_json::Value = client
+            .get(format!("http://{addr}{}", docs::OPENAPI_PATH))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(served, serde_json::to_value(&document).unwrap());
+        let page = client
+            .get(format!("http://{addr}{}/", docs::DOCS_PATH))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    pub async fn test_subsonic_errors() {
+        use crate::endpoints::subsonic;
+
+        use_test_config(); // authenticating looks the user up
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, subsonic::router()).await });
+        let get = |query: &'static str| async move {
+            reqwest::get(format!("http://{addr}/rest/ping.view?{query}"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        };
+
+        let xml = get("v=1.16.1&c=test").await;
+        assert!(xml.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?><subsonic-response xmlns="http://subsonic.org/restapi""#));
+        assert!(xml.contains(r#"status="failed""#));
+        assert!(xml.contains(r#"<error code="10" message="#));
+
+        let codes = [
+            ("u=nobody&t=0123&s=salt&f=json", 40),
+            ("u=nobody&t=0123&s=salt&p=secret&f=json", 43),
+            ("u=nobody&apiKey=key&f=json", 42),
+        ];
+        for (query, code) in codes {
+            let json: serde_json::Value = serde_json::from_str(&get(query).await).unwrap();
+            let response = &json["subsonic-response"];
+            assert_eq!(response["status"], "failed", "{query}");
+            assert_eq!(response["error"]["code"], code, "{query}");
+            assert_eq!(response["version"], subsonic::API_VERSION);
+        }
+    }
+
+    #[test]
+    pub fn test_event_resume() {
+        use crate::service::events::{EventBus, EventKind, Topic, HISTORY};
+
+        let events = EventBus::new();
+        let notice = |n: usize| EventKind::Notice {
+            message: n.to_string(),
+        };
+        let first = events.publish(None, notice(0));
+        let second = events.publish(Some("bob"), EventKind::QueueChanged { client: None });
+        let mut live = events.subscribe(None).receiver;
+        let third = events.publish(None, notice(2));
+        assert_eq!((second, third), (first + 1, first + 2));
+        assert_eq!(live.try_recv().unwrap().id, third);
+
+        // resuming sends everything after the last event seen
+        let missed = events.subscribe(Some(first)).missed.unwrap();
+        let ids: Vec<u64> = missed.iter().map(|event| event.id).collect();
+        assert_eq!(ids, [second, third]);
+        assert_eq!(missed[0].topic, Topic::Queue);
+        assert!(missed[0].is_for("bob") && !missed[0].is_for("alice"));
+        assert!(events.subscribe(Some(third)).missed.unwrap().is_empty());
+        let json = serde_json::to_value(&*missed[1]).unwrap();
+        assert_eq!(json["type"], "notice");
+        assert_eq!(json["topic"], "notices");
+
+        // unless some of it is gone, or from before a restart
+        for n in 0..HISTORY {
+            events.publish(None, notice(n));
+        }
+        assert!(events.subscribe(Some(first)).missed.is_none());
+        assert!(EventBus::new().subscribe(Some(third)).missed.is_none());
+    }
+
+    #[test]
+    pub fn test_sessions_store_token_hashes() {
+        use crate::service::auth::AuthManager;
+        use crate::types::AuthCode;
+
+        use_test_config();
+        let username = "hashed-session";
+        let _ = AccountService.register(username.into(), "password".into(), false); // or left from an earlier run
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::default());
+        let sessions = AuthManager::from_storage(storage.clone());
+        let expiry = chrono::TimeDelta::hours(1);
+        let AuthCode::Success(_, token) = sessions.login(username, &"password".into(), expiry)
+        else {
+            panic!("failed to log in");
+        };
+
+        // neither the key nor the record gives the token away
+        let token_text = token.to_string();
+        for (key, record) in storage.load(storage::Table::Sessions).unwrap() {
+            assert_eq!(key, hex::encode(token.hash()));
+            assert!(!String::from_utf8_lossy(&record).contains(&token_text));
+        }
+
+        // the session survives a restart all the same
+        let restarted = AuthManager::from_storage(storage);
+        assert!(restarted.auth_get_session(username, token).is_some());
+        assert!(restarted.auth_get_session("someone-else", token).is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_event_stream_to_several_devices() {
+        use crate::endpoints::events::EVENTS_PATH;
+        use crate::service::events::EventKind;
+        use crate::services::{EventService, SessionService};
+        use crate::types::AuthCode;
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+        use_test_config();
+        let username = "two-devices";
+        let _ = AccountService.register(username.into(), "password".into(), false); // or left from an earlier run
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, crate::endpoints::router()).await });
+
+        // each device logs in on its own, the second login doesn't end the first session
+        let mut sockets = Vec::new();
+        let mut tokens = Vec::new();
+        for _ in 0..2 {
+            let expiry = chrono::TimeDelta::hours(1);
+            let AuthCode::Success(_, token) =
+                SessionService.login(username, &"password".into(), expiry)
+            else {
+                panic!("failed to log in");
+            };
+            let mut request = format!("ws://{addr}{EVENTS_PATH}?topics=queue")
+                .into_client_request()
+                .unwrap();
+            let headers = request.headers_mut();
+            headers.insert("username", username.parse().unwrap());
+            headers.insert("auth-token", token.to_string().parse().unwrap());
+            let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+            sockets.push(socket);
+            tokens.push(token);
+        }
+        for token in &tokens {
+            assert!(SessionService.auth_get_session(username, *token).is_some());
+        }
+
+        EventService.publish(Some(username), EventKind::QueueChanged { client: None });
+        for socket in &mut sockets {
+            let wait = async {
+                loop {
+                    match socket.next().await {
+                        Some(Ok(Message::Text(text))) => {
+                            let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+                            if message["type"] == "queue_changed" {
+                                return;
+                            }
+                        }
+                        Some(Ok(_)) => continue,
+                        other => panic!("stream ended early: {other:?}"),
+                    }
+                }
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(10), wait)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_event_stream_session_expiry() {
+        use crate::endpoints::events::{EVENTS_PATH, SESSION_ENDED};
+        use crate::services::SessionService;
+        use crate::types::AuthCode;
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+        use_test_config();
+        let username = "expiring";
+        let _ = AccountService.register(username.into(), "password".into(), false); // or left from an earlier run
+        let expiry = chrono::TimeDelta::seconds(1);
+        let AuthCode::Success(_, token) =
+            SessionService.login(username, &"password".into(), expiry)
+        else {
+            panic!("failed to log in");
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, crate::endpoints::router()).await });
+        let mut request = format!("ws://{addr}{EVENTS_PATH}")
+            .into_client_request()
+            .unwrap();
+        let headers = request.headers_mut();
+        headers.insert("username", username.parse().unwrap());
+        headers.insert("auth-token", token.to_string().parse().unwrap());
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        // the session runs out long before the next keepalive would notice
+        let wait = async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Message::Close(frame))) => return frame.unwrap(),
+                    Some(Ok(_)) => continue,
+                    other => panic!("stream ended without closing: {other:?}"),
+                }
+            }
+        };
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(10), wait)
+            .await
+            .unwrap();
+        assert_eq!(u16::from(frame.code), SESSION_ENDED);
+        assert!(SessionService.auth_get_session(username, token).is_none());
+    }
+
+    #[test]
+    pub fn test_metrics_format() {
+        use crate::service::metrics::{Gauge, Metrics};
+        use std::time::Duration;
+
+        let metrics = Metrics::default();
+        let route = "/rest/{method}";
+        metrics.record_request("GET", route, 200, Duration::from_millis(3));
+        metrics.record_request("GET", route, 200, Duration::from_millis(30));
+        metrics.record_request("GET", route, 404, Duration::from_secs(60));
+        metrics.record_scan(Duration::from_secs(2));
+        metrics.add_stream_bytes(1000);
+        let gauge = Gauge {
+            name: "orpheus_accounts",
+            help: "Registered accounts",
+            value: 3,
+        };
+        let text = metrics.render(&[gauge]);
+
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            r#"orpheus_http_requests_total{method="GET",route="/rest/{method}",status="200"} 2"#,
+            r#"orpheus_http_requests_total{method="GET",route="/rest/{method}",status="404"} 1"#,
+            r#"orpheus_http_request_duration_seconds_bucket{method="GET",route="/rest/{method}",le="0.005"} 1"#,
+            r#"orpheus_http_request_duration_seconds_bucket{method="GET",route="/rest/{method}",le="0.05"} 2"#,
+            r#"orpheus_http_request_duration_seconds_bucket{method="GET",route="/rest/{method}",le="10"} 2"#,
+            r#"orpheus_http_request_duration_seconds_bucket{method="GET",route="/rest/{method}",le="+Inf"} 3"#,
+            r#"orpheus_http_request_duration_seconds_count{method="GET",route="/rest/{method}"} 3"#,
+            r#"orpheus_library_scan_duration_seconds_bucket{le="1"} 0"#,
+            r#"orpheus_library_scan_duration_seconds_bucket{le="10"} 1"#,
+            "orpheus_library_scan_duration_seconds_sum 2",
+            "orpheus_stream_bytes_total 1000",
+            "orpheus_autosave_failures_total 0",
+            "# TYPE orpheus_accounts gauge",
+            "orpheus_accounts 3",
+        ] {
+            assert!(lines.contains(&expected), "missing {expected} in:\n{text}");
+        }
+    }
+
+    #[test]
+    pub fn test_wire_format_negotiation() {
+        use crate::endpoints::wire::{LoginResponse, WireFormat};
+        use axum::http::{header, HeaderMap};
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(WireFormat::of_request(&headers), Some(WireFormat::Pot));
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        assert_eq!(WireFormat::of_request(&headers), None);
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        assert_eq!(WireFormat::of_request(&headers), Some(WireFormat::Json));
+        // no Accept, answer in the format of the request
+        assert_eq!(WireFormat::accepted(&headers), WireFormat::Json);
+        headers.insert(
+            header::ACCEPT,
+            "application/json;q=0.5, application/x-pot, */*;q=0.1"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(WireFormat::accepted(&headers), WireFormat::Pot);
+
+        // the same value makes it through either format
+        let response = LoginResponse {
+            token: "token".into(),
+            expires: 42,
+        };
+        for format in [WireFormat::Json, WireFormat::Pot] {
+            let bytes = format.encode(&response).unwrap();
+            let decoded: LoginResponse = format.decode(&bytes).unwrap();
+            assert_eq!((decoded.token, decoded.expires), ("token".into(), 42));
+        }
+    }
+
+    #[test]
+    pub fn test_backup_archive_restore() {
+        let dir = TempDir::new();
+        let config: crate::config::Config = toml::from_str(&format!(
+            "[server]\ndata_dir = {:?}\nbind_address = \"127.0.0.1:0\"",
+            &*dir
+        ))
+        .unwrap();
+        let data_dir = DataDir::from_config(&config);
+        let cipher = Arc::new(Cipher::new(Key::new([9; 32])));
+        let storage: Arc<dyn StorageBackend> = Arc::new(PotFileStorage::open(
+            &data_dir.db(),
+            1,
+            Some(cipher.clone()),
+        ));
+        let accounts = AccountsManager::from_storage(storage.clone());
+        accounts
+            .register("alice".into(), "password".into(), true)
+            .unwrap();
+        let queue = ScrobbleQueue::from_path(data_dir.scrobble_queue(), Some(cipher.clone()));
+        let archive = backup::create_archive(storage.as_ref(), &queue, Some(&cipher)).unwrap();
+        assert!(crypto::is_sealed(&archive));
+
+        // changes after the backup are undone by restoring it
+        accounts
+            .register("bob".into(), "password".into(), false)
+            .unwrap();
+        drop((accounts, storage));
+        assert!(backup::read_archive(&archive, None).is_err());
+        let archive = backup::read_archive(&archive, Some(&cipher)).unwrap();
+        assert_eq!(archive.manifest.tables["accounts"], 1);
+        let aside = backup::restore(archive, &config, &data_dir, Some(cipher.clone())).unwrap();
+
+        let restored = AccountsManager::from_storage(Arc::new(PotFileStorage::open(
+            &data_dir.db(),
+            1,
+            Some(cipher),
+        )));
+        assert!(restored.get("alice").is_some());
+        assert!(restored.get("bob").is_none());
+        assert!(aside.join("db").is_dir());
+    }
+
+    #[test]
+    pub fn test_listenbrainz_submission() {
+        let listen = Listen {
+            listened_at: 1_700_000_000,
+            artist: "Artist".into(),
+            track: "Track".into(),
+            release: None,
+            duration_secs: Some(200),
+        };
+        let body = crate::service::scrobble::submission(&listen);
+        assert_eq!(body["listen_type"], "single");
+        let entry = &body["payload"][0];
+        assert_eq!(entry["listened_at"], 1_700_000_000);
+        assert_eq!(entry["track_metadata"]["artist_name"], "Artist");
+        assert_eq!(entry["track_metadata"]["track_name"], "Track");
+        assert_eq!(
+            entry["track_metadata"]["additional_info"]["duration_ms"],
+            200_000
+        );
+        assert!(entry["track_metadata"].get("release_name").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_scrobble_forwarding_to_stub() {
+        use axum::{http::HeaderMap, routing::post, Json, Router};
+        use std::sync::Mutex;
+
+        // stand-in ListenBrainz server that records what it receives
+        let received: Arc<Mutex<Vec<(String, serde_json::Value)>>> = Arc::default();
+        let sink = received.clone();
+        let stub = Router::new().route(
+            "/1/submit-listens",
+            post(
+                async move |headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+                    let auth = headers["authorization"].to_str().unwrap().to_owned();
+                    sink.lock().unwrap().push((auth, body));
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, stub).await.unwrap() });
+
+        let dir = TempDir::new();
+        let path = dir.join("scrobbles");
+        let targets = [
+            ScrobbleTarget::new(format!("http://{addr}"), "secret".into()),
+            ScrobbleTarget::new("http://127.0.0.1:1".into(), "unreachable".into()),
+        ];
+        let queue = ScrobbleQueue::from_path(path.clone(), None);
+        queue.enqueue(
+            &targets,
+            Listen {
+                listened_at: 1_700_000_000,
+                artist: "Artist".into(),
+                track: "Track".into(),
+                release: Some("Album".into()),
+                duration_secs: None,
+            },
+        );
+        assert_eq!(queue.len(), 2);
+        queue.deliver_due().await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "Token secret");
+        assert_eq!(
+            received[0].1["payload"][0]["track_metadata"]["release_name"],
+            "Album"
+        );
+
+        // the undeliverable listen stays queued, including across a restart
+        assert_eq!(queue.len(), 1);
+        drop(queue);
+        assert_eq!(ScrobbleQueue::from_path(path, None).len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_scrobble_delivery_in_flight() {
+        use axum::{routing::post, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::sync::Notify;
+
+        // one target answers right away, the other only once released
+        let delivered: Arc<AtomicUsize> = Arc::default();
+        let release: Arc<Notify> = Arc::default();
+        let (fast, slow) = (delivered.clone(), release.clone());
+        let stub = Router::new()
+            .route(
+                "/fast/1/submit-listens",
+                post(async move || {
+                    fast.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+            .route(
+                "/slow/1/submit-listens",
+                post(async move || slow.notified().await),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, stub).await.unwrap() });
+
+        let dir = TempDir::new();
+        let path = dir.join("scrobbles");
+        let targets = [
+            ScrobbleTarget::new(format!("http://{addr}/slow"), "slow".into()),
+            ScrobbleTarget::new(format!("http://{addr}/fast"), "fast".into()),
+        ];
+        let listen = Listen {
+            listened_at: 1_700_000_000,
+            artist: "Artist".into(),
+            track: "Track".into(),
+            release: None,
+            duration_secs: None,
+        };
+        let queue = Arc::new(ScrobbleQueue::from_path(path.clone(), None));
+        queue.enqueue(&targets, listen.clone());
+        let delivery = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.deliver_due().await }
+        });
+
+        // the fast target isn't held up behind the slow one
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while delivered.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // a save while the slow submission is in flight keeps its listen
+        queue.enqueue(&targets[1..], listen);
+        assert_eq!(ScrobbleQueue::from_path(path.clone(), None).len(), 2);
+
+        release.notify_one();
+        delivery.await.unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(ScrobbleQueue::from_path(path, None).len(), 1);
+    }
 }
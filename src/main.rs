@@ -2,86 +2,527 @@
 //! the main.rs file contains the binary part of the application, i.e.
 //! the code for the main function and any relevant details.
 
-use std::time::Duration;
-
-use axum::{
-    http::StatusCode,
-    routing::{get, post},
-    Router,
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use tracing::{debug, info, Level};
+
+use anyhow::Context;
+use axum::serve::ListenerExt;
+use tower_http::trace::TraceLayer;
+use tracing::info;
+use tracing_subscriber::filter::LevelFilter;
 // import exports defined in `src/lib.rs`:
 use orpheus::{
-    endpoints,
-    services::{AccountService, Config},
+    config::{self, BackendKind},
+    endpoints::{self, middleware},
+    service::{
+        backup,
+        crypto::{Cipher, Key},
+        data_dir, fs, logging, persistence, reload, schema,
+        storage::{self, Table},
+        tls,
+    },
+    services::{
+        AccountService, CipherService, Config, DataDir, EventService, LibraryService,
+        ScrobbleService, StorageService,
+    },
+    types::{ExportFormat, ImportMode},
 };
 
 #[tokio::main]
 async fn main() {
-    // note that tracing doesn't even get compiled in release mode so no need
-    // to change this based on debug/release builds.
-    // currently, the crate is set up to not even compile any tracing calls
-    // above the INFO level in release.
-    tracing_subscriber::fmt() // set up global tracing
-        .with_max_level(Level::TRACE) // set maximum log level to be outputted
-        .without_time() // remove timestamp from log messages
-        .init();
+    // the crate is set up to not even compile any tracing calls above the
+    // INFO level in release, so debug and trace only take effect in debug builds.
+    // everything is logged to stderr until `run` switches to the configured level
+    logging::init(LevelFilter::TRACE);
 
-    let args: Vec<String> = std::env::args().skip(1).collect(); // skip binary name
+    let mut args: Vec<String> = std::env::args().skip(1).collect(); // skip binary name
+
+    // `--config <path>` may come anywhere, it's taken out before matching sub-commands
+    if let Some(i) = args
+        .iter()
+        .position(|arg| arg == "--config" || arg.starts_with("--config="))
+    {
+        let path: String = match args.remove(i).strip_prefix("--config=") {
+            Some(path) => path.to_owned(),
+            None if i < args.len() => args.remove(i),
+            None => {
+                tracing::error!("Please provide the path to the config file after --config!");
+                std::process::exit(1);
+            }
+        };
+        config::set_path(path.into());
+    }
 
     if args.is_empty() {
         tracing::error!(
-            "Please provide a subcommand! Valid sub-commands are: run, init-config, account"
+            "Please provide a subcommand! Valid sub-commands are: run, init-config, check-config, account, migrate, rotate-key, backup, restore, openapi"
         );
         std::process::exit(0);
     }
 
+    // anything else reads the config through `Config`, which can't report problems as well
+    if !matches!(args[0].as_str(), "init-config" | "check-config" | "openapi") {
+        check_config();
+    }
+
     // match sub-commands
     match args[0].as_str() {
         "run" => {
-            std::sync::LazyLock::force(&AccountService);
             let lock = Config.try_read().unwrap(); // gain a read lock over config temporarily
+            logging::apply(lock.logging(), &DataDir.logs()).expect("Failed to set up logging!");
+            std::sync::LazyLock::force(&AccountService);
             let port: &str = lock.server().bind_address(); // obtain port to bind to from Config service
 
-            let app = Router::new()
-                .layer(TraceLayer::new_for_http()) // makes debugging in async frameworks tear-free!
-                .layer(CorsLayer::permissive()) // idk what cors even does but it ruins my life
-                .route("/", get(root_responder)) // mostly to test logging and firewalls
-                .route(
-                    "/create-account",
-                    get(async || StatusCode::METHOD_NOT_ALLOWED), // explicitly disallow get requests as we need binary data
-                )
-                .route("/create-account", post(endpoints::create_account))
-                .route("/login", post(endpoints::login));
-
-            std::thread::spawn(|| loop {
-                // spawn a separate thread to infinitely loop and save registry if necessary
-                let account_service = AccountService.as_ref();
-                if account_service.is_dirty() {
-                    // if account registry changed since last write
-                    debug!("accounts service is marked dirty, autosaving...");
-                    account_service.save();
-                }
-                std::thread::sleep(Duration::from_secs(1));
-            });
+            let app = endpoints::router()
+                // layers only wrap the routes added before them
+                .layer(axum::middleware::from_fn(middleware::rate_limit))
+                .layer(axum::middleware::from_fn(middleware::error_format)) // errors as JSON or pot
+                .layer(middleware::cors()) // origins come from the config, any if unset
+                .layer(axum::middleware::from_fn(middleware::metrics)) // for `/metrics`
+                .layer(TraceLayer::new_for_http()); // makes debugging in async frameworks tear-free!
+
+            tokio::spawn(ScrobbleService.run()); // forward queued listens in the background
+
+            // keep the library index in sync with the music on disk
+            tokio::spawn(LibraryService.run());
+
+            // picks up changes to the config file, or reloads it on SIGHUP
+            tokio::spawn(reload::run());
+
+            // compact the storage backend in the background after every burst of changes
+            tokio::spawn(persistence::run(StorageService.clone()));
+
+            // lets `orpheus backup` take a consistent snapshot while the server runs
+            #[cfg(unix)]
+            tokio::spawn(backup::serve_control_socket(
+                DataDir.control_socket(),
+                StorageService.clone(),
+                &ScrobbleService,
+                CipherService.clone(),
+            ));
 
             let listener = tokio::net::TcpListener::bind(port)
                 .await
                 .unwrap_or_else(|_| panic!("Failed to bind to address {port}!"));
-            info!("Listening on {}...", port);
-            drop(lock);
-            axum::serve(listener, app).await.unwrap();
+            // the rate limit needs to know who is asking
+            let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+            match lock.server().tls().cloned() {
+                Some(tls_config) => {
+                    let key = tls::load_certified_key(&tls_config).unwrap_or_else(|e| {
+                        tracing::error!("Failed to load TLS certificate, {e:#}");
+                        std::process::exit(1);
+                    });
+                    let certs = Arc::new(tls::CertResolver::new(key));
+                    tokio::spawn(certs.clone().watch(tls_config)); // picks up renewed certificates
+                    let listener = tls::TlsListener::new(listener, certs)
+                        .expect("Failed to set up TLS!")
+                        // `tap_io` lets connect info through, which `TlsListener` can't provide itself
+                        .tap_io(|stream| {
+                            let _ = stream.get_ref().0.set_nodelay(true);
+                        });
+                    info!("Listening on https://{}...", port);
+                    drop(lock);
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(shutdown_signal()) // finishes in-flight requests first
+                        .await
+                        .unwrap();
+                }
+                None => {
+                    info!("Listening on {}...", port);
+                    drop(lock);
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                        .unwrap();
+                }
+            }
+            // statics are never dropped, so this is the last chance to save anything
+            persistence::flush(StorageService.as_ref());
+            let _ = std::fs::remove_file(DataDir.control_socket());
             info!("Exiting gracefully...");
+            logging::flush();
+        }
+        "init-config" => init_config_command(&args[1..]),
+        "check-config" => {
+            // validates the config without starting anything, for scripts and deployments
+            let path = check_config();
+            println!("{} is valid", path.display());
+        }
+        "account" => account_command(&args[1..]),
+        "openapi" => {
+            // the same document the server serves at /api/openapi.json, for client generators
+            println!("{}", endpoints::docs::openapi().to_pretty_json().unwrap());
+        }
+        "migrate" => {
+            // upgrades every persisted file to the current schema, the server shouldn't be running
+            let dry_run: bool = args.iter().any(|arg| arg == "--dry-run");
+            let lock = Config.try_read().unwrap();
+            let mut failed = false;
+            for (kind, path) in schema::persisted_files(&lock, &DataDir) {
+                if !path.exists() {
+                    continue;
+                }
+                match schema::migrate_file(kind, &path, CipherService.as_ref(), dry_run) {
+                    Ok((version, [])) => {
+                        println!("{}: up to date (version {version})", path.display())
+                    }
+                    Ok((version, pending)) => {
+                        let verb = if dry_run { "would migrate" } else { "migrated" };
+                        println!(
+                            "{}: {verb} version {version} -> {}",
+                            path.display(),
+                            kind.current_version()
+                        );
+                        for migration in pending {
+                            println!("  - {}", migration.description);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("{}: {e}", path.display());
+                        failed = true;
+                    }
+                }
+            }
+            if lock.storage().backend() == BackendKind::Sqlite {
+                // records in a database are versioned one by one rather than per file
+                let storage = storage::open(&lock, &DataDir, CipherService.clone());
+                for table in Table::ALL {
+                    match schema::migrate_records(storage.as_ref(), table, dry_run) {
+                        Ok(0) => println!("{}: up to date", table.name()),
+                        Ok(outdated) => {
+                            let verb = if dry_run { "would migrate" } else { "migrated" };
+                            println!("{}: {verb} {outdated} records", table.name());
+                        }
+                        Err(e) => {
+                            tracing::error!("{}: {e}", table.name());
+                            failed = true;
+                        }
+                    }
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+        "backup" => {
+            let Some(path) = args.get(1).map(Path::new) else {
+                tracing::error!("Please provide the path to write the backup archive to!");
+                std::process::exit(1);
+            };
+            let lock = Config.try_read().unwrap();
+            // a running server holds the data directory, so ask it before trying to take it
+            let archive = backup::request_archive(&data_dir::DataDir::from_config(&lock))
+                .and_then(|archive| match archive {
+                    Some(archive) => Ok(archive),
+                    None => backup::create_archive(
+                        StorageService.as_ref(),
+                        &ScrobbleService,
+                        CipherService.as_deref(),
+                    ),
+                })
+                .and_then(|archive| {
+                    // holds every table, so it's as private as the data dir, and never overwritten
+                    let mut file = fs::private_options()
+                        .create_new(true)
+                        .open(path)
+                        .with_context(|| format!("failed to create {}", path.display()))?;
+                    Ok(file.write_all(&archive)?)
+                });
+            match archive {
+                Ok(()) => info!("Wrote backup to {}", path.display()),
+                Err(e) => {
+                    tracing::error!("Failed to take backup: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "restore" => {
+            // replaces the data directory, the server can't be running as it holds the lock
+            let Some(path) = args.get(1).map(Path::new) else {
+                tracing::error!("Please provide the backup archive to restore!");
+                std::process::exit(1);
+            };
+            let lock = Config.try_read().unwrap();
+            let restored =
+                backup::read_archive_file(path, CipherService.as_deref()).and_then(|archive| {
+                    info!(
+                        "Restoring backup taken by Orpheus {} at {}",
+                        archive.manifest.server_version,
+                        chrono::DateTime::from_timestamp(archive.manifest.created, 0)
+                            .unwrap_or_default()
+                    );
+                    backup::restore(archive, &lock, &DataDir, CipherService.clone())
+                });
+            match restored {
+                Ok(aside) => info!(
+                    "Restored {}, the previous data was moved to {}",
+                    path.display(),
+                    aside.display()
+                ),
+                Err(e) => {
+                    tracing::error!("Failed to restore backup: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "rotate-key" => {
+            // re-encrypts every persisted file with a new key, the server shouldn't be running
+            let Some(new_path) = args.iter().skip(1).find(|arg| !arg.starts_with("--")) else {
+                tracing::error!(
+                    "Please provide the file holding the new key! Add --generate to create it."
+                );
+                std::process::exit(1);
+            };
+            let new_path = Path::new(new_path);
+            let lock = Config.try_read().unwrap();
+            let rotated = Key::from_config(&lock).and_then(|old_key| {
+                let new_key = if args.iter().any(|arg| arg == "--generate") {
+                    Key::generate(new_path)?
+                } else {
+                    Key::read(new_path)?
+                };
+                // keep accepting the old key, so an interrupted rotation can just be run again,
+                // and without one this is turning encryption on, so nothing is sealed yet
+                let cipher = match old_key {
+                    Some(old_key) => Cipher::new(new_key).with_previous(old_key),
+                    None => Cipher::new(new_key).accepting_plaintext(),
+                };
+                let cipher = Arc::new(cipher);
+                let mut resealed: usize = 0;
+                for (kind, path) in schema::persisted_files(&lock, &DataDir) {
+                    if path.exists() && schema::reseal_file(kind, &path, &cipher)? {
+                        resealed += 1;
+                    }
+                }
+                if lock.storage().backend() == BackendKind::Sqlite {
+                    let storage = storage::open(&lock, &DataDir, Some(cipher.clone()));
+                    for table in Table::ALL {
+                        for (key, value) in storage.load(table)? {
+                            storage.put(table, &key, value)?;
+                        }
+                    }
+                    storage.flush()?;
+                }
+                Ok((resealed, cipher.key_id()))
+            });
+            match rotated {
+                Ok((resealed, key_id)) => {
+                    info!("Re-encrypted {resealed} files with key {key_id}");
+                    println!(
+                        "Point key_file in [storage.encryption] at {} (or put its contents in the \
+                         variable named by key_env) before starting the server again.",
+                        new_path.display()
+                    );
+                }
+                Err(e) => {
+                    tracing::error!("Failed to rotate key: {e:#}");
+                    std::process::exit(1);
+                }
+            }
         }
-        "account" => {}
         _ => {
             tracing::error!("Invalid subcommand!")
         }
     };
 }
 
-async fn root_responder() -> Result<(), StatusCode> {
-    tracing::debug!("root response");
-    Ok(())
+/// Checks the config in use, exiting with every problem found if it can't be
+/// used. Returns the path it was read from.
+fn check_config() -> PathBuf {
+    let path: PathBuf = config::path();
+    if let Err(e) = config::Config::load_checked(&path) {
+        // logging may not be set up yet, and this is for whoever is starting the server
+        eprintln!("{e}");
+        if !path.exists() {
+            let searched: Vec<String> = config::search_paths()
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            eprintln!(
+                "Searched {}, pass --config or set ORPHEUS_CONFIG to use another path.",
+                searched.join(", ")
+            );
+        }
+        std::process::exit(1);
+    }
+    path
+}
+
+/// Resolves once the server is asked to stop, after telling clients on the
+/// event stream, whose connections would otherwise keep it from stopping.
+async fn shutdown_signal() {
+    persistence::shutdown_signal().await;
+    EventService.shut_down();
+}
+
+/// `orpheus account export [<file>] [--format json|toml]` writes the registry to
+/// `file` (or stdout), `orpheus account import <file> [--replace] [--dry-run]`
+/// loads one back. The format follows the file extension unless given.
+fn account_command(args: &[String]) {
+    let flag_value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+    };
+    let format_flag: Option<ExportFormat> =
+        flag_value("--format").map(|format| match format.as_str() {
+            "json" => ExportFormat::Json,
+            "toml" => ExportFormat::Toml,
+            other => {
+                tracing::error!("Unknown format \"{other}\", expected json or toml!");
+                std::process::exit(1);
+            }
+        });
+    // the first argument after the action that isn't a flag or a flag's value
+    let file: Option<&Path> = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, arg)| !arg.starts_with("--") && args[i - 1] != "--format")
+        .map(|(_, arg)| Path::new(arg.as_str()));
+
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            let format = format_flag
+                .unwrap_or_else(|| file.map_or(ExportFormat::Json, ExportFormat::from_path));
+            let text: String = format
+                .encode(&AccountService.export())
+                .expect("Failed to serialize account registry!");
+            match file {
+                Some(path) => {
+                    // every password hash is in there
+                    fs::private_options()
+                        .create_new(true)
+                        .open(path)
+                        .and_then(|mut file| file.write_all(text.as_bytes()))
+                        .unwrap_or_else(|e| panic!("Failed to write {}: {e}", path.display()));
+                    info!("Exported accounts to {}", path.display());
+                }
+                None => println!("{text}"),
+            }
+        }
+        Some("import") => {
+            let Some(path) = file else {
+                tracing::error!("Please provide the file to import!");
+                std::process::exit(1);
+            };
+            let format = format_flag.unwrap_or_else(|| ExportFormat::from_path(path));
+            let export = std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|text| format.decode(&text))
+                .unwrap_or_else(|e| {
+                    tracing::error!("Failed to read {}: {e}", path.display());
+                    std::process::exit(1);
+                });
+            let mode = if args.iter().any(|arg| arg == "--replace") {
+                ImportMode::Replace
+            } else {
+                ImportMode::Merge
+            };
+            let dry_run: bool = args.iter().any(|arg| arg == "--dry-run");
+            let report = AccountService
+                .import(export, mode, dry_run)
+                .expect("Failed to import accounts!");
+            AccountService.save(); // statics are never dropped
+
+            let verb = if dry_run { "would be " } else { "" };
+            for (label, usernames) in [
+                ("added", &report.added),
+                ("replaced", &report.replaced),
+                ("removed", &report.removed),
+                ("unchanged", &report.unchanged),
+            ] {
+                if !usernames.is_empty() {
+                    println!("{verb}{label}: {}", usernames.join(", "));
+                }
+            }
+            for username in &report.conflicts {
+                println!("conflict: {username} differs from the registered account, kept the registered one");
+            }
+            for (username, reason) in &report.invalid {
+                println!("invalid: {username:?} skipped, {reason}");
+            }
+            if !report.invalid.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            tracing::error!("Please provide an account action! Valid actions are: export, import");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `orpheus init-config [<file>] [--interactive] [--force]` writes a commented
+/// config with every setting at its default to `file`, or wherever the config
+/// is looked for. With `--interactive`, the most important ones are asked for.
+fn init_config_command(args: &[String]) {
+    let path: PathBuf = args
+        .iter()
+        .find(|arg| !arg.starts_with('-'))
+        .map_or_else(config::path, PathBuf::from);
+    if path.exists() && !args.iter().any(|arg| arg == "--force") {
+        tracing::error!(
+            "{} already exists, pass --force to overwrite it!",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+
+    let mut new = config::Config::default();
+    if args.iter().any(|arg| arg == "--interactive" || arg == "-i") {
+        let bind_address: String = loop {
+            let answer = prompt("Address to listen on", new.server().bind_address());
+            match config::check_bind_address(&answer) {
+                Ok(()) => break answer,
+                Err(e) => println!("{e}"),
+            }
+        };
+        new.server_mut().set_bind_address(bind_address);
+        let data_dir = prompt("Directory to keep server data in", new.server().data_dir());
+        new.server_mut().set_data_dir(data_dir);
+        let roots: Vec<String> = prompt("Music directories, separated by commas", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|root| !root.is_empty())
+            .map(str::to_owned)
+            .collect();
+        for root in roots.iter().filter(|root| !Path::new(root).is_dir()) {
+            println!("Note that {root} isn't a directory yet.");
+        }
+        new.library_mut().set_roots(roots);
+    }
+
+    if let Err(e) = new.save_to(&path) {
+        tracing::error!("Failed to write {}: {e}", path.display());
+        std::process::exit(1);
+    }
+    info!("Wrote {}", path.display());
+    // e.g. a data directory that can't be created, better to hear now than on startup
+    if let Err(e) = config::Config::load_checked(&path) {
+        tracing::warn!("{e}");
+    }
+}
+
+/// Asks `question` on the terminal, returning the trimmed answer or `default`
+/// if there was none.
+fn prompt(question: &str, default: &str) -> String {
+    match default {
+        "" => print!("{question}: "),
+        _ => print!("{question} [{default}]: "),
+    }
+    std::io::stdout()
+        .flush()
+        .expect("Failed to write to stdout!");
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .expect("Failed to read from stdin!");
+    match answer.trim() {
+        "" => default.to_owned(),
+        answer => answer.to_owned(),
+    }
 }
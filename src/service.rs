@@ -1,4 +1,18 @@
 pub mod accounts;
 pub mod auth;
+pub mod backup;
+pub mod crypto;
+pub mod data_dir;
+pub mod events;
 pub mod fs;
+pub mod library;
+pub mod logging;
+pub mod metrics;
+pub mod persistence;
+pub mod reload;
 pub mod scanner;
+pub mod schema;
+pub mod scrobble;
+pub mod storage;
+pub mod tls;
+pub mod user_data;
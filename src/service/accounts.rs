@@ -1,7 +1,7 @@
 //! # Server Accounts
 //! Manages the storage and retrieval of accounts to/from disk. This module
 //! focuses on loading the account database, registering/deleting accounts,
-//! and saving the database back to file.
+//! and saving the database back to its storage backend.
 
 use anyhow::{bail, Result};
 use papaya::HashMap;
@@ -11,55 +11,85 @@ use scrypt::{
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    fmt::Display,
-    path::{Path, PathBuf},
+    path::Path,
     sync::{Arc, LazyLock, Mutex},
 };
 use tracing::{debug, trace};
 
-use crate::services;
+use crate::{
+    secret::SecretString,
+    service::{
+        scrobble::ScrobbleTarget,
+        storage::{StorageBackend, Table},
+    },
+    services,
+};
 
 /// Global variable holding the singleton instance of [AccountsManager].
 ///
 /// TODO:
 /// - Consider changing to a frozen map
 #[allow(non_upper_case_globals)] // i like the "*Service" naming scheme, sue me
-pub static AccountService: LazyLock<AccountsManager> = LazyLock::new(|| {
-    let data_path = PathBuf::from(
-        services::Config
-            .try_read() // we immediately try to acquire the lock as this is startup
-            .unwrap() // immediately unwrap said lock since nothing else is locking yet
-            .server()
-            .account_data_path(), // see key [server.account_data_path] in `orpheus.toml`
-    );
-    AccountsManager::from_path(data_path)
-});
+pub static AccountService: LazyLock<AccountsManager> =
+    LazyLock::new(|| AccountsManager::from_storage(services::StorageService.clone()));
+
+/// Hash of a random password no one knows, checked when logging in to an
+/// account that doesn't exist, see [AccountsManager::login]. Made with the
+/// same parameters [AccountsManager::register] uses, so it takes as long.
+const DUMMY_HASH: &str =
+    "$scrypt$ln=17,r=8,p=1$lBjpr45+hKKfa/wqBxhl/Q$LYoCmmLC6J66ge+5tyRG6Dagp7BM3SHnxNKGFE+Ril8";
 
 /// A small data struct to hold information about an account. Username is a duplicate
 /// field here despite also being used as the key to the HashMap.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct AccountRecord {
     username: String,
-    password_hash: String,
+    /// masked when logged, like everything else derived from a password
+    password_hash: SecretString,
     /// can the user manage the server (i.e. create new accounts?)
     is_admin: bool,
+    /// defaulted so registries saved before user data existed still load
+    #[serde(default)]
+    data: AccountData,
 }
 
-#[derive(Serialize, Deserialize)]
-struct AccountData {
+/// Per-user settings and data that aren't needed to authenticate.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountData {
     // all fields commented out need their respective types to be
     // implemented before they can be uncommented.
     // playlists: UserPlaylists,
     // stats: StatRecorder,
+    /// ListenBrainz-compatible services to forward this user's plays to
+    #[serde(default)]
+    scrobble_targets: Vec<ScrobbleTarget>,
+    /// password for Subsonic clients, which need it in the clear to check
+    /// their salted tokens, so it's separate from the account password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subsonic_password: Option<SecretString>,
 }
 
-crate::make_getters!(AccountRecord, username: String, password_hash: String, is_admin: bool);
+crate::make_getters!(AccountRecord, username: String, password_hash: SecretString, is_admin: bool, data: AccountData);
+crate::make_getters!(AccountData, scrobble_targets: Vec<ScrobbleTarget>, subsonic_password: Option<SecretString>);
+
+impl AccountData {
+    pub fn scrobble_targets_mut(&mut self) -> &mut Vec<ScrobbleTarget> {
+        &mut self.scrobble_targets
+    }
+
+    pub fn set_subsonic_password(&mut self, password: Option<SecretString>) {
+        self.subsonic_password = password;
+    }
+}
 
-/// A thread-safe in-memory account database. It is initialized by providing a path to
-/// a database file, one it will either create or read depending on the constructor used.
+/// A thread-safe in-memory account database. It is initialized by providing the
+/// [StorageBackend] to persist the registry to, which it will either fill or read
+/// from depending on the constructor used. Every change is written through to the
+/// backend before the call making it returns.
 pub struct AccountsManager {
-    path: PathBuf,
-    dirty: Mutex<bool>,
+    storage: Arc<dyn StorageBackend>,
+    /// serializes changes, so the backend sees them in the same order as the map
+    write_lock: Mutex<()>,
     accounts: Arc<HashMap<String, Arc<AccountRecord>>>,
 }
 
@@ -74,146 +104,356 @@ impl AsRef<AccountsManager> for AccountsManager {
     }
 }
 
+/// The registry as written by `orpheus account export`, in JSON or TOML.
+/// Records keep their password hashes, so exports must be guarded as
+/// carefully as the registry itself.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccountExport {
+    /// bumped whenever the layout of exported records changes
+    pub version: u32,
+    #[serde(default)]
+    pub accounts: Vec<AccountRecord>,
+}
+
+impl AccountExport {
+    pub const VERSION: u32 = 1;
+}
+
+/// The text formats the registry can be exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Toml,
+}
+
+impl ExportFormat {
+    /// Picks the format matching the extension of `path`, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ExportFormat::Toml,
+            _ => ExportFormat::Json,
+        }
+    }
+
+    pub fn encode(self, export: &AccountExport) -> Result<String> {
+        Ok(match self {
+            ExportFormat::Json => serde_json::to_string_pretty(export)?,
+            ExportFormat::Toml => toml::to_string_pretty(export)?,
+        })
+    }
+
+    pub fn decode(self, text: &str) -> Result<AccountExport> {
+        let export: AccountExport = match self {
+            ExportFormat::Json => serde_json::from_str(text)?,
+            ExportFormat::Toml => toml::from_str(text)?,
+        };
+        if export.version > AccountExport::VERSION {
+            bail!(
+                "export is version {}, but this build of Orpheus only understands up to version {}",
+                export.version,
+                AccountExport::VERSION
+            );
+        }
+        Ok(export)
+    }
+}
+
+/// How [AccountsManager::import] treats accounts that are already registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportMode {
+    /// add new accounts, keeping the registered version of any that exist in both
+    Merge,
+    /// make the registry exactly the imported accounts
+    Replace,
+}
+
+/// What an import did, or would have done.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    /// imported in place of a differing registered account (replace mode)
+    pub replaced: Vec<String>,
+    /// registered accounts missing from the import (replace mode)
+    pub removed: Vec<String>,
+    /// identical in the registry and the import
+    pub unchanged: Vec<String>,
+    /// registered accounts that differ from the import and were kept (merge mode)
+    pub conflicts: Vec<String>,
+    /// imported records that were skipped, with the reason why
+    pub invalid: Vec<(String, String)>,
+}
+
 pub enum LoginCode {
     Success(Arc<AccountRecord>),
     InvalidPassword,
     AccountNotFound,
 }
 
+/// Why a change to the registry was refused, returned inside the
+/// [anyhow::Error] so callers can tell it apart from storage failures.
+#[derive(thiserror::Error, Debug)]
+pub enum AccountError {
+    #[error("account {0} already exists")]
+    Exists(String),
+    #[error("account {0} does not exist")]
+    NotFound(String),
+}
+
 impl AccountsManager {
     // Constructors //
-    pub fn create(to_path: impl Display, map: HashMap<String, Arc<AccountRecord>>) -> Self {
-        let path: PathBuf = PathBuf::from(to_path.to_string()); // convert generic parameter to [String]
-        if let Some(p) = path.parent() {
-            std::fs::create_dir_all(p) // make all necessary directories to create data file
-                .expect("Failed to create data file path! Double check write permissions.");
+    /// Replaces whatever registry `storage` holds with the accounts in `map`.
+    pub fn create(
+        storage: Arc<dyn StorageBackend>,
+        map: HashMap<String, Arc<AccountRecord>>,
+    ) -> Self {
+        for (username, _) in storage
+            .load(Table::Accounts)
+            .expect("Failed to load accounts!")
+        {
+            if !map.pin().contains_key(&username) {
+                storage
+                    .delete(Table::Accounts, &username)
+                    .expect("Failed to delete account!");
+            }
+        }
+        for (username, record) in map.pin().iter() {
+            storage
+                .put_record(Table::Accounts, username, record)
+                .expect("Failed to save account!");
         }
 
-        let s = Self {
-            path,
-            dirty: Mutex::new(false),
+        Self {
+            storage,
+            write_lock: Mutex::new(()),
             accounts: Arc::new(map),
-        };
-        s.save(); // test if writing crashes so the user doesn't find out when it's too late
-        s
-    }
-
-    pub fn from_path(path: PathBuf) -> Self {
-        if let Some(p) = path.parent() {
-            std::fs::create_dir_all(p) // make all necessary directories to create data file
-                .expect("Failed to create data file path! Double check write permissions.");
         }
+    }
 
-        let accounts: HashMap<String, Arc<AccountRecord>> = if !path.exists() {
-            HashMap::new() // if there's no file at path, make a new map
-        } else {
-            let contents: Vec<u8> = std::fs::read(&path).expect("Failed to read data file!");
-            pot::from_slice(contents.as_slice()).expect("Failed to deserialize account data file!")
-        };
+    /// Loads the registry held by `storage`.
+    pub fn from_storage(storage: Arc<dyn StorageBackend>) -> Self {
+        let accounts: HashMap<String, Arc<AccountRecord>> = storage
+            .load_records(Table::Accounts)
+            .expect("Failed to load account data!")
+            .into_iter()
+            .collect();
 
         let new: Self = Self {
-            path,
-            dirty: Mutex::new(false),
+            storage,
+            write_lock: Mutex::new(()),
             accounts: Arc::new(accounts),
         };
-        trace!("Creating account manager with table: {:?}", new.accounts);
-        new.save();
+        trace!(
+            "Creating account manager with {} accounts",
+            new.accounts.len()
+        );
         new
     }
 
     // Methods //
-    /// Unmarks the struct as dirty and saves the entire contents
-    /// to the file path provided on creation of the struct.
+    /// Flushes the storage backend, which for the pot file backend means
+    /// compacting its write-ahead logs into snapshots.
     pub fn save(&self) {
-        *self.dirty.lock().unwrap() = false; // set self.dirty to false
-        trace!("Saving accounts database with table: {:?}", self.accounts);
-        let encoded: Vec<u8> =
-            pot::to_vec(self.accounts.as_ref()).expect("Failed to serialize accounts storage!");
-        let path: &Path = self.path.as_ref();
-        std::fs::write(path, &encoded).expect("Failed to save to accounts DB path!");
+        trace!("Saving accounts database");
+        self.storage
+            .flush()
+            .expect("Failed to flush account storage!");
     }
 
-    /// Uploads an account record directly to the map, using a clone of
-    /// its `username` field as the key.
-    pub fn register_from_record(&self, record: AccountRecord) -> Result<()> {
-        *self.dirty.lock().unwrap() = true;
-        let amap = self.accounts.clone(); // obtain atomic reference to map
-        let map = amap.pin(); // lock map's memory from being freed
-        if !map.contains_key(&record.username) {
-            debug!(
-                "Registering account {{ username: {}, password: {} }}",
-                &record.username, &record.password_hash
-            );
-            map.insert(record.username.clone(), Arc::new(record));
-            Ok(())
-        } else {
-            bail!("Account already exists!")
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Inserts `record` into the map and durably stores it before returning.
+    /// Whether the account has to be registered already, or not, is checked
+    /// under the write lock, so concurrent changes can't undo each other.
+    fn commit(&self, record: Arc<AccountRecord>, exists: bool) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        match self.accounts.pin().contains_key(&record.username) {
+            true if !exists => bail!(AccountError::Exists(record.username.clone())),
+            false if exists => bail!(AccountError::NotFound(record.username.clone())),
+            _ => {}
         }
+        self.storage
+            .put_record(Table::Accounts, &record.username, &record)?;
+        self.accounts.pin().insert(record.username.clone(), record);
+        Ok(())
     }
 
-    /// Registers from record without checking if it already exists. Used only
-    /// in the [AccountsManager::register] function to skip redundant checking.
-    fn register_from_record_unchecked(&self, record: AccountRecord) {
-        *self.dirty.lock().unwrap() = true;
-        let map = self.accounts.clone();
-        debug!(
-            "Registering account {{ username: {}, password: {} }}",
-            &record.username, &record.password_hash
-        );
-        map.pin().insert(record.username.clone(), Arc::new(record));
+    /// Uploads an account record directly to the map, using a clone of
+    /// its `username` field as the key.
+    pub fn register_from_record(&self, record: AccountRecord) -> Result<()> {
+        debug!("Registering account {}", &record.username);
+        self.commit(Arc::new(record), false)
     }
 
     /// Creates a new entry in the account registry with:
     /// 1. the username as the key,
     /// 2. and an [AccountRecord] containing a clone of the username,
     ///    the password, and whether or not the account is an admin.
-    pub fn register(&self, username: String, password: String, is_admin: bool) -> Result<()> {
+    pub fn register(&self, username: String, password: SecretString, is_admin: bool) -> Result<()> {
         let salt = SaltString::generate(&mut OsRng); // generate salt for the password hash
         let map = self.accounts.clone(); // obtain reference to map
         let password_hash = if !map.pin().contains_key(&username) {
             Scrypt // return newly hashed password if not already registered
-                .hash_password(password.as_bytes(), &salt)?
+                .hash_password(password.expose().as_bytes(), &salt)?
                 .to_string()
+                .into()
         } else {
             tracing::error!("Failed to register already-registered account \"{username}\"!");
-            bail!("Account already exists!") // error on existing account
+            bail!(AccountError::Exists(username)) // error on existing account
         };
         drop(map); // drop our reference to map as next function will reference it
         let record: AccountRecord = AccountRecord {
             username,
             password_hash,
             is_admin,
+            data: AccountData::default(),
         };
-        self.register_from_record_unchecked(record);
-        Ok(())
+        // checked again when committing, as it may have been registered while hashing
+        self.register_from_record(record)
     }
 
     /// Attempts to verify the provided password against the entry for the
-    /// username provided. This function will either return:
-    /// - `Some(true)` if the username and password are both valid and correct
-    /// - `Some(false)` if the username is registered, but the password is incorrect
-    /// - `None` if the username is not registered.
+    /// username provided, returning the record on success.
     ///
-    /// Note that this function can also return `None` if the password hash fails to
-    /// be read into the password hash format, however this shouldn't happen in
-    /// practice and most likely doesn't need to be accounted for.
-    pub fn login(&self, username: &str, password: &str) -> LoginCode {
-        let amap = self.accounts.clone(); // obtain atomic reference to map
-        let map = amap.pin(); // lock map's memory from being freed
-        if let Some(record) = map.get(username).cloned() {
-            let hash = PasswordHash::new(record.password_hash()).unwrap(); // parse hash (should never fail)
-            if Scrypt.verify_password(password.as_bytes(), &hash).is_ok() {
-                LoginCode::Success(record)
-            } else {
-                LoginCode::InvalidPassword
+    /// An unknown username takes as long to turn down as a wrong password, as
+    /// the password is checked against [DUMMY_HASH] instead, so the time a
+    /// login takes doesn't give away which usernames exist.
+    pub fn login(&self, username: &str, password: &SecretString) -> LoginCode {
+        let record: Option<Arc<AccountRecord>> = self.get(username);
+        let hash: &str = record
+            .as_ref()
+            .map_or(DUMMY_HASH, |record| record.password_hash().expose());
+        let hash = PasswordHash::new(hash).unwrap(); // parse hash (should never fail)
+        let verified: bool = Scrypt
+            .verify_password(password.expose().as_bytes(), &hash)
+            .is_ok();
+        match record {
+            Some(record) if verified => LoginCode::Success(record),
+            Some(_) => LoginCode::InvalidPassword,
+            None => LoginCode::AccountNotFound,
+        }
+    }
+
+    /// Returns the record registered under `username`, if any.
+    pub fn get(&self, username: &str) -> Option<Arc<AccountRecord>> {
+        self.accounts.pin().get(username).cloned()
+    }
+
+    /// Applies `f` to a copy of the user data of `username` and swaps the
+    /// updated record into the registry.
+    pub fn update_data(&self, username: &str, f: impl FnOnce(&mut AccountData)) -> Result<()> {
+        let Some(record) = self.get(username) else {
+            bail!(AccountError::NotFound(username.to_owned()))
+        };
+        let mut updated: AccountRecord = record.as_ref().clone();
+        f(&mut updated.data);
+        self.commit(Arc::new(updated), true)
+    }
+
+    /// Removes `username` from the registry, along with their user data,
+    /// returning whether it was registered.
+    pub fn delete(&self, username: &str) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap();
+        if !self.accounts.pin().contains_key(username) {
+            return Ok(false);
+        }
+        self.storage.delete(Table::UserData, username)?;
+        self.storage.delete(Table::Accounts, username)?;
+        self.accounts.pin().remove(username);
+        Ok(true)
+    }
+
+    /// Every registered account, sorted by username.
+    pub fn export(&self) -> AccountExport {
+        let mut accounts: Vec<AccountRecord> = self
+            .accounts
+            .pin()
+            .values()
+            .map(|record| record.as_ref().clone())
+            .collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        AccountExport {
+            version: AccountExport::VERSION,
+            accounts,
+        }
+    }
+
+    /// Loads the accounts of `export` into the registry according to `mode`.
+    /// Records with an unparseable password hash, or whose username appears more
+    /// than once, are skipped. With `dry_run` the registry is left untouched.
+    pub fn import(
+        &self,
+        export: AccountExport,
+        mode: ImportMode,
+        dry_run: bool,
+    ) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+
+        for record in export.accounts {
+            let username: String = record.username.clone();
+            if username.is_empty() {
+                report.invalid.push((username, "empty username".into()));
+                continue;
+            }
+            if !seen.insert(username.clone()) {
+                report
+                    .invalid
+                    .push((username, "listed more than once".into()));
+                continue;
+            }
+            if let Err(e) = PasswordHash::new(record.password_hash.expose()) {
+                report
+                    .invalid
+                    .push((username, format!("invalid password hash: {e}")));
+                continue;
+            }
+
+            match self.get(&username) {
+                None => {
+                    if !dry_run {
+                        self.commit(Arc::new(record), false)?;
+                    }
+                    report.added.push(username);
+                }
+                Some(existing) if *existing == record => report.unchanged.push(username),
+                Some(_) if mode == ImportMode::Merge => report.conflicts.push(username),
+                Some(_) => {
+                    if !dry_run {
+                        self.commit(Arc::new(record), true)?;
+                    }
+                    report.replaced.push(username);
+                }
             }
-        } else {
-            LoginCode::AccountNotFound
         }
+
+        if mode == ImportMode::Replace {
+            let mut stale: Vec<String> = self
+                .accounts
+                .pin()
+                .keys()
+                .filter(|username| !seen.contains(*username))
+                .cloned()
+                .collect();
+            stale.sort();
+            for username in stale {
+                if !dry_run {
+                    self.delete(&username)?;
+                }
+                report.removed.push(username);
+            }
+        }
+        Ok(report)
     }
 
+    /// Whether the storage backend has work left for [AccountsManager::save].
     pub fn is_dirty(&self) -> bool {
-        *self.dirty.lock().unwrap()
+        self.storage.is_dirty()
     }
 }
 
@@ -222,8 +462,11 @@ impl AccountsManager {
 /// Typically the solution reached is allowing manual saving + auto-saving at
 /// every set interval or action. However, since Rust will call
 /// every struct's drop implementation, on graceful exit or on panic, we can
-/// simply just delegate the saving to disk then. Ideally we'll implement more frequent
-/// saves than that though, to account for power outages and [std::process::exit] calls.
+/// simply just delegate the saving to disk then. Changes are already durable in the
+/// storage backend by then, so this only spares the next startup from replaying logs.
+///
+/// Statics are never dropped though, so for [AccountService] the server flushes the
+/// storage backend itself on shutdown, see the `persistence` module.
 impl Drop for AccountsManager {
     fn drop(&mut self) {
         self.save();
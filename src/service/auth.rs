@@ -6,15 +6,19 @@
 
 use std::sync::{Arc, LazyLock};
 
-use crate::types::LoginCode;
-use crate::{services::AccountService, types::AccountRecord};
+use crate::service::storage::{StorageBackend, Table};
+use crate::types::{LoginCode, SecretString};
+use crate::{
+    services::{AccountService, StorageService},
+    types::AccountRecord,
+};
 use axum::response::IntoResponse;
 use chrono::{prelude::*, TimeDelta};
 use papaya::HashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-const SESSION_EXPIRY: TimeDelta = TimeDelta::hours(6);
-
 // Simple strong type around Uuid for clarity
 #[derive(Hash, PartialEq, Eq, Clone, Copy)]
 pub struct Token(pub Uuid);
@@ -23,8 +27,17 @@ impl Token {
     pub fn generate() -> Self {
         Self(Uuid::new_v4())
     }
+
+    /// What the token is kept as, so a copy of the session table can't be
+    /// used to take over anyone's session.
+    pub fn hash(&self) -> TokenHash {
+        Sha256::digest(self.0.as_bytes()).into()
+    }
 }
 
+/// SHA-256 of a [Token], see [Token::hash].
+pub type TokenHash = [u8; 32];
+
 impl TryFrom<&str> for Token {
     type Error = uuid::Error;
 
@@ -45,6 +58,11 @@ impl IntoResponse for Token {
     }
 }
 
+/// Target of the audit log, the events that matter for security such as
+/// failed logins. Filter it separately with a `logging.directives` entry
+/// like `audit=info`.
+pub const AUDIT: &str = "audit";
+
 /// global variable holding the singleton instance of [AuthManager].
 pub static SESSIONS: LazyLock<AuthManager> = LazyLock::new(AuthManager::start);
 
@@ -52,7 +70,7 @@ pub static SESSIONS: LazyLock<AuthManager> = LazyLock::new(AuthManager::start);
 /// current session.
 pub struct AccountSession {
     record: Arc<AccountRecord>,
-    token: Token,
+    token_hash: TokenHash,
     started: DateTime<Utc>,
     expires: DateTime<Utc>,
 }
@@ -62,10 +80,6 @@ impl AccountSession {
         &self.record
     }
 
-    pub fn token(&self) -> Token {
-        self.token
-    }
-
     pub fn started(&self) -> DateTime<Utc> {
         self.started
     }
@@ -75,18 +89,42 @@ impl AccountSession {
     }
 
     pub fn is_expired(&self) -> bool {
-        self.started() >= self.expires()
+        self.expires() <= Utc::now()
+    }
+}
+
+/// How a session is kept in the storage backend under the hex-encoded hash of
+/// its token, so logins survive restarts without the token touching the disk. The account record itself is looked up again when loading.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    /// missing from sessions stored by username by older versions, which
+    /// are dropped on load like those stored under their plain token
+    #[serde(default)]
+    username: String,
+    started: i64,
+    expires: i64,
+}
+
+impl From<&AccountSession> for StoredSession {
+    fn from(session: &AccountSession) -> Self {
+        Self {
+            username: session.record.username().clone(),
+            started: session.started.timestamp(),
+            expires: session.expires.timestamp(),
+        }
     }
 }
 
 /// A global authentication manager which handles logging in users and
 /// authenticating their requests via session tokens. This struct uses
 /// UUID v4s as session tokens, which are issued upon a successful login and
-/// stored in the account record. A user authenticates themselves per-action
-/// by providing their session token along with their username.
+/// only ever kept as their hash. A user authenticates themselves per-action
+/// by providing their session token along with their username. An account
+/// can have any number of sessions, one per device it logged in from.
 pub struct AuthManager {
-    /// A hash table mapping usernames to their respective session instances.
-    sessions: Arc<HashMap<String, Arc<AccountSession>>>,
+    storage: Arc<dyn StorageBackend>,
+    /// A hash table mapping token hashes to their respective session instances.
+    sessions: Arc<HashMap<TokenHash, Arc<AccountSession>>>,
 }
 
 // Mark types as safe to send since all methods use thread-safe
@@ -94,12 +132,13 @@ pub struct AuthManager {
 unsafe impl Send for AuthManager {}
 unsafe impl Sync for AuthManager {}
 
-/// Basic wrapper strong-type around the 3 possible login results.
+/// Basic wrapper strong-type around the 3 possible login results. A new
+/// session comes with its token, which can't be recovered from the session.
 /// This is used in `AuthManager` to return an account session, and is not
 /// necessary in the login function for `AccountsManager` as that isn't
 /// API-facing.
 pub enum AuthCode {
-    Success(Arc<AccountSession>),
+    Success(Arc<AccountSession>, Token),
     InvalidPassword,
     AccountNotFound,
 }
@@ -107,64 +146,119 @@ pub enum AuthCode {
 impl AuthManager {
     // Constructor //
     pub fn start() -> Self {
+        Self::from_storage(StorageService.clone())
+    }
+
+    /// Restores the sessions kept in `storage` that haven't expired yet and
+    /// still belong to a registered account, forgetting the rest.
+    pub fn from_storage(storage: Arc<dyn StorageBackend>) -> Self {
+        let sessions: HashMap<TokenHash, Arc<AccountSession>> = HashMap::new();
+        let stored: Vec<(String, StoredSession)> = storage
+            .load_records(Table::Sessions)
+            .expect("Failed to load sessions!");
+        let now = Utc::now();
+        for (key, stored) in stored {
+            let session = match (
+                AccountService.get(&stored.username),
+                hex::decode(&key)
+                    .ok()
+                    .and_then(|hash| TokenHash::try_from(hash).ok()),
+                DateTime::from_timestamp(stored.started, 0),
+                DateTime::from_timestamp(stored.expires, 0),
+            ) {
+                (Some(record), Some(token_hash), Some(started), Some(expires)) if expires > now => {
+                    AccountSession {
+                        record,
+                        token_hash,
+                        started,
+                        expires,
+                    }
+                }
+                _ => {
+                    tracing::debug!("dropping stale session of {:?}", stored.username);
+                    if let Err(e) = storage.delete(Table::Sessions, &key) {
+                        tracing::error!("Failed to delete session of {:?}: {e}", stored.username);
+                    }
+                    continue;
+                }
+            };
+            sessions.pin().insert(session.token_hash, Arc::new(session));
+        }
+
         Self {
-            sessions: Arc::new(HashMap::new()),
+            storage,
+            sessions: Arc::new(sessions),
         }
     }
 
     // Methods //
-    /// Registers a given [AccountSession] into the global session table. This
-    /// method will return [true] if the account was successfully logged in, and [false]
-    /// if the account was already logged in and it's session has not yet expired.
-    fn register_new_session(&self, session: Arc<AccountSession>) -> bool {
+    /// Registers a given [AccountSession] into the global session table,
+    /// alongside any other sessions of the account. Expired sessions are
+    /// forgotten on the way.
+    fn register_new_session(&self, session: Arc<AccountSession>) {
         let sessions = self.sessions.clone();
         let map = sessions.pin();
         let name: &str = session.record().username();
 
-        tracing::debug!(?name, "attempting to register session");
-        if !map.contains_key(name) || map.get(name).unwrap().is_expired() {
-            tracing::debug!(
-                "registered session for {name} with {}",
-                session.token().0.to_string()
-            );
-            map.insert(name.to_owned(), session);
-            true
-        } else {
-            false
+        for (hash, _) in map.iter().filter(|(_, session)| session.is_expired()) {
+            map.remove(hash);
+            if let Err(e) = self.storage.delete(Table::Sessions, &hex::encode(hash)) {
+                tracing::error!("Failed to delete expired session: {e}");
+            }
+        }
+
+        tracing::debug!("registered session for {name}");
+        if let Err(e) = self.storage.put_record(
+            Table::Sessions,
+            &hex::encode(session.token_hash),
+            &StoredSession::from(session.as_ref()),
+        ) {
+            // the session still works until the server restarts
+            tracing::error!("Failed to store session of {name}: {e}");
         }
+        map.insert(session.token_hash, session);
     }
 
-    /// TODO:
-    /// - Add customizable session expiry (current +6 hours)
-    pub fn login(&self, username: &str, password: &str) -> AuthCode {
+    /// Logs in with the given credentials, starting a session that lasts for
+    /// `expiry`, see `session_expiry_hours` in the config. Sessions started
+    /// by earlier logins keep working.
+    pub fn login(&self, username: &str, password: &SecretString, expiry: TimeDelta) -> AuthCode {
         match AccountService.login(username, password) {
             LoginCode::Success(record) => {
                 let now = Utc::now();
+                let token = Token::generate();
                 let session = AccountSession {
                     record,
-                    token: Token::generate(),
+                    token_hash: token.hash(),
                     started: now,
-                    expires: now + SESSION_EXPIRY,
+                    expires: now + expiry,
                 };
                 let sr: Arc<AccountSession> = Arc::new(session);
                 self.register_new_session(sr.clone());
-                AuthCode::Success(sr.clone())
+                AuthCode::Success(sr.clone(), token)
             }
             LoginCode::InvalidPassword => AuthCode::InvalidPassword,
             LoginCode::AccountNotFound => AuthCode::AccountNotFound,
         }
     }
 
+    /// How many sessions haven't expired yet.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions
+            .pin()
+            .values()
+            .filter(|session| !session.is_expired())
+            .count()
+    }
+
     /// Attempts to authenticate a user's credentials by ensuring they have the
-    /// correct session token for their username.
+    /// correct session token for their username, and that it hasn't expired.
     pub fn auth_get_session(&self, username: &str, token: Token) -> Option<Arc<AccountSession>> {
         let map = self.sessions.clone();
         let guard = map.pin();
-        let record = guard.get(username);
-        if record.is_some_and(|u| u.token() == token) {
-            Some(record.unwrap().clone())
-        } else {
-            None
-        }
+        guard
+            .get(&token.hash())
+            .filter(|session| session.record().username() == username && !session.is_expired())
+            .cloned()
     }
 }
@@ -0,0 +1,341 @@
+//! # Backup and Restore
+//! `orpheus backup <archive>` writes a gzipped tarball of every persistent
+//! store, taken from the storage backend in a single point in time (see
+//! [StorageBackend::snapshot]) rather than by copying files that may be
+//! half-written. While the server is running it holds the data directory
+//! lock, so the snapshot is requested from it over the control socket in the
+//! data directory instead, which only the user running the server can open.
+//!
+//! An archive holds:
+//! - `manifest.json`, see [Manifest],
+//! - `db/<table>` for every [Table], in the format of a pot snapshot (see
+//!   the `schema` module) regardless of the backend it was taken from,
+//! - `scrobbles`, the listens waiting to be forwarded.
+//!
+//! With encryption at rest enabled, the whole archive is sealed too.
+//!
+//! `orpheus restore <archive>` validates all of it before anything in the
+//! data directory is touched, then moves the current data aside.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tracing::{error, info};
+
+use crate::{
+    config::{BackendKind, Config},
+    service::{
+        crypto::{self, Cipher},
+        data_dir::DataDir,
+        fs,
+        schema::{self, FileKind},
+        scrobble::ScrobbleQueue,
+        storage::{self, StorageBackend, Table},
+    },
+};
+
+/// What a sealed archive is authenticated as, see [crypto::seal].
+const ARCHIVE_AAD: &[u8] = b"backup";
+
+/// Describes the contents of an archive.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// bumped whenever the layout of archives changes
+    pub format: u32,
+    /// version of Orpheus that took the backup
+    pub server_version: String,
+    /// unix timestamp of when the backup was taken
+    pub created: i64,
+    /// number of records in each table, by [Table::name]
+    pub tables: HashMap<String, usize>,
+}
+
+impl Manifest {
+    pub const FORMAT: u32 = 1;
+}
+
+/// The validated contents of an archive.
+pub struct Archive {
+    pub manifest: Manifest,
+    pub tables: Vec<(Table, HashMap<String, ByteBuf>)>,
+    /// encoded scrobble queue, see [ScrobbleQueue::encoded]
+    pub scrobbles: Vec<u8>,
+}
+
+/// Builds an archive of everything in `storage` and `scrobbles`, sealed with
+/// `cipher` if given.
+pub fn create_archive(
+    storage: &dyn StorageBackend,
+    scrobbles: &ScrobbleQueue,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<u8>> {
+    let snapshot = storage.snapshot()?;
+    let scrobbles: Vec<u8> = scrobbles.encoded()?;
+    let manifest = Manifest {
+        format: Manifest::FORMAT,
+        server_version: env!("CARGO_PKG_VERSION").to_owned(),
+        created: Utc::now().timestamp(),
+        tables: snapshot
+            .iter()
+            .map(|(table, records)| (table.name().to_owned(), records.len()))
+            .collect(),
+    };
+
+    let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    append(
+        &mut tar,
+        "manifest.json",
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    for (table, records) in snapshot {
+        let records: HashMap<String, ByteBuf> = records
+            .into_iter()
+            .map(|(key, value)| (key, ByteBuf::from(value)))
+            .collect();
+        let encoded: Vec<u8> = schema::encode(FileKind::Snapshot(table), &records)?;
+        append(&mut tar, &format!("db/{}", table.name()), &encoded)?;
+    }
+    append(&mut tar, "scrobbles", &scrobbles)?;
+    let compressed: Vec<u8> = tar.into_inner()?.finish()?;
+    Ok(crypto::seal(cipher, ARCHIVE_AAD, compressed))
+}
+
+fn append(tar: &mut tar::Builder<impl Write>, path: &str, contents: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    tar.append_data(&mut header, path, contents)?;
+    Ok(())
+}
+
+/// Reads and validates an archive made by [create_archive]: its format, the
+/// version of every table and record, and that nothing is missing.
+pub fn read_archive(bytes: &[u8], cipher: Option<&Cipher>) -> Result<Archive> {
+    // archives are handed over explicitly, so one taken before encryption was on is fine
+    let compressed: Vec<u8> = match crypto::is_sealed(bytes) {
+        true => crypto::unseal(cipher, ARCHIVE_AAD, bytes)?.0,
+        false => bytes.to_vec(),
+    };
+    let mut tar = tar::Archive::new(GzDecoder::new(&compressed[..]));
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    for entry in tar.entries().context("not a backup archive")? {
+        let mut entry = entry.context("corrupt backup archive")?;
+        let path: String = entry.path()?.to_string_lossy().into_owned();
+        let mut contents: Vec<u8> = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(path, contents);
+    }
+
+    let Some(manifest) = files.remove("manifest.json") else {
+        bail!("not a backup archive, manifest.json is missing");
+    };
+    let manifest: Manifest = serde_json::from_slice(&manifest).context("invalid manifest.json")?;
+    if manifest.format > Manifest::FORMAT {
+        bail!(
+            "archive is format {}, but this build of Orpheus only understands up to format {}",
+            manifest.format,
+            Manifest::FORMAT
+        );
+    }
+
+    let mut tables = Vec::new();
+    for table in Table::ALL {
+        let path = format!("db/{}", table.name());
+        let Some(contents) = files.remove(&path) else {
+            bail!("{path} is missing from the archive");
+        };
+        let records: HashMap<String, ByteBuf> =
+            schema::decode(FileKind::Snapshot(table), &contents)
+                .with_context(|| format!("{path} is corrupt"))?;
+        let expected: usize = manifest
+            .tables
+            .get(table.name())
+            .copied()
+            .unwrap_or_default();
+        if records.len() != expected {
+            bail!(
+                "{path} holds {} records, but the manifest lists {expected}",
+                records.len()
+            );
+        }
+        for (key, record) in &records {
+            let (version, _) = schema::split_header(record);
+            FileKind::Record(table)
+                .pending_migrations(version)
+                .with_context(|| format!("record {key:?} in {path}"))?;
+        }
+        tables.push((table, records));
+    }
+    let Some(scrobbles) = files.remove("scrobbles") else {
+        bail!("scrobbles is missing from the archive");
+    };
+    let (version, _) = schema::split_header(&scrobbles);
+    FileKind::ScrobbleQueue.pending_migrations(version)?;
+
+    Ok(Archive {
+        manifest,
+        tables,
+        scrobbles,
+    })
+}
+
+/// Reads the archive at `path`, mostly so errors name the file.
+pub fn read_archive_file(path: &Path, cipher: Option<&Cipher>) -> Result<Archive> {
+    let bytes: Vec<u8> =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    read_archive(&bytes, cipher).with_context(|| format!("invalid archive {}", path.display()))
+}
+
+/// Replaces the data in `data_dir` with the contents of `archive`. The new
+/// data is written next to the current one first, which is then moved aside
+/// to `<data_dir>/before-restore-<timestamp>`. Returns where it went.
+pub fn restore(
+    archive: Archive,
+    config: &Config,
+    data_dir: &DataDir,
+    cipher: Option<Arc<Cipher>>,
+) -> Result<PathBuf> {
+    if config.storage().backend() == BackendKind::Memory {
+        bail!("the memory storage backend has nothing to restore into");
+    }
+
+    let staging = DataDir::new(data_dir.root().join("restore.tmp"));
+    if staging.root().exists() {
+        std::fs::remove_dir_all(staging.root())?; // left over from a failed restore
+    }
+    fs::create_private_dir(staging.root())?;
+    {
+        let storage = storage::open(config, &staging, cipher.clone());
+        for (table, records) in archive.tables {
+            for (key, record) in records {
+                storage.put(table, &key, record.into_vec())?;
+            }
+        }
+        storage.flush()?;
+    }
+    let aad: Vec<u8> = FileKind::ScrobbleQueue.aad("");
+    let scrobbles: Vec<u8> = crypto::seal(cipher.as_deref(), &aad, archive.scrobbles);
+    fs::atomic_write(&staging.scrobble_queue(), &scrobbles, 0)?;
+
+    let aside = data_dir.root().join(format!(
+        "before-restore-{}",
+        Utc::now().format("%Y%m%d-%H%M%S")
+    ));
+    fs::create_private_dir(&aside)?;
+    for (current, restored) in [
+        (data_dir.db(), staging.db()),
+        (data_dir.scrobble_queue(), staging.scrobble_queue()),
+    ] {
+        let name = current.file_name().unwrap();
+        if current.exists() {
+            std::fs::rename(&current, aside.join(name))?;
+        }
+        std::fs::rename(&restored, &current)?;
+    }
+    std::fs::remove_dir_all(staging.root())?;
+    Ok(aside)
+}
+
+/// Asks the server running in `data_dir` for an archive. Returns `None` if
+/// no server is running there.
+#[cfg(unix)]
+pub fn request_archive(data_dir: &DataDir) -> Result<Option<Vec<u8>>> {
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = match UnixStream::connect(data_dir.control_socket()) {
+        Ok(stream) => stream,
+        Err(_) => return Ok(None), // left behind by a server that didn't shut down cleanly
+    };
+    stream.write_all(b"backup\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response: Vec<u8> = Vec::new();
+    stream.read_to_end(&mut response)?;
+    match response.strip_prefix(b"ok\n") {
+        Some(archive) => Ok(Some(archive.to_vec())),
+        None => bail!(
+            "server failed to take a backup: {}",
+            String::from_utf8_lossy(&response).trim()
+        ),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn request_archive(_: &DataDir) -> Result<Option<Vec<u8>>> {
+    Ok(None) // no control socket, the data directory lock will complain instead
+}
+
+/// Serves archives to `orpheus backup` over the control socket at `path`.
+/// Meant to be spawned once as a background task by the running server.
+#[cfg(unix)]
+pub async fn serve_control_socket(
+    path: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+    scrobbles: &'static ScrobbleQueue,
+    cipher: Option<Arc<Cipher>>,
+) {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    // bound in a directory only this user can enter and moved into place once
+    // restricted, so no one else can connect in between
+    let private: PathBuf = fs::with_suffix(&path, ".tmp");
+    let _ = std::fs::remove_dir_all(&private); // left over from a crash
+    let bound: PathBuf = private.join("socket");
+    let listener = std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .and_then(|()| {
+            let listener = tokio::net::UnixListener::bind(&bound)?;
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&bound, &path)?; // replaces a stale one, this instance holds the lock
+            Ok(listener)
+        });
+    let _ = std::fs::remove_dir_all(&private);
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to open control socket {}: {e}", path.display());
+            return;
+        }
+    };
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let (storage, cipher) = (storage.clone(), cipher.clone());
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut command = String::new();
+            if BufReader::new(read).read_line(&mut command).await.is_err() {
+                return;
+            }
+            let response: Vec<u8> = match command.trim() {
+                "backup" => {
+                    info!("Taking a backup for the control socket");
+                    let archive = tokio::task::spawn_blocking(move || {
+                        create_archive(storage.as_ref(), scrobbles, cipher.as_deref())
+                    })
+                    .await;
+                    match archive {
+                        Ok(Ok(archive)) => [&b"ok\n"[..], &archive].concat(),
+                        Ok(Err(e)) => format!("error: {e}\n").into_bytes(),
+                        Err(e) => format!("error: {e}\n").into_bytes(),
+                    }
+                }
+                other => format!("error: unknown command {other:?}\n").into_bytes(),
+            };
+            let _ = write.write_all(&response).await;
+        });
+    }
+}
@@ -0,0 +1,256 @@
+//! # Encryption at Rest
+//! When `[storage.encryption]` is set in `orpheus.toml`, every file the
+//! storage backends and the scrobble queue write is sealed with
+//! XChaCha20-Poly1305, so it can neither be read nor tampered with without
+//! the key. Write-ahead logs are sealed entry by entry.
+//!
+//! Sealed data starts with the magic bytes `ORPE`, followed by the id of the
+//! key it was sealed with (see [Key::id]), a random nonce and the ciphertext.
+//! What the data is, like the table and key of a record, is authenticated
+//! along with it, so sealed data can't be moved to another place either.
+//!
+//! Once a key is configured, data that isn't sealed is refused, as anyone
+//! able to write to the data directory could otherwise slip in records of
+//! their own. Encrypt the data of an existing server with `orpheus rotate-key`
+//! before configuring the key.
+
+use std::{
+    path::Path,
+    sync::{Arc, LazyLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, services};
+
+pub const MAGIC: [u8; 4] = *b"ORPE";
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+
+/// Global variable holding the cipher configured in `orpheus.toml`, if any.
+pub static CIPHER: LazyLock<Option<Arc<Cipher>>> = LazyLock::new(|| {
+    Cipher::from_config(
+        &services::Config
+            .try_read() // startup, nothing else is writing to the config yet
+            .unwrap(),
+    )
+    .expect("Failed to load encryption key!")
+});
+
+/// A 256-bit key along with its id.
+pub struct Key {
+    id: [u8; KEY_ID_LEN],
+    aead: XChaCha20Poly1305,
+}
+
+impl Key {
+    // Constructors //
+    pub fn new(bytes: [u8; 32]) -> Self {
+        let digest = Sha256::digest(bytes);
+        Self {
+            id: digest[..KEY_ID_LEN].try_into().unwrap(),
+            aead: XChaCha20Poly1305::new(&bytes.into()),
+        }
+    }
+
+    /// Parses a key written as 64 hex digits, surrounding whitespace ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut bytes = [0_u8; 32];
+        hex::decode_to_slice(text.trim(), &mut bytes)
+            .map_err(|e| anyhow!("a key must be 64 hex digits: {e}"))?;
+        Ok(Self::new(bytes))
+    }
+
+    /// Reads a key file as written by [Key::generate].
+    pub fn read(path: &Path) -> Result<Self> {
+        let text: String = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid key file {}", path.display()))
+    }
+
+    /// Writes a fresh random key to `path`, readable only by the current user,
+    /// and returns it. Refuses to replace an existing file.
+    pub fn generate(path: &Path) -> Result<Self> {
+        let bytes: [u8; 32] = XChaCha20Poly1305::generate_key(&mut OsRng).into();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("failed to create key file {}", path.display()))?;
+        std::io::Write::write_all(&mut file, format!("{}\n", hex::encode(bytes)).as_bytes())?;
+        file.sync_all()?;
+        Ok(Self::new(bytes))
+    }
+
+    /// The key configured in `[storage.encryption]`, if any.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(encryption) = config.storage().encryption() else {
+            return Ok(None);
+        };
+        let key = match (encryption.key_file(), encryption.key_env()) {
+            (Some(path), None) => Self::read(Path::new(path))?,
+            (None, Some(var)) => {
+                let text = std::env::var(var).with_context(|| {
+                    format!("environment variable {var} holding the key isn't set")
+                })?;
+                Self::parse(&text).with_context(|| format!("invalid key in {var}"))?
+            }
+            _ => bail!("[storage.encryption] needs exactly one of key_file or key_env"),
+        };
+        Ok(Some(key))
+    }
+
+    /// Short fingerprint stored next to sealed data, so the right key can be
+    /// picked while rotating and a wrong key gives a clear error.
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+/// Why sealed data couldn't be opened.
+#[derive(thiserror::Error, Debug)]
+pub enum UnsealError {
+    #[error("encrypted data is truncated")]
+    Truncated,
+    /// Holds the id of the key the data was sealed with.
+    #[error("data was encrypted with key {0}, which isn't configured")]
+    UnknownKey(String),
+    #[error("data is encrypted, but no key is configured in [storage.encryption]")]
+    NoKey,
+    #[error("encrypted data failed authentication, it's corrupt or was tampered with")]
+    Tampered,
+    #[error(
+        "data isn't encrypted, but a key is configured in [storage.encryption]; \
+         encrypt existing data with `orpheus rotate-key` first"
+    )]
+    NotSealed,
+}
+
+/// Seals data with its first key, and opens data sealed with any of its keys.
+/// Keeping the previous key around is what lets a key rotation pick up where
+/// it left off after being interrupted.
+pub struct Cipher {
+    keys: Vec<Key>,
+    /// whether data that isn't sealed at all is opened too, see [Cipher::accepting_plaintext]
+    plaintext: bool,
+}
+
+impl Cipher {
+    // Constructors //
+    pub fn new(key: Key) -> Self {
+        Self {
+            keys: vec![key],
+            plaintext: false,
+        }
+    }
+
+    /// Also accepts data sealed with `key`, without ever sealing with it.
+    pub fn with_previous(mut self, key: Key) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    /// Also accepts data that isn't sealed, so it can be sealed for the first
+    /// time. Only meant for encrypting an existing server with `orpheus
+    /// rotate-key`, see the module docs.
+    pub fn accepting_plaintext(mut self) -> Self {
+        self.plaintext = true;
+        self
+    }
+
+    /// The cipher configured in `[storage.encryption]`, if any.
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Self>>> {
+        Ok(Key::from_config(config)?.map(|key| Arc::new(Self::new(key))))
+    }
+
+    /// The id of the key data is sealed with.
+    pub fn key_id(&self) -> String {
+        self.keys[0].id()
+    }
+
+    // Methods //
+    /// Encrypts and authenticates `plaintext` with the current key, along
+    /// with `aad` describing what it is, which has to match when opening it.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let key: &Key = &self.keys[0];
+        let nonce: XNonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed: Vec<u8> = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        sealed.extend_from_slice(&MAGIC);
+        sealed.extend_from_slice(&key.id);
+        sealed.extend_from_slice(&nonce);
+        // the key id is authenticated too, so it can't be swapped
+        let aad: Vec<u8> = [&sealed[..MAGIC.len() + KEY_ID_LEN], aad].concat();
+        let payload = Payload {
+            msg: plaintext,
+            aad: &aad,
+        };
+        let ciphertext = key
+            .aead
+            .encrypt(&nonce, payload)
+            .expect("Failed to encrypt data!");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts data sealed by [Cipher::seal], returning the plaintext and
+    /// whether it was sealed with a previous key.
+    fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<(Vec<u8>, bool), UnsealError> {
+        let header: &[u8] = sealed.get(..HEADER_LEN).ok_or(UnsealError::Truncated)?;
+        let (prefix, nonce) = header.split_at(MAGIC.len() + KEY_ID_LEN);
+        let id: &[u8] = &prefix[MAGIC.len()..];
+        let aad: Vec<u8> = [prefix, aad].concat();
+        let position: usize = self
+            .keys
+            .iter()
+            .position(|key| key.id == id)
+            .ok_or_else(|| UnsealError::UnknownKey(hex::encode(id)))?;
+        let payload = Payload {
+            msg: &sealed[HEADER_LEN..],
+            aad: &aad,
+        };
+        let plaintext = self.keys[position]
+            .aead
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| UnsealError::Tampered)?;
+        Ok((plaintext, position != 0))
+    }
+}
+
+/// Whether `bytes` were sealed by a [Cipher].
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Seals `plaintext` along with `aad` if there is a cipher, passing it
+/// through otherwise.
+pub fn seal(cipher: Option<&Cipher>, aad: &[u8], plaintext: Vec<u8>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.seal(aad, &plaintext),
+        None => plaintext,
+    }
+}
+
+/// The inverse of [seal], given the same `aad`. Also returns whether the
+/// data should be sealed again, i.e. it's sealed with a previous key, or
+/// isn't sealed and the cipher is [accepting it](Cipher::accepting_plaintext).
+pub fn unseal(
+    cipher: Option<&Cipher>,
+    aad: &[u8],
+    bytes: &[u8],
+) -> Result<(Vec<u8>, bool), UnsealError> {
+    match (cipher, is_sealed(bytes)) {
+        (Some(cipher), true) => cipher.open(aad, bytes),
+        (Some(cipher), false) if cipher.plaintext => Ok((bytes.to_vec(), true)),
+        (Some(_), false) => Err(UnsealError::NotSealed),
+        (None, true) => Err(UnsealError::NoKey),
+        (None, false) => Ok((bytes.to_vec(), false)),
+    }
+}
@@ -0,0 +1,265 @@
+//! # Data Directory
+//! Everything the server writes lives under a single directory, set with
+//! `data_dir` in the `[server]` section of `orpheus.toml`:
+//!
+//! ```text
+//! <data_dir>/
+//! ├── orpheus.lock       held by the running instance, holds its PID
+//! ├── orpheus.sock       control socket of the running instance, see `backup`
+//! ├── db/                the storage backend
+//! │   ├── accounts       pot backend: one snapshot per table, each with
+//! │   ├── sessions       its `.wal` log and `.1`, `.2`, ... backups
+//! │   ├── library
+//! │   ├── user_data
+//! │   └── orpheus.sqlite SQLite backend: every table in one database
+//! ├── scrobbles          listens waiting to be forwarded
+//! ├── cache/             anything that can be rebuilt, safe to delete
+//! ├── artwork/           cover art extracted from the library
+//! └── logs/
+//! ```
+//!
+//! Before the data directory existed, data was kept next to
+//! `server.account_data_path` and, for a while, in `.orpheus/accounts-db`
+//! under the platform data directory. Both are moved into place on startup.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{LazyLock, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    service::{
+        fs,
+        storage::{pot_file, Table},
+    },
+    services,
+};
+
+/// Global variable holding the [DataDir] of this server. The first access
+/// creates the layout, takes the lock and migrates data from old paths.
+pub static DATA_DIR: LazyLock<DataDir> = LazyLock::new(|| {
+    let config = services::Config
+        .try_read() // startup, nothing else is writing to the config yet
+        .unwrap();
+    let data_dir = DataDir::from_config(&config);
+    let lock: File = data_dir
+        .prepare(&config)
+        .expect("Failed to prepare the data directory!");
+    LOCK.set(lock).unwrap();
+    data_dir
+});
+
+/// The lock on [DATA_DIR], held until the process exits.
+static LOCK: OnceLock<File> = OnceLock::new();
+
+/// The paths of the data directory layout, see the module docs.
+#[derive(Clone, Debug)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    // Constructors //
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.server().data_dir())
+    }
+
+    // Paths //
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn lock_file(&self) -> PathBuf {
+        self.root.join("orpheus.lock")
+    }
+
+    pub fn control_socket(&self) -> PathBuf {
+        self.root.join("orpheus.sock")
+    }
+
+    /// Directory of the storage backend.
+    pub fn db(&self) -> PathBuf {
+        self.root.join("db")
+    }
+
+    /// Snapshot of `table` kept by the pot file backend.
+    pub fn table(&self, table: Table) -> PathBuf {
+        pot_file::table_path(&self.db(), table)
+    }
+
+    pub fn sqlite(&self) -> PathBuf {
+        self.db().join("orpheus.sqlite")
+    }
+
+    pub fn scrobble_queue(&self) -> PathBuf {
+        self.root.join("scrobbles")
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    pub fn artwork(&self) -> PathBuf {
+        self.root.join("artwork")
+    }
+
+    pub fn logs(&self) -> PathBuf {
+        self.root.join("logs")
+    }
+
+    // Methods //
+    /// Creates the layout, locks the directory and moves in any data still at
+    /// the paths used by older versions. Returns the lock, see [DataDir::lock].
+    pub fn prepare(&self, config: &Config) -> Result<File> {
+        for dir in [self.db(), self.cache(), self.artwork(), self.logs()] {
+            fs::create_private_dir(&dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        // directories made by older versions were open to everyone
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.root, std::fs::Permissions::from_mode(0o700))
+                .with_context(|| format!("failed to restrict {}", self.root.display()))?;
+        }
+        let lock: File = self.lock()?;
+        self.migrate_legacy(config)?;
+        Ok(lock)
+    }
+
+    /// Locks the directory so no other instance can use it, failing if one
+    /// already does. The lock is released when the returned file is closed,
+    /// which the OS also does if the process dies.
+    pub fn lock(&self) -> Result<File> {
+        let path: PathBuf = self.lock_file();
+        let mut file: File = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        if let Err(e) = file.try_lock() {
+            let owner: String = std::fs::read_to_string(&path).unwrap_or_default();
+            match e {
+                std::fs::TryLockError::WouldBlock => bail!(
+                    "{} is in use by another Orpheus instance (PID {})",
+                    self.root.display(),
+                    owner.trim()
+                ),
+                std::fs::TryLockError::Error(e) => return Err(e.into()),
+            }
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(file)
+    }
+
+    /// Moves data kept at the paths of older versions into the layout. Data
+    /// already in the layout always wins, the old copy is then left alone.
+    fn migrate_legacy(&self, config: &Config) -> Result<()> {
+        if let Some(old) = config.server().account_data_path() {
+            let old = PathBuf::from(old);
+            let mut moves: Vec<(PathBuf, PathBuf)> = vec![
+                (old.with_extension("scrobbles"), self.scrobble_queue()),
+                (fs::with_suffix(&old, ".sqlite"), self.sqlite()),
+            ];
+            for table in Table::ALL {
+                let from: PathBuf = match table {
+                    Table::Accounts => old.clone(),
+                    _ => old.with_extension(table.name()),
+                };
+                moves.push((from, self.table(table)));
+            }
+            for (from, to) in moves {
+                move_with_companions(&from, &to)?;
+            }
+        }
+
+        // briefly, accounts were also written here, starting out as a single zero byte
+        if let Some(old) = dirs::data_dir().map(|dir| dir.join(".orpheus/accounts-db")) {
+            match std::fs::metadata(&old) {
+                Ok(meta) if meta.len() <= 1 => std::fs::remove_file(&old)?,
+                Ok(_) => move_with_companions(&old, &self.table(Table::Accounts))?,
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves `from` to `to` along with the files kept next to it: write-ahead
+/// logs, backups and SQLite journals. Nothing happens if `from` doesn't exist,
+/// or `to` already does.
+fn move_with_companions(from: &Path, to: &Path) -> Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+    if to.exists() {
+        warn!(
+            "Not migrating {} as {} already exists",
+            from.display(),
+            to.display()
+        );
+        return Ok(());
+    }
+    let Some(name) = from.file_name().and_then(|name| name.to_str()) else {
+        bail!("{} isn't a valid file name", from.display());
+    };
+    let Some(dir) = from.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return move_file(from, to);
+    };
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        // `accounts` matches `accounts.wal` and `accounts.1`, but not `accounts.sessions`
+        let companion = file_name
+            .strip_prefix(name)
+            .is_some_and(is_companion_suffix);
+        if companion {
+            let target = fs::with_suffix(to, &file_name[name.len()..]);
+            move_file(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_companion_suffix(suffix: &str) -> bool {
+    match suffix {
+        "" | ".wal" | ".corrupt" | "-wal" | "-shm" | "-journal" => true,
+        _ => suffix
+            .strip_prefix('.')
+            .is_some_and(|generation| generation.parse::<usize>().is_ok()),
+    }
+}
+
+/// Renames `from` to `to`, copying when they're on different file systems.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    info!("Migrating {} to {}", from.display(), to.display());
+    if let Some(parent) = to.parent() {
+        fs::create_private_dir(parent)?;
+    }
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
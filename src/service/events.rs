@@ -0,0 +1,234 @@
+//! # Events
+//! Changes clients would otherwise have to poll for, published as they
+//! happen and pushed to the subscribers of the `/ws` endpoint. Every event
+//! belongs to a [Topic] and is either for everyone or for a single user.
+//!
+//! The most recent [HISTORY] events are kept so a client that lost its
+//! connection can resume from the last event it saw. Ids keep growing
+//! across restarts, as they start from the time the server started, but the
+//! history doesn't survive one: a client asking for events the server no
+//! longer has is told to fetch everything again instead.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
+
+/// Global variable holding the singleton instance of [EventBus].
+pub static EVENTS: LazyLock<EventBus> = LazyLock::new(EventBus::new);
+
+/// How many of the latest events are kept for clients resuming.
+pub const HISTORY: usize = 1024;
+
+/// What an event is about, clients subscribe to the topics they care about.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Library,
+    Playlists,
+    Queue,
+    Notices,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 4] = [
+        Topic::Library,
+        Topic::Playlists,
+        Topic::Queue,
+        Topic::Notices,
+    ];
+
+    /// The name of the topic on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            Topic::Library => "library",
+            Topic::Playlists => "playlists",
+            Topic::Queue => "queue",
+            Topic::Notices => "notices",
+        }
+    }
+}
+
+impl std::str::FromStr for Topic {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Topic::ALL
+            .into_iter()
+            .find(|topic| topic.name() == name)
+            .ok_or_else(|| format!("unknown topic `{name}`"))
+    }
+}
+
+/// What happened, tagged with `type` on the wire.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// a scan found new tracks, changed ones or lost some, by track id
+    LibraryChanged {
+        added: Vec<String>,
+        updated: Vec<String>,
+        removed: Vec<String>,
+    },
+    /// a playlist was made or changed, by playlist id
+    PlaylistChanged {
+        playlist: String,
+        owner: String,
+    },
+    PlaylistDeleted {
+        playlist: String,
+        owner: String,
+    },
+    /// the play queue of the user was saved, by the client named `client`
+    QueueChanged {
+        client: Option<String>,
+    },
+    /// a message from the server or an admin, like a restart coming up
+    Notice {
+        message: String,
+    },
+}
+
+impl EventKind {
+    pub fn topic(&self) -> Topic {
+        match self {
+            EventKind::LibraryChanged { .. } => Topic::Library,
+            EventKind::PlaylistChanged { .. } | EventKind::PlaylistDeleted { .. } => {
+                Topic::Playlists
+            }
+            EventKind::QueueChanged { .. } => Topic::Queue,
+            EventKind::Notice { .. } => Topic::Notices,
+        }
+    }
+}
+
+/// A published [EventKind].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub id: u64,
+    /// unix timestamp of when it was published
+    pub time: i64,
+    pub topic: Topic,
+    #[serde(flatten)]
+    pub kind: EventKind,
+    /// the only user it's for, everyone if unset
+    #[serde(skip)]
+    pub audience: Option<String>,
+}
+
+impl Event {
+    /// Whether `username` gets to see this event.
+    pub fn is_for(&self, username: &str) -> bool {
+        self.audience
+            .as_deref()
+            .is_none_or(|audience| audience == username)
+    }
+}
+
+/// Where subscribing left off, see [EventBus::subscribe].
+pub struct Subscription {
+    /// the events after the one asked for, or `None` if some of them are
+    /// no longer kept
+    pub missed: Option<Vec<Arc<Event>>>,
+    /// every event published from now on
+    pub receiver: broadcast::Receiver<Arc<Event>>,
+}
+
+/// Hands published events to every subscriber and keeps the latest ones,
+/// see the module docs.
+pub struct EventBus {
+    /// the id of the next event and the latest [HISTORY] events, oldest first
+    history: Mutex<(u64, VecDeque<Arc<Event>>)>,
+    sender: broadcast::Sender<Arc<Event>>,
+    /// set once the server is shutting down
+    closing: watch::Sender<bool>,
+}
+
+impl EventBus {
+    // Constructor //
+    pub fn new() -> Self {
+        let first_id: u64 = Utc::now().timestamp_micros().max(1) as u64;
+        Self {
+            history: Mutex::new((first_id, VecDeque::with_capacity(HISTORY))),
+            sender: broadcast::channel(HISTORY).0,
+            closing: watch::Sender::new(false),
+        }
+    }
+
+    // Methods //
+    /// Publishes `kind` to everyone, or only to `audience` if it's set, and
+    /// returns the id of the event.
+    pub fn publish(&self, audience: Option<&str>, kind: EventKind) -> u64 {
+        let mut history = self.history.lock().unwrap();
+        let (next_id, events) = &mut *history;
+        let id: u64 = *next_id;
+        let event = Arc::new(Event {
+            id,
+            time: Utc::now().timestamp(),
+            topic: kind.topic(),
+            kind,
+            audience: audience.map(str::to_owned),
+        });
+        *next_id += 1;
+        if events.len() == HISTORY {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+        // sent while holding the lock, so subscribers see events in the same
+        // order as the history. it fails only if nobody is listening
+        let _ = self.sender.send(event);
+        id
+    }
+
+    /// Subscribes to every event published from now on, along with those
+    /// published after the event with id `since` if it's set.
+    pub fn subscribe(&self, since: Option<u64>) -> Subscription {
+        let history = self.history.lock().unwrap();
+        let (next_id, events) = &*history;
+        let missed = match since {
+            None => Some(Vec::new()),
+            // the history reaches back to the event right after `since`
+            Some(since) => {
+                let oldest: u64 = events.front().map_or(*next_id, |event| event.id);
+                (oldest <= since.saturating_add(1) && since < *next_id).then(|| {
+                    events
+                        .iter()
+                        .filter(|event| event.id > since)
+                        .cloned()
+                        .collect()
+                })
+            }
+        };
+        Subscription {
+            missed,
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    /// Tells everyone the server is going away and has subscribers wrap up,
+    /// see [EventBus::closed].
+    pub fn shut_down(&self) {
+        self.publish(
+            None,
+            EventKind::Notice {
+                message: "The server is shutting down".to_owned(),
+            },
+        );
+        self.closing.send_replace(true);
+    }
+
+    /// Resolves once the server is shutting down.
+    pub async fn closed(&self) {
+        let _ = self.closing.subscribe().wait_for(|closing| *closing).await;
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
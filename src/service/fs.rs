@@ -2,32 +2,229 @@
 //! the in-memory DB store
 
 use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Write},
     path::{Path, PathBuf},
-    sync::LazyLock,
+    sync::Arc,
 };
 
-use serde::Serialize;
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
 
-// struct DbFileManager {
-//     file: File,
-// }
+use crate::service::{
+    crypto::{self, Cipher},
+    schema::{self, FileKind},
+};
+
+/// Returns `path` with `suffix` appended to its file name, e.g. `accounts`
+/// and `.wal` make `accounts.wal`. Unlike [Path::with_extension] this never
+/// replaces anything already in the name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Returns the path of the `generation`th backup of `path`, i.e. `path.1`
+/// for the newest backup, `path.2` for the one before, and so on.
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    with_suffix(path, &format!(".{generation}"))
+}
+
+/// Creates `path` and any missing parents, accessible only by the current
+/// user. Everything under the data directory is created this way, as it holds
+/// password hashes, tokens and the like.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(path)
+}
+
+/// Options for writing a file that only the current user may read, the
+/// caller picks how it's created.
+pub fn private_options() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+/// Replaces the file at `path` with `contents` in a way that can't leave a
+/// half-written file behind, even on a crash or power loss:
+/// 1. the data is written to a temporary file next to `path` and fsynced,
+/// 2. up to `backups` previous generations are rotated (see [backup_path]),
+///    with the current file becoming backup 1,
+/// 3. the temporary file is atomically renamed over `path`.
+///
+/// The file is only readable by the current user, see [private_options].
+pub fn atomic_write(path: &Path, contents: &[u8], backups: usize) -> io::Result<()> {
+    let tmp_path: PathBuf = with_suffix(path, ".tmp");
+
+    let _ = std::fs::remove_file(&tmp_path); // left over from a crash, possibly readable by others
+    let mut tmp: File = private_options().create_new(true).open(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?; // make sure the data is on disk before it becomes visible
+    drop(tmp);
+
+    if backups > 0 && path.exists() {
+        for generation in (1..backups).rev() {
+            let older = backup_path(path, generation);
+            if older.exists() {
+                std::fs::rename(&older, backup_path(path, generation + 1))?;
+            }
+        }
+        // hard link rather than rename so `path` never stops existing
+        let newest = backup_path(path, 1);
+        let _ = std::fs::remove_file(&newest); // only still there when keeping a single backup
+        if std::fs::hard_link(path, &newest).is_err() {
+            std::fs::copy(path, &newest)?;
+        }
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Renames only become durable once the directory holding them is synced.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_: &Path) -> io::Result<()> {
+    Ok(()) // directories can't be opened as files here, rename is durable enough
+}
+
+/// An append-only log of pot-encoded entries, used to persist changes to a
+/// store as they happen instead of rewriting the whole store on every change.
+/// The store is expected to periodically write a full snapshot of itself and
+/// then [truncate](WriteAheadLog::truncate) the log.
+///
+/// On disk, the log starts with the usual versioned header (see [schema]),
+/// followed by entries each made of a little-endian `u32` length and that
+/// many bytes of pot data, each sealed on its own if there's a [Cipher].
+pub struct WriteAheadLog {
+    file: File,
+    cipher: Option<Arc<Cipher>>,
+    /// what entries are sealed as, see [FileKind::aad]
+    aad: Vec<u8>,
+    entries: usize,
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log at `path` and returns it along with every
+    /// entry already in it, oldest first. An incomplete entry at the end, as
+    /// left by a crash mid-append, is cut off. A complete entry that can't be
+    /// read is an error rather than a crash though, so the log is left as it
+    /// is instead of losing everything after it. Logs written by older
+    /// versions are migrated entry by entry and rewritten in the current
+    /// version, as are entries that aren't sealed with the current key of
+    /// `cipher`.
+    pub fn open<T: DeserializeOwned>(
+        kind: FileKind,
+        path: &Path,
+        cipher: Option<Arc<Cipher>>,
+    ) -> anyhow::Result<(Self, Vec<T>)> {
+        let contents: Vec<u8> = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (version, body) = match contents.is_empty() {
+            true => (kind.current_version(), &contents[..]),
+            false => schema::split_header(&contents),
+        };
+
+        let aad: Vec<u8> = kind.aad("");
+        let mut entries: Vec<T> = Vec::new();
+        let mut upgraded: Vec<u8> = kind.header().to_vec();
+        let mut reseal: bool = false;
+        let mut offset: usize = 0;
+        while let Some(header) = body.get(offset..offset + 4) {
+            let len = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+            let Some(frame) = body.get(offset + 4..offset + 4 + len) else {
+                break; // torn, only ever the last frame as appends are a single write
+            };
+            let corrupt = |e: &dyn std::fmt::Display| {
+                anyhow!(
+                    "entry {} of {} is corrupt: {e}",
+                    entries.len() + 1,
+                    path.display()
+                )
+            };
+            let (payload, stale) =
+                crypto::unseal(cipher.as_deref(), &aad, frame).map_err(|e| corrupt(&e))?;
+            reseal |= stale;
+            let payload: Vec<u8> = kind.upgrade(version, payload)?;
+            let entry: T = pot::from_slice(&payload).map_err(|e| corrupt(&e))?;
+            entries.push(entry);
+            let sealed: Vec<u8> = crypto::seal(cipher.as_deref(), &aad, payload);
+            upgraded.extend_from_slice(&frame_entry(&sealed)?);
+            offset += 4 + len;
+        }
 
-static DB_FILE_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
-    let mut path: PathBuf = dirs::data_dir().expect("Unsupported operating system!");
-    path.push(".orpheus/accounts-db");
-    if !path.exists() {
-        std::fs::write(&path, const { [0_u8] }).expect("Failed to write to account db file!");
+        if offset < body.len() {
+            warn!(
+                "Discarding {} bytes of incomplete entries at the end of {}",
+                body.len() - offset,
+                path.display()
+            );
+        }
+        if contents.is_empty() || version != kind.current_version() || offset < body.len() || reseal
+        {
+            atomic_write(path, &upgraded, 0)?;
+        }
+
+        let log = Self {
+            file: OpenOptions::new().append(true).open(path)?,
+            cipher,
+            aad,
+            entries: entries.len(),
+        };
+        Ok((log, entries))
+    }
+
+    /// Appends `entry` to the log, only returning once it's been synced to disk.
+    pub fn append(&mut self, entry: &impl Serialize) -> anyhow::Result<()> {
+        let sealed: Vec<u8> = crypto::seal(self.cipher.as_deref(), &self.aad, pot::to_vec(entry)?);
+        let frame: Vec<u8> = frame_entry(&sealed)?;
+        self.file.write_all(&frame)?; // single write so a torn frame can only be the last one
+        self.file.sync_data()?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Empties the log, to be called once its entries are part of a snapshot.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(schema::HEADER_LEN as u64)?; // keep the header
+        self.file.sync_all()?;
+        self.entries = 0;
+        Ok(())
+    }
+
+    /// Number of entries appended since the log was last truncated.
+    pub fn len(&self) -> usize {
+        self.entries
     }
-    path
-});
 
-pub fn get_accdb_file() -> &'static Path {
-    DB_FILE_PATH.as_ref()
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
 }
 
-pub fn write_accdb(buf: &impl Serialize) -> anyhow::Result<()> {
-    let encoded: Vec<u8> = pot::to_vec(buf)?;
-    let path: &'static Path = DB_FILE_PATH.as_ref();
-    std::fs::write(path, &encoded)?;
-    Ok(())
+/// Prefixes an encoded log entry with its length.
+fn frame_entry(encoded: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut frame: Vec<u8> = Vec::with_capacity(4 + encoded.len());
+    frame.extend_from_slice(&u32::try_from(encoded.len())?.to_le_bytes());
+    frame.extend_from_slice(encoded);
+    Ok(frame)
 }
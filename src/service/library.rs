@@ -0,0 +1,359 @@
+//! # Music Library
+//! The index of every track found under the library roots set in the
+//! `[library]` section of the config, kept in the library table of the
+//! storage backend so it survives restarts.
+//!
+//! Scans are incremental: only files whose size or modification time
+//! changed since the last scan have their tags read again. The library is
+//! scanned on startup, then every `scan_interval_minutes`, and, with
+//! `watch` enabled, shortly after files under the roots change. Changing the
+//! `[library]` settings while the server runs triggers a scan as well.
+//!
+//! Tracks under a root that's missing, like a drive that isn't mounted, are
+//! kept until the root is either back or removed from the config.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use papaya::HashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::Notify,
+    time::{sleep, sleep_until, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::LibraryConfig,
+    service::{
+        events,
+        scanner::{self, FileFilter},
+        storage::{StorageBackend, Table},
+    },
+    services,
+};
+
+/// How long the roots have to be left alone after a change before it's
+/// scanned, so copying an album in is picked up by a single scan.
+pub const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+/// Global variable holding the singleton instance of [LibraryManager].
+pub static LIBRARY: LazyLock<LibraryManager> =
+    LazyLock::new(|| LibraryManager::from_storage(services::StorageService.clone()));
+
+/// A single audio file in the library, keyed by [track_id].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub id: String,
+    /// the root the file was found in
+    pub root: PathBuf,
+    pub path: PathBuf,
+    /// from the tags, or the file name if there are none
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub duration_secs: Option<u32>,
+    /// size in bytes, used to tell whether the file changed
+    pub size: u64,
+    /// unix timestamp of the last modification, used like `size`
+    pub modified: i64,
+}
+
+impl Track {
+    /// Reads the tags of the file at `path`.
+    fn read(root: PathBuf, path: PathBuf, size: u64, modified: i64) -> Self {
+        let tags = scanner::parse_local_id3(&path);
+        let tags = tags.as_ref();
+        let file_name: String = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Self {
+            id: track_id(&path),
+            title: tags
+                .and_then(|tags| tags.title.clone())
+                .unwrap_or(file_name),
+            artist: tags.and_then(|tags| tags.artist.clone()),
+            album: tags.and_then(|tags| tags.album.clone()),
+            album_artist: tags.and_then(|tags| tags.album_artist.clone()),
+            track_number: tags.and_then(|tags| tags.track),
+            disc_number: tags.and_then(|tags| tags.disc),
+            year: tags.and_then(|tags| tags.date.map(|date| date.year)),
+            genre: tags.and_then(|tags| tags.genre.clone()),
+            duration_secs: tags.and_then(|tags| tags.duration.map(|ms| ms / 1000)),
+            root,
+            path,
+            size,
+            modified,
+        }
+    }
+
+    /// The name the track is credited to as part of an album.
+    pub fn album_artist_or_artist(&self) -> Option<&str> {
+        self.album_artist.as_deref().or(self.artist.as_deref())
+    }
+}
+
+/// A stable id for the file at `path`, so it keeps its id across scans.
+pub fn track_id(path: &Path) -> String {
+    let digest = Sha256::digest(path.to_string_lossy().as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// What a scan changed in the library.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// Keeps the library index in memory and in the storage backend, and keeps
+/// it in sync with the files under the roots, see the module docs.
+pub struct LibraryManager {
+    storage: Arc<dyn StorageBackend>,
+    tracks: HashMap<String, Arc<Track>>,
+    /// notified whenever a scan should happen soon, see [LibraryManager::request_scan]
+    rescan: Notify,
+    /// scans are serialized, the index is only ever updated by one at a time
+    scanning: Mutex<()>,
+    /// whether [LibraryManager::run] finished its first scan
+    scanned: AtomicBool,
+}
+
+impl LibraryManager {
+    // Constructor //
+    /// Loads the index kept in `storage`.
+    pub fn from_storage(storage: Arc<dyn StorageBackend>) -> Self {
+        let tracks: HashMap<String, Arc<Track>> = HashMap::new();
+        let stored: Vec<(String, Track)> = storage
+            .load_records(Table::Library)
+            .expect("Failed to load library!");
+        for (id, track) in stored {
+            tracks.pin().insert(id, Arc::new(track));
+        }
+        Self {
+            storage,
+            tracks,
+            rescan: Notify::new(),
+            scanning: Mutex::new(()),
+            scanned: AtomicBool::new(false),
+        }
+    }
+
+    // Methods //
+    pub fn get(&self, id: &str) -> Option<Arc<Track>> {
+        self.tracks.pin().get(id).cloned()
+    }
+
+    /// Every track in the library, in no particular order.
+    pub fn tracks(&self) -> Vec<Arc<Track>> {
+        self.tracks.pin().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Whether the scan on startup is done, successful or not. Until then
+    /// the index may be missing files added while the server was down.
+    pub fn has_scanned(&self) -> bool {
+        self.scanned.load(Ordering::Acquire)
+    }
+
+    /// Makes [LibraryManager::run] scan again soon.
+    pub fn request_scan(&self) {
+        self.rescan.notify_one();
+    }
+
+    /// Brings the index in line with the files under the roots in `config`,
+    /// only reading the tags of new and changed files. Blocks on file IO.
+    pub fn scan(&self, config: &LibraryConfig) -> Result<ScanReport> {
+        let _scanning = self.scanning.lock().unwrap();
+        let filter = FileFilter::new(config)?;
+        let mut report = ScanReport::default();
+        let (mut added, mut updated): (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
+        let mut found: HashSet<String> = HashSet::new();
+        // an unmounted drive shouldn't empty the library, keep what was on it
+        let unavailable: Vec<PathBuf> = config
+            .roots()
+            .iter()
+            .map(PathBuf::from)
+            .filter(|root| !root.is_dir())
+            .collect();
+        for root in &unavailable {
+            warn!(
+                "Library root {} isn't available, skipping it",
+                root.display()
+            );
+        }
+
+        for (root, path) in scanner::find_files(config, &filter) {
+            let id: String = track_id(&path);
+            let meta = match std::fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    warn!("Skipping {}: {e}", path.display());
+                    continue;
+                }
+            };
+            let modified: i64 = meta
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs() as i64);
+            found.insert(id.clone());
+
+            let existing = self.get(&id);
+            if existing.as_ref().is_some_and(|track| {
+                track.size == meta.len() && track.modified == modified && track.root == root
+            }) {
+                report.unchanged += 1;
+                continue;
+            }
+            debug!("reading tags of {}", path.display());
+            let track = Track::read(root, path, meta.len(), modified);
+            self.storage.put_record(Table::Library, &id, &track)?;
+            self.tracks.pin().insert(id.clone(), Arc::new(track));
+            match existing {
+                Some(_) => updated.push(id),
+                None => added.push(id),
+            }
+        }
+
+        let gone: Vec<String> = self
+            .tracks
+            .pin()
+            .iter()
+            .filter(|(id, track)| !found.contains(*id) && !unavailable.contains(&track.root))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &gone {
+            self.storage.delete(Table::Library, id)?;
+            self.tracks.pin().remove(id);
+        }
+
+        (report.added, report.updated, report.removed) = (added.len(), updated.len(), gone.len());
+        if !(added.is_empty() && updated.is_empty() && gone.is_empty()) {
+            services::EventService.publish(
+                None,
+                events::EventKind::LibraryChanged {
+                    added,
+                    updated,
+                    removed: gone,
+                },
+            );
+        }
+        Ok(report)
+    }
+
+    /// Scans the library on startup and whenever it's due again, see the
+    /// module docs. Meant to be spawned once as a background task.
+    pub async fn run(&'static self) {
+        // what the watcher was set up for, it's rebuilt when that changes
+        let mut watching: Option<(LibraryConfig, RecommendedWatcher)> = None;
+        loop {
+            let config: LibraryConfig = services::Config.read().await.library().clone();
+            let outdated = watching
+                .as_ref()
+                .is_none_or(|(watched, _)| !same_watch(watched, &config));
+            if outdated {
+                watching = self.watch(&config).map(|watcher| (config.clone(), watcher));
+            }
+
+            let scan_config = config.clone();
+            let started = Instant::now();
+            match tokio::task::spawn_blocking(move || self.scan(&scan_config)).await {
+                Ok(Ok(report)) => {
+                    services::MetricsService.record_scan(started.elapsed());
+                    info!(
+                        "Scanned library: {} added, {} updated, {} removed, {} unchanged",
+                        report.added, report.updated, report.removed, report.unchanged
+                    )
+                }
+                Ok(Err(e)) => error!("Failed to scan library: {e:#}"),
+                Err(e) => error!("Scanning library panicked: {e}"),
+            }
+            self.scanned.store(true, Ordering::Release);
+
+            let next_scan = config
+                .scan_interval()
+                .map(|interval| Instant::now() + interval);
+            tokio::select! {
+                _ = async {
+                    match next_scan {
+                        Some(deadline) => sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+                _ = self.rescan.notified() => {
+                    // wait for things to settle down before scanning
+                    loop {
+                        tokio::select! {
+                            _ = sleep(QUIET_PERIOD) => break,
+                            _ = self.rescan.notified() => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Watches the roots in `config` for changes if it asks for it, requesting
+    /// a scan on every change.
+    fn watch(&'static self, config: &LibraryConfig) -> Option<RecommendedWatcher> {
+        if !config.watch() || config.roots().is_empty() {
+            return None;
+        }
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            // reading tags while scanning mustn't set off another scan
+            let relevant = event.is_ok_and(|event| match event.kind {
+                EventKind::Create(_) | EventKind::Remove(_) => true,
+                EventKind::Modify(notify::event::ModifyKind::Metadata(_)) => false,
+                EventKind::Modify(_) => true,
+                _ => false,
+            });
+            if relevant {
+                self.request_scan();
+            }
+        });
+        let mut watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!("Failed to watch library: {e}");
+                return None;
+            }
+        };
+        for root in config.roots() {
+            if let Err(e) = watcher.watch(Path::new(root), RecursiveMode::Recursive) {
+                error!("Failed to watch {root}: {e}");
+            }
+        }
+        Some(watcher)
+    }
+}
+
+/// Whether a watcher set up for `a` also does for `b`.
+fn same_watch(a: &LibraryConfig, b: &LibraryConfig) -> bool {
+    a.watch() == b.watch() && a.roots() == b.roots() && a.follow_symlinks() == b.follow_symlinks()
+}
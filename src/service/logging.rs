@@ -0,0 +1,119 @@
+//! # Logging
+//! Sets up `tracing` for the binary, following the `[logging]` section of
+//! the config: the level plus per-module directives, human-readable or JSON
+//! lines, and optionally a copy of everything in rotating files under
+//! `<data_dir>/logs`. All of it can change while the server runs, see
+//! [apply] and the `reload` module.
+
+use std::{
+    io::IsTerminal,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tracing_appender::{non_blocking::WorkerGuard, rolling::RollingFileAppender};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{self, MakeWriter},
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::config::{LogFormat, LoggingConfig};
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type Output = Box<dyn Layer<Filtered> + Send + Sync>;
+
+/// Swap out the parts of the subscriber set up by [init].
+struct Handles {
+    filter: reload::Handle<EnvFilter, Registry>,
+    output: reload::Handle<Output, Filtered>,
+}
+
+static HANDLES: OnceLock<Handles> = OnceLock::new();
+
+/// Keeps the thread writing the log file alive, dropping it flushes the file.
+static FILE_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+
+/// Installs the global subscriber, logging everything up to `level` until
+/// the config is applied. Until then logs go to stderr, as sub-commands
+/// like `account export` write their output to stdout.
+pub fn init(level: LevelFilter) {
+    let (filter, filter_handle) = reload::Layer::new(
+        EnvFilter::builder()
+            .with_default_directive(level.into())
+            .parse_lossy(""),
+    );
+    let (output, output_handle) = reload::Layer::new(format_layer(
+        LogFormat::Pretty,
+        std::io::stderr,
+        std::io::stderr().is_terminal(),
+    ));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .init();
+    let _ = HANDLES.set(Handles {
+        filter: filter_handle,
+        output: output_handle,
+    });
+}
+
+/// Logs as `config` says from now on, writing log files to `logs` if it asks
+/// for them.
+pub fn apply(config: &LoggingConfig, logs: &Path) -> Result<()> {
+    let handles = HANDLES
+        .get()
+        .ok_or_else(|| anyhow!("logging isn't set up"))?;
+    let mut outputs: Vec<Output> = vec![format_layer(config.format(), std::io::stdout, true)];
+    let mut guard: Option<WorkerGuard> = None;
+    if config.file() {
+        let mut appender = RollingFileAppender::builder()
+            .rotation(config.rotation().into())
+            .filename_prefix("orpheus")
+            .filename_suffix("log");
+        if config.max_files() > 0 {
+            appender = appender.max_log_files(config.max_files());
+        }
+        let appender = appender
+            .build(logs)
+            .with_context(|| format!("failed to open log file in {}", logs.display()))?;
+        let (writer, file_guard) = tracing_appender::non_blocking(appender);
+        outputs.push(format_layer(config.format(), writer, false));
+        guard = Some(file_guard);
+    }
+
+    handles.filter.reload(filter(config)?)?;
+    handles.output.reload(Box::new(outputs) as Output)?;
+    // only now that nothing writes to it anymore, flush the previous file
+    *FILE_GUARD.lock().unwrap() = guard;
+    Ok(())
+}
+
+/// The filter `config` describes: its level, refined by its directives.
+pub fn filter(config: &LoggingConfig) -> Result<EnvFilter> {
+    Ok(EnvFilter::builder()
+        .with_default_directive(config.level().into())
+        .parse(config.directives().join(","))?)
+}
+
+/// Writes out whatever is still buffered for the log file, to be called
+/// before exiting.
+pub fn flush() {
+    FILE_GUARD.lock().unwrap().take();
+}
+
+/// Formats events as `format` and writes them to `writer`, colored if `ansi`
+/// is set and the format has colors.
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> Output
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
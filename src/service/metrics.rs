@@ -0,0 +1,227 @@
+//! # Metrics
+//! Counters and histograms of what the server has been doing since it
+//! started, served in the Prometheus text format at `/metrics`. Gauges of
+//! the current state, like the number of accounts, are read from the other
+//! services when scraped instead, see [Metrics::render].
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+/// Global variable holding the singleton instance of [Metrics].
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Upper bounds of the request latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Upper bounds of the scan duration buckets, in seconds.
+const SCAN_BUCKETS: [f64; 7] = [0.1, 1.0, 10.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// A point-in-time value, for [Metrics::render].
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: u64,
+}
+
+/// Observations sorted into buckets by upper bound.
+struct Histogram {
+    bounds: &'static [f64],
+    /// observations per bucket, not cumulative, with the last one for
+    /// those above every bound
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket: usize = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Writes the buckets, sum and count of `name`, each with `labels`.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator: &str = if labels.is_empty() { "" } else { "," };
+        let mut cumulative: u64 = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let total: u64 = cumulative + self.counts[self.bounds.len()];
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {total}"
+        );
+        let labels: String = match labels {
+            "" => String::new(),
+            labels => format!("{{{labels}}}"),
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {total}");
+    }
+}
+
+/// What was seen of requests to a single route.
+struct RouteStats {
+    /// responses by status code
+    statuses: BTreeMap<u16, u64>,
+    latency: Histogram,
+}
+
+/// Everything counted since the server started, see the module docs.
+pub struct Metrics {
+    /// by method and route, the path pattern rather than the path so ids
+    /// don't make up a new series each
+    requests: Mutex<BTreeMap<(String, String), RouteStats>>,
+    scans: Mutex<Histogram>,
+    stream_bytes: AtomicU64,
+    autosave_failures: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            scans: Mutex::new(Histogram::new(&SCAN_BUCKETS)),
+            stream_bytes: AtomicU64::new(0),
+            autosave_failures: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    // Methods //
+    /// Counts a request to `route` answered with `status` after `elapsed`.
+    pub fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests
+            .entry((method.to_owned(), route.to_owned()))
+            .or_insert_with(|| RouteStats {
+                statuses: BTreeMap::new(),
+                latency: Histogram::new(&LATENCY_BUCKETS),
+            });
+        *stats.statuses.entry(status).or_default() += 1;
+        stats.latency.observe(elapsed.as_secs_f64());
+    }
+
+    /// Records how long a library scan took.
+    pub fn record_scan(&self, elapsed: Duration) {
+        self.scans.lock().unwrap().observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts `bytes` of audio sent to clients.
+    pub fn add_stream_bytes(&self, bytes: u64) {
+        self.stream_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a failed background save of the storage backend.
+    pub fn autosave_failed(&self) {
+        self.autosave_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything counted along with `gauges`, in the Prometheus text format.
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut out = String::new();
+        let requests = self.requests.lock().unwrap();
+        header(
+            &mut out,
+            "orpheus_http_requests_total",
+            "counter",
+            "HTTP requests answered, by route and status",
+        );
+        for ((method, route), stats) in requests.iter() {
+            for (status, count) in &stats.statuses {
+                let _ = writeln!(
+                    out,
+                    "orpheus_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}",
+                    escape(method),
+                    escape(route)
+                );
+            }
+        }
+        header(
+            &mut out,
+            "orpheus_http_request_duration_seconds",
+            "histogram",
+            "Time taken to answer HTTP requests, by route",
+        );
+        for ((method, route), stats) in requests.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            stats
+                .latency
+                .write(&mut out, "orpheus_http_request_duration_seconds", &labels);
+        }
+        drop(requests);
+
+        header(
+            &mut out,
+            "orpheus_library_scan_duration_seconds",
+            "histogram",
+            "Time taken by library scans",
+        );
+        self.scans
+            .lock()
+            .unwrap()
+            .write(&mut out, "orpheus_library_scan_duration_seconds", "");
+        header(
+            &mut out,
+            "orpheus_stream_bytes_total",
+            "counter",
+            "Bytes of audio sent to clients",
+        );
+        let _ = writeln!(
+            out,
+            "orpheus_stream_bytes_total {}",
+            self.stream_bytes.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "orpheus_autosave_failures_total",
+            "counter",
+            "Background saves of the storage backend that failed",
+        );
+        let _ = writeln!(
+            out,
+            "orpheus_autosave_failures_total {}",
+            self.autosave_failures.load(Ordering::Relaxed)
+        );
+
+        for gauge in gauges {
+            header(&mut out, gauge.name, "gauge", gauge.help);
+            let _ = writeln!(out, "{} {}", gauge.name, gauge.value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,80 @@
+//! # Background Persistence
+//! Every change is durable in the storage backend as soon as it's made, but
+//! some backends batch expensive housekeeping, like the pot file backend
+//! compacting its write-ahead logs. That work is done here, by a task woken
+//! whenever the backend reports a change, and once more on shutdown.
+//!
+//! Changes are debounced: a flush waits until no change has been made for
+//! [QUIET_PERIOD], so a burst of changes costs a single flush, but never
+//! longer than [MAX_DELAY] after the first change of a burst.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, error, info};
+
+use crate::{service::storage::StorageBackend, services::MetricsService};
+
+pub const QUIET_PERIOD: Duration = Duration::from_secs(5);
+pub const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Flushes `storage` after every burst of changes, meant to be spawned once
+/// as a background task. Returns right away for backends that never need it.
+pub async fn run(storage: Arc<dyn StorageBackend>) {
+    let Some(changes) = storage.changes() else {
+        return;
+    };
+    loop {
+        changes.notified().await;
+        let deadline = Instant::now() + MAX_DELAY;
+        loop {
+            let quiet_until = (Instant::now() + QUIET_PERIOD).min(deadline);
+            tokio::select! {
+                biased; // once the deadline is up, don't let a stream of changes hold it off
+                _ = sleep_until(quiet_until) => break,
+                _ = changes.notified() => {}
+            }
+        }
+        debug!("storage is dirty, flushing...");
+        let storage = storage.clone();
+        // flushing means blocking file IO, keep it off the async workers
+        if let Err(e) = tokio::task::spawn_blocking(move || flush(storage.as_ref())).await {
+            error!("Flushing storage panicked: {e}");
+            MetricsService.autosave_failed();
+        }
+    }
+}
+
+/// Flushes `storage` if it has anything to flush, logging any failure.
+pub fn flush(storage: &dyn StorageBackend) {
+    if !storage.is_dirty() {
+        return;
+    }
+    if let Err(e) = storage.flush() {
+        error!("Failed to flush storage: {e}");
+        MetricsService.autosave_failed();
+    }
+}
+
+/// Resolves once the process is asked to stop, by Ctrl+C (SIGINT) or SIGTERM.
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C!");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM!")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("Received Ctrl+C, shutting down..."),
+        _ = terminate => info!("Received SIGTERM, shutting down..."),
+    }
+}
@@ -0,0 +1,135 @@
+//! # Config Hot-Reload
+//! While the server runs, `orpheus.toml` is re-read whenever it changes on
+//! disk, or when the process receives SIGHUP. The new config is validated
+//! first and ignored entirely if there's anything wrong with it.
+//!
+//! Only some settings can change without a restart, see
+//! [Config::apply_live]: logging, session expiry, rate limit, CORS origins
+//! and library. Those are read from [services::Config] whenever they're
+//! used, except for logging, which is applied here, and the library, which
+//! is rescanned. Changes to any other setting are logged along with a
+//! reminder to restart.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{self, Config},
+    service::logging,
+    services,
+};
+
+/// How long the config file has to stay unchanged before it's reloaded, as
+/// editors tend to save in several steps.
+pub const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// Reloads the config whenever it changes or SIGHUP is received, meant to be
+/// spawned once as a background task.
+pub async fn run() {
+    let path = config::path();
+    let changed: Arc<Notify> = Arc::default();
+    let _watcher: Option<RecommendedWatcher> = watch(&path, changed.clone());
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to listen for SIGHUP!");
+    loop {
+        #[cfg(unix)]
+        let hangup = hangup.recv();
+        #[cfg(not(unix))]
+        let hangup = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = changed.notified() => {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(QUIET_PERIOD) => break,
+                        _ = changed.notified() => {}
+                    }
+                }
+                info!("{} changed, reloading...", path.display());
+            }
+            _ = hangup => info!("Received SIGHUP, reloading {}...", path.display()),
+        }
+        reload(&path).await;
+    }
+}
+
+/// Watches the directory holding the config file at `path`, rather than the
+/// file itself, so saves that replace the file by renaming another over it
+/// are seen too. `changed` is notified on every change to the file.
+fn watch(path: &Path, changed: Arc<Notify>) -> Option<RecommendedWatcher> {
+    let name = path.file_name()?.to_owned();
+    let dir: &Path = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // reading the file on reload mustn't set off another reload
+        let relevant = event.is_ok_and(|event| {
+            let kind = match event.kind {
+                EventKind::Create(_) => true,
+                EventKind::Modify(notify::event::ModifyKind::Metadata(_)) => false,
+                EventKind::Modify(_) => true,
+                _ => false,
+            };
+            kind && event.paths.iter().any(|p| p.file_name() == Some(&name))
+        });
+        if relevant {
+            changed.notify_one();
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!(
+                "Failed to watch {}, only SIGHUP reloads it: {e}",
+                path.display()
+            );
+            return None;
+        }
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!(
+            "Failed to watch {}, only SIGHUP reloads it: {e}",
+            path.display()
+        );
+        return None;
+    }
+    Some(watcher)
+}
+
+/// Loads the config at `path` and applies what it can, see the module docs.
+pub async fn reload(path: &Path) {
+    // parse outside the lock, it touches the file system
+    let new = match Config::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Keeping the current config, {e}");
+            return;
+        }
+    };
+    let mut config = services::Config.write().await;
+    let changes = config.apply_live(new);
+    if changes.live.contains(&"logging") {
+        if let Err(e) = logging::apply(config.logging(), &services::DataDir.logs()) {
+            error!("Failed to change logging: {e:#}");
+        }
+    }
+    drop(config);
+    if changes.live.contains(&"library") {
+        services::LibraryService.request_scan();
+    }
+
+    for key in &changes.live {
+        info!("Applied changes to {key}");
+    }
+    for key in &changes.restart {
+        warn!("{key} changed, restart the server for it to take effect");
+    }
+    if changes.live.is_empty() && changes.restart.is_empty() {
+        info!("Config reloaded, nothing changed");
+    }
+}
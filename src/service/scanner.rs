@@ -9,9 +9,15 @@
 //! 2. Then attempting to match the file name with a MusicBrainz result
 //! This means local metadata will always take priority, as it is assumed to
 //! be verified by a human.
+//!
+//! Which files are scanned at all follows the `[library]` section of the
+//! config, see [FileFilter].
 
+mod chromaprint;
 mod file_parser;
 mod matcher;
-mod chromaprint;
+mod walker;
 
+pub use file_parser::{parse_local_id3, PartialID3Parse};
 pub use matcher::{Album, Artist, Song};
+pub use walker::{build_globs, find_files, FileFilter};
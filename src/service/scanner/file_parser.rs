@@ -1,5 +1,5 @@
-use std::{ffi::OsString, io, path::Path};
 use id3::{Tag, TagLike, Timestamp};
+use std::{ffi::OsString, io, path::Path};
 
 /// Internal struct that holds the results of a parsed song name. This is
 /// different from [Song], as that represents a song in the server's
@@ -30,29 +30,36 @@ pub fn scan_dir(dir: &Path) -> io::Result<Vec<ParsedSong>> {
     Ok(result)
 }
 
-struct PartialID3Parse {
-    title: Option<String>,
-    artist: Option<String>,
-    artists: Option<Vec<String>>,
-    album: Option<String>,
-    date: Option<Timestamp>,
-    duration: Option<u32>,
-    genre: Option<String>,
+pub struct PartialID3Parse {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub artists: Option<Vec<String>>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub date: Option<Timestamp>,
+    /// in milliseconds
+    pub duration: Option<u32>,
+    pub genre: Option<String>,
 }
 
 /// Parsing stage 1
 /// Local tags are prioritized above all other parsed information.
-fn parse_local_id3(path: &Path) -> Option<PartialID3Parse> {
+pub fn parse_local_id3(path: &Path) -> Option<PartialID3Parse> {
     let tag: Tag = Tag::read_from_path(path).ok()?;
-    Some(
-        PartialID3Parse {
-            title: tag.title().map(|s| s.to_string()),
-            artist: tag.artist().map(|s| s.to_string()),
-            artists: tag.artists().map(|s| s.iter().map(|s| s.to_string()).collect()),
-            album: tag.album().map(|s| s.to_string()),
-            date: tag.date_released(),
-            duration: tag.duration(),
-            genre: tag.genre_parsed().map(|s| s.to_string()),
-        }
-    )
+    Some(PartialID3Parse {
+        title: tag.title().map(|s| s.to_string()),
+        artist: tag.artist().map(|s| s.to_string()),
+        artists: tag
+            .artists()
+            .map(|s| s.iter().map(|s| s.to_string()).collect()),
+        album: tag.album().map(|s| s.to_string()),
+        album_artist: tag.album_artist().map(|s| s.to_string()),
+        track: tag.track(),
+        disc: tag.disc(),
+        date: tag.date_released().or_else(|| tag.date_recorded()),
+        duration: tag.duration(),
+        genre: tag.genre_parsed().map(|s| s.to_string()),
+    })
 }
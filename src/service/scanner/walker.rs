@@ -0,0 +1,121 @@
+//! Finds the files under the library roots that should be scanned, following
+//! the `[library]` settings in the config.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::config::LibraryConfig;
+
+/// Decides which files are part of the library, by their path relative to
+/// the root they were found in.
+pub struct FileFilter {
+    extensions: HashSet<String>,
+    exclude_extensions: HashSet<String>,
+    /// `None` includes everything
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl FileFilter {
+    // Constructor //
+    pub fn new(config: &LibraryConfig) -> Result<Self> {
+        let include = match config.include() {
+            [] => None,
+            patterns => Some(build_globs(patterns)?),
+        };
+        Ok(Self {
+            extensions: normalize_extensions(config.extensions()),
+            exclude_extensions: normalize_extensions(config.exclude_extensions()),
+            include,
+            exclude: build_globs(config.exclude())?,
+        })
+    }
+
+    // Methods //
+    /// Whether the file at `relative` should be scanned.
+    pub fn matches(&self, relative: &Path) -> bool {
+        let Some(extension) = relative
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+        else {
+            return false;
+        };
+        self.extensions.contains(&extension)
+            && !self.exclude_extensions.contains(&extension)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative))
+            && !self.exclude.is_match(relative)
+    }
+
+    /// Whether the directory at `relative` should be skipped entirely.
+    pub fn excludes_dir(&self, relative: &Path) -> bool {
+        self.exclude.is_match(relative)
+    }
+}
+
+/// Compiles glob patterns, where `*` also matches across directories.
+pub fn build_globs(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid pattern {pattern:?}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Lowercases extensions, dropping a leading dot if there is one.
+fn normalize_extensions(extensions: &[String]) -> HashSet<String> {
+    extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .collect()
+}
+
+/// Every file to scan under the configured roots, along with the root it was
+/// found in. Roots that aren't directories are left out, and entries that
+/// can't be read are logged and skipped.
+pub fn find_files(config: &LibraryConfig, filter: &FileFilter) -> Vec<(PathBuf, PathBuf)> {
+    let mut files: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for root in config.roots().iter().map(PathBuf::from) {
+        if !root.is_dir() {
+            continue;
+        }
+        let walker = WalkDir::new(&root)
+            .follow_links(config.follow_symlinks())
+            .into_iter()
+            .filter_entry(|entry| {
+                !entry.file_type().is_dir()
+                    || entry
+                        .path()
+                        .strip_prefix(&root)
+                        .is_ok_and(|relative| !filter.excludes_dir(relative))
+            });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping unreadable library entry: {e}");
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue; // directories, and links when they aren't followed
+            }
+            let Ok(relative) = entry.path().strip_prefix(&root) else {
+                continue;
+            };
+            if filter.matches(relative) {
+                files.push((root.clone(), entry.into_path()));
+            }
+        }
+    }
+    files
+}
@@ -0,0 +1,288 @@
+//! # On-disk Schema
+//! Every file Orpheus persists starts with a small header: the magic bytes
+//! `ORPH` followed by the little-endian `u32` version of the format the rest
+//! of the file is in. Files written before the header existed have no magic
+//! and are treated as version 0.
+//!
+//! Whenever the format of a file changes, a [Migration] from the previous
+//! version is appended to the chain of its [FileKind]. Old files are then
+//! upgraded one version at a time as they're loaded, or ahead of time with
+//! `orpheus migrate`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use pot::OwnedValue;
+use serde::{de::DeserializeOwned, de::IgnoredAny, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    config::{BackendKind, Config},
+    service::{
+        crypto::{self, Cipher},
+        data_dir::DataDir,
+        fs::{self, WriteAheadLog},
+        storage::{StorageBackend, Table},
+    },
+};
+
+pub const MAGIC: [u8; 4] = *b"ORPH";
+pub const HEADER_LEN: usize = MAGIC.len() + size_of::<u32>();
+
+/// Upgrades a payload from one version of its format to the next.
+pub struct Migration {
+    /// shown by `orpheus migrate`
+    pub description: &'static str,
+    pub upgrade: fn(Vec<u8>) -> Result<Vec<u8>>,
+}
+
+/// Version 0 files are the same pot data, just without a header.
+const ADD_HEADER: Migration = Migration {
+    description: "wrap headerless pot data in a versioned envelope",
+    upgrade: Ok,
+};
+
+/// Snapshots used to map keys straight to records, now they map keys to
+/// encoded records so any storage backend can hold them.
+const SNAPSHOT_RECORDS_TO_BLOBS: Migration = Migration {
+    description: "store snapshot records as versioned blobs",
+    upgrade: |payload| {
+        let records: HashMap<String, OwnedValue> = pot::from_slice(&payload)?;
+        let blobs = records
+            .into_iter()
+            .map(|(key, record)| Ok((key, ByteBuf::from(encode_version(1, &record)?))))
+            .collect::<Result<HashMap<String, ByteBuf>>>()?;
+        Ok(pot::to_vec(&blobs)?)
+    },
+};
+
+/// The first logs only ever held account records, keyed by their username.
+const LOG_ENTRIES_TO_BLOBS: Migration = Migration {
+    description: "store log entries as keyed, versioned blobs",
+    upgrade: |payload| {
+        #[derive(Deserialize)]
+        enum Old {
+            Put(OwnedValue),
+        }
+        #[derive(Serialize)]
+        enum New {
+            Put(String, ByteBuf),
+        }
+        #[derive(Deserialize)]
+        struct Keyed {
+            username: String,
+        }
+
+        let Old::Put(record) = pot::from_slice(&payload)?;
+        let key: String = record.deserialize_as::<Keyed>()?.username;
+        let blob = ByteBuf::from(encode_version(1, &record)?);
+        Ok(pot::to_vec(&New::Put(key, blob))?)
+    },
+};
+
+/// The different kinds of files and records Orpheus persists, each with its
+/// own version history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    /// snapshot of a table kept by the pot file backend, and its backups
+    Snapshot(Table),
+    /// write-ahead log of changes to a table, migrated entry by entry
+    Log(Table),
+    /// a single record of a table, as handed to any storage backend
+    Record(Table),
+    /// listens waiting to be forwarded
+    ScrobbleQueue,
+}
+
+impl FileKind {
+    /// The chain of migrations for this kind, where the `n`th entry upgrades
+    /// version `n` to version `n + 1`.
+    fn migrations(self) -> &'static [Migration] {
+        match self {
+            FileKind::Snapshot(_) => &[ADD_HEADER, SNAPSHOT_RECORDS_TO_BLOBS],
+            FileKind::Log(_) => &[ADD_HEADER, LOG_ENTRIES_TO_BLOBS],
+            FileKind::Record(_) => &[ADD_HEADER],
+            FileKind::ScrobbleQueue => &[ADD_HEADER],
+        }
+    }
+
+    /// The version files of this kind are written in.
+    pub fn current_version(self) -> u32 {
+        self.migrations().len() as u32
+    }
+
+    /// The header every file of this kind is written with.
+    pub fn header(self) -> [u8; HEADER_LEN] {
+        header(self.current_version())
+    }
+
+    /// Returns the migrations needed to bring a file of `version` up to date.
+    pub fn pending_migrations(self, version: u32) -> Result<&'static [Migration]> {
+        match self.migrations().get(version as usize..) {
+            Some(pending) => Ok(pending),
+            None => bail!(
+                "{self:?} file is version {version}, but this build of Orpheus only \
+                 understands up to version {}",
+                self.current_version()
+            ),
+        }
+    }
+
+    /// What data of this kind is authenticated as when sealed, see
+    /// [crypto::seal]: the kind, the table and the `key` of a record, empty
+    /// for whole files. Sealed data moved to another table or record then
+    /// fails to open.
+    pub fn aad(self, key: &str) -> Vec<u8> {
+        let (kind, table) = match self {
+            FileKind::Snapshot(table) => ("snapshot", table.name()),
+            FileKind::Log(table) => ("log", table.name()),
+            FileKind::Record(table) => ("record", table.name()),
+            FileKind::ScrobbleQueue => ("scrobble_queue", ""),
+        };
+        let mut aad: Vec<u8> = Vec::new();
+        for part in [kind, table, key] {
+            // length-prefixed, so no two different keys make the same data
+            aad.extend_from_slice(&(part.len() as u32).to_le_bytes());
+            aad.extend_from_slice(part.as_bytes());
+        }
+        aad
+    }
+
+    /// Runs every pending migration on `payload`, which is in `version`.
+    pub fn upgrade(self, version: u32, payload: Vec<u8>) -> Result<Vec<u8>> {
+        self.pending_migrations(version)?
+            .iter()
+            .try_fold(payload, |payload, migration| (migration.upgrade)(payload))
+    }
+}
+
+fn header(version: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0_u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&version.to_le_bytes());
+    header
+}
+
+/// Serializes `value` with the header of a fixed version, for migrations
+/// producing data in a format that may no longer be the current one.
+fn encode_version(version: u32, value: &impl Serialize) -> Result<Vec<u8>> {
+    let mut encoded: Vec<u8> = header(version).to_vec();
+    pot::to_writer(value, &mut encoded)?;
+    Ok(encoded)
+}
+
+/// Splits the contents of a persisted file into its version and payload.
+pub fn split_header(bytes: &[u8]) -> (u32, &[u8]) {
+    match bytes.strip_prefix(&MAGIC) {
+        Some(rest) if rest.len() >= size_of::<u32>() => {
+            let (version, payload) = rest.split_at(size_of::<u32>());
+            (u32::from_le_bytes(version.try_into().unwrap()), payload)
+        }
+        _ => (0, bytes), // written before versioning
+    }
+}
+
+/// Serializes `value` into a file of the given kind, header included.
+pub fn encode(kind: FileKind, value: &impl Serialize) -> Result<Vec<u8>> {
+    encode_version(kind.current_version(), value)
+}
+
+/// Deserializes the contents of a file of the given kind, migrating it
+/// first if it's from an older version.
+pub fn decode<T: DeserializeOwned>(kind: FileKind, bytes: &[u8]) -> Result<T> {
+    let (version, payload) = split_header(bytes);
+    let payload: Vec<u8> = kind.upgrade(version, payload.to_vec())?;
+    Ok(pot::from_slice(&payload)?)
+}
+
+/// Every versioned file persisted in `data_dir` for a server using `config`, whether or not it
+/// exists yet. Records kept in a database are covered by [migrate_records].
+pub fn persisted_files(config: &Config, data_dir: &DataDir) -> Vec<(FileKind, PathBuf)> {
+    let mut files: Vec<(FileKind, PathBuf)> =
+        vec![(FileKind::ScrobbleQueue, data_dir.scrobble_queue())];
+    if config.storage().backend() == BackendKind::Pot {
+        for table in Table::ALL {
+            let snapshot: PathBuf = data_dir.table(table);
+            files.push((FileKind::Log(table), fs::with_suffix(&snapshot, ".wal")));
+            files.extend((1..=config.server().account_backups()).map(|generation| {
+                (
+                    FileKind::Snapshot(table),
+                    fs::backup_path(&snapshot, generation),
+                )
+            }));
+            files.push((FileKind::Snapshot(table), snapshot));
+        }
+    }
+    files
+}
+
+/// Brings every record of `table` in `storage` up to date, returning how many
+/// were outdated. With `dry_run` nothing is written.
+pub fn migrate_records(storage: &dyn StorageBackend, table: Table, dry_run: bool) -> Result<usize> {
+    let kind = FileKind::Record(table);
+    let mut outdated: usize = 0;
+    for (key, value) in storage.load(table)? {
+        let (version, payload) = split_header(&value);
+        if version == kind.current_version() {
+            continue;
+        }
+        outdated += 1;
+        if !dry_run {
+            let mut upgraded: Vec<u8> = kind.header().to_vec();
+            upgraded.extend(kind.upgrade(version, payload.to_vec())?);
+            storage.put(table, &key, upgraded)?;
+        }
+    }
+    Ok(outdated)
+}
+
+/// Brings the file at `path` up to date, returning its version and the
+/// migrations it needed. With `dry_run` nothing is written.
+pub fn migrate_file(
+    kind: FileKind,
+    path: &Path,
+    cipher: Option<&Arc<Cipher>>,
+    dry_run: bool,
+) -> Result<(u32, &'static [Migration])> {
+    let contents: Vec<u8> = std::fs::read(path)?;
+    if let FileKind::Log(_) = kind {
+        // the header of a log is never sealed, only its entries
+        let (version, _) = split_header(&contents);
+        let pending = kind.pending_migrations(version)?;
+        if !dry_run && !pending.is_empty() {
+            WriteAheadLog::open::<IgnoredAny>(kind, path, cipher.cloned())?; // upgrades the log in place
+        }
+        return Ok((version, pending));
+    }
+
+    let (contents, _) = crypto::unseal(cipher.map(Arc::as_ref), &kind.aad(""), &contents)?;
+    let (version, payload) = split_header(&contents);
+    let pending = kind.pending_migrations(version)?;
+    if !dry_run && !pending.is_empty() {
+        let mut upgraded: Vec<u8> = kind.header().to_vec();
+        upgraded.extend(kind.upgrade(version, payload.to_vec())?);
+        let upgraded: Vec<u8> = crypto::seal(cipher.map(Arc::as_ref), &kind.aad(""), upgraded);
+        fs::atomic_write(path, &upgraded, 0)?;
+    }
+    Ok((version, pending))
+}
+
+/// Seals the file at `path` with the current key of `cipher` if it isn't
+/// already, returning whether it had to be rewritten.
+pub fn reseal_file(kind: FileKind, path: &Path, cipher: &Arc<Cipher>) -> Result<bool> {
+    if let FileKind::Log(_) = kind {
+        let before: Vec<u8> = std::fs::read(path)?;
+        WriteAheadLog::open::<IgnoredAny>(kind, path, Some(cipher.clone()))?; // reseals entries in place
+        return Ok(std::fs::read(path)? != before);
+    }
+    let aad: Vec<u8> = kind.aad("");
+    let (contents, reseal) = crypto::unseal(Some(cipher), &aad, &std::fs::read(path)?)?;
+    if reseal {
+        fs::atomic_write(path, &cipher.seal(&aad, &contents), 0)?;
+    }
+    Ok(reseal)
+}
@@ -0,0 +1,349 @@
+//! # Scrobble Forwarding
+//! Relays plays recorded by the server to external listen trackers. Any
+//! service speaking the ListenBrainz `submit-listens` API works as a target,
+//! which includes ListenBrainz itself and self-hosted Maloja instances
+//! (under `<maloja>/apis/listenbrainz`).
+//!
+//! Every play is put into a queue that is written to disk on each change
+//! and drained by a background task, so listens survive both a target
+//! being down and the server restarting. A listen only leaves the queue
+//! once its target has accepted or refused it.
+
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use futures_util::future::join_all;
+use reqwest::{header::AUTHORIZATION, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+
+use crate::{
+    service::{
+        crypto::{self, Cipher},
+        fs,
+        schema::{self, FileKind},
+    },
+    services,
+    types::SecretString,
+};
+
+/// Base URL used for targets that don't specify one.
+pub const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+
+/// Delay before the first retry of a failed submission, doubled on every
+/// further failure up to [MAX_RETRY_DELAY].
+const BASE_RETRY_DELAY: i64 = 30;
+const MAX_RETRY_DELAY: i64 = 60 * 60;
+
+/// Global variable holding the singleton instance of [ScrobbleQueue].
+pub static SCROBBLER: LazyLock<ScrobbleQueue> = LazyLock::new(|| {
+    ScrobbleQueue::from_path(
+        services::DataDir.scrobble_queue(),
+        services::CipherService.clone(),
+    )
+});
+
+/// A ListenBrainz-compatible server that a user wants their plays forwarded to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, utoipa::ToSchema)]
+pub struct ScrobbleTarget {
+    /// API root, e.g. `https://api.listenbrainz.org`. `/1/submit-listens`
+    /// is appended to it when submitting.
+    #[serde(default = "default_base_url")]
+    base_url: String,
+    /// the user token issued by the target service
+    #[schema(value_type = String)]
+    token: SecretString,
+}
+
+fn default_base_url() -> String {
+    LISTENBRAINZ_URL.to_owned()
+}
+
+crate::make_getters!(ScrobbleTarget, base_url: String, token: SecretString);
+
+impl ScrobbleTarget {
+    pub fn new(base_url: String, token: SecretString) -> Self {
+        Self { base_url, token }
+    }
+
+    fn submit_url(&self) -> String {
+        format!("{}/1/submit-listens", self.base_url.trim_end_matches('/'))
+    }
+}
+
+/// A single recorded play of a song.
+#[derive(Serialize, Deserialize, Debug, Clone, utoipa::ToSchema)]
+pub struct Listen {
+    /// unix timestamp of when the song started playing, defaults to now
+    #[serde(default = "now")]
+    pub listened_at: i64,
+    pub artist: String,
+    pub track: String,
+    pub release: Option<String>,
+    pub duration_secs: Option<u32>,
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+/// Builds the ListenBrainz JSON submission body for a single listen.
+pub(crate) fn submission(listen: &Listen) -> serde_json::Value {
+    let mut additional_info = json!({
+        "submission_client": "Orpheus",
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(secs) = listen.duration_secs {
+        additional_info["duration_ms"] = json!(u64::from(secs) * 1000);
+    }
+
+    let mut track_metadata = json!({
+        "artist_name": listen.artist,
+        "track_name": listen.track,
+        "additional_info": additional_info,
+    });
+    if let Some(release) = &listen.release {
+        track_metadata["release_name"] = json!(release);
+    }
+
+    json!({
+        "listen_type": "single",
+        "payload": [{
+            "listened_at": listen.listened_at,
+            "track_metadata": track_metadata,
+        }],
+    })
+}
+
+/// A listen waiting to be delivered to one target.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PendingListen {
+    target: ScrobbleTarget,
+    listen: Listen,
+    attempts: u32,
+    /// unix timestamp before which this entry shouldn't be retried
+    next_attempt: i64,
+    /// id of the submission currently in flight, the entry stays queued
+    /// (and saved) until it settles
+    #[serde(skip)]
+    in_flight: Option<u64>,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DeliveryError {
+    /// The target refused the listen outright, retrying won't help.
+    #[error("target rejected listen with status {0}")]
+    Rejected(StatusCode),
+    #[error("failed to submit listen: {0}")]
+    Transient(String),
+}
+
+/// A persistent queue of listens to forward, see the module docs.
+pub struct ScrobbleQueue {
+    path: PathBuf,
+    cipher: Option<Arc<Cipher>>,
+    pending: Mutex<VecDeque<PendingListen>>,
+    /// source of [PendingListen::in_flight] ids
+    next_submission: AtomicU64,
+    wake: Notify,
+    client: reqwest::Client,
+}
+
+impl ScrobbleQueue {
+    // Constructor //
+    pub fn from_path(path: PathBuf, cipher: Option<Arc<Cipher>>) -> Self {
+        if let Some(p) = path.parent() {
+            fs::create_private_dir(p)
+                .expect("Failed to create scrobble queue path! Double check write permissions.");
+        }
+
+        let pending: VecDeque<PendingListen> = if !path.exists() {
+            VecDeque::new()
+        } else {
+            let contents: Vec<u8> = std::fs::read(&path).expect("Failed to read scrobble queue!");
+            // unlike a corrupt queue, a missing key is worth refusing to start over
+            let aad: Vec<u8> = FileKind::ScrobbleQueue.aad("");
+            let (contents, _) = crypto::unseal(cipher.as_deref(), &aad, &contents)
+                .expect("Failed to decrypt scrobble queue!");
+            schema::decode(FileKind::ScrobbleQueue, &contents).unwrap_or_else(|e| {
+                // losing a few listens isn't worth refusing to start over
+                error!("Failed to deserialize scrobble queue, starting empty: {e}");
+                VecDeque::new()
+            })
+        };
+
+        Self {
+            path,
+            cipher,
+            pending: Mutex::new(pending),
+            next_submission: AtomicU64::new(0),
+            wake: Notify::new(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("Failed to build HTTP client!"),
+        }
+    }
+
+    // Methods //
+    /// Queues `listen` for delivery to every target in `targets` and wakes
+    /// the delivery task.
+    pub fn enqueue(&self, targets: &[ScrobbleTarget], listen: Listen) {
+        if targets.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        for target in targets {
+            pending.push_back(PendingListen {
+                target: target.clone(),
+                listen: listen.clone(),
+                attempts: 0,
+                next_attempt: 0,
+                in_flight: None,
+            });
+        }
+        self.save(&pending);
+        drop(pending);
+        self.wake.notify_one();
+    }
+
+    /// Number of listens still waiting to be delivered.
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The queue as it would be saved, but never sealed, for backups.
+    pub fn encoded(&self) -> anyhow::Result<Vec<u8>> {
+        schema::encode(FileKind::ScrobbleQueue, &*self.pending.lock().unwrap())
+    }
+
+    fn save(&self, pending: &VecDeque<PendingListen>) {
+        let result = schema::encode(FileKind::ScrobbleQueue, pending).and_then(|encoded| {
+            let aad: Vec<u8> = FileKind::ScrobbleQueue.aad("");
+            let encoded: Vec<u8> = crypto::seal(self.cipher.as_deref(), &aad, encoded);
+            Ok(fs::atomic_write(&self.path, &encoded, 0)?)
+        });
+        if let Err(e) = result {
+            error!("Failed to save scrobble queue: {e}");
+        }
+    }
+
+    /// Delivery loop, meant to be spawned once as a background task.
+    pub async fn run(&self) {
+        loop {
+            match self.deliver_due().await {
+                Some(wait) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.wake.notified() => {}
+                    }
+                }
+                None => self.wake.notified().await,
+            }
+        }
+    }
+
+    /// Attempts to deliver every entry whose retry time has come, then
+    /// returns how long to wait until the next entry is due (if any).
+    /// Each target gets its listens in order, but targets are delivered to
+    /// concurrently, so a slow or dead one only holds up its own listens.
+    pub async fn deliver_due(&self) -> Option<Duration> {
+        let now: i64 = now();
+        let mut batches: Vec<Vec<(u64, PendingListen)>> = Vec::new();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let due = pending
+                .iter_mut()
+                .filter(|p| p.in_flight.is_none() && p.next_attempt <= now);
+            for entry in due {
+                let id: u64 = self.next_submission.fetch_add(1, Ordering::Relaxed);
+                entry.in_flight = Some(id);
+                match batches.iter_mut().find(|b| b[0].1.target == entry.target) {
+                    Some(batch) => batch.push((id, entry.clone())),
+                    None => batches.push(vec![(id, entry.clone())]),
+                }
+            }
+        }
+
+        if !batches.is_empty() {
+            join_all(batches.into_iter().map(|batch| self.deliver(batch, now))).await;
+            let pending = self.pending.lock().unwrap();
+            self.save(&pending);
+        }
+
+        let pending = self.pending.lock().unwrap();
+        pending
+            .iter()
+            .filter(|p| p.in_flight.is_none())
+            .map(|p| p.next_attempt)
+            .min()
+            .map(|next| Duration::from_secs((next - now).max(1) as u64))
+    }
+
+    /// Submits the listens of one target one after another, settling each
+    /// queued entry once its submission has.
+    async fn deliver(&self, batch: Vec<(u64, PendingListen)>, now: i64) {
+        for (id, entry) in batch {
+            let result = self.submit(&entry).await;
+            let mut pending = self.pending.lock().unwrap();
+            let Some(index) = pending.iter().position(|p| p.in_flight == Some(id)) else {
+                continue;
+            };
+            match result {
+                Ok(()) => {
+                    debug!("forwarded listen to {}", entry.target.base_url);
+                    pending.remove(index);
+                }
+                Err(e @ DeliveryError::Rejected(_)) => {
+                    warn!("dropping listen for {}: {e}", entry.target.base_url);
+                    pending.remove(index);
+                }
+                Err(e @ DeliveryError::Transient(_)) => {
+                    let entry: &mut PendingListen = &mut pending[index];
+                    let delay = (BASE_RETRY_DELAY << entry.attempts.min(16)).min(MAX_RETRY_DELAY);
+                    entry.attempts += 1;
+                    entry.next_attempt = now + delay;
+                    entry.in_flight = None;
+                    debug!("{e}, retrying in {delay}s");
+                }
+            }
+        }
+    }
+
+    async fn submit(&self, entry: &PendingListen) -> Result<(), DeliveryError> {
+        let response = self
+            .client
+            .post(entry.target.submit_url())
+            .header(
+                AUTHORIZATION,
+                format!("Token {}", entry.target.token.expose()),
+            )
+            .json(&submission(&entry.listen))
+            .send()
+            .await
+            .map_err(|e| DeliveryError::Transient(e.to_string()))?;
+
+        let status: StatusCode = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(DeliveryError::Transient(format!("status {status}")))
+        } else {
+            Err(DeliveryError::Rejected(status))
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! # Storage Backends
+//! Everything the server persists (accounts, sessions, library metadata and
+//! user data) goes through a [StorageBackend], which is chosen with the
+//! `[storage] backend` key in `orpheus.toml`. Services keep their own
+//! in-memory view of the data and write every change through to the backend.
+//!
+//! Backends only deal in keys and opaque bytes grouped into [Table]s. Records
+//! are encoded by [StorageBackend::put_record] with a versioned header (see
+//! the `schema` module), so they're migrated the same way whatever backend
+//! they were stored in.
+
+pub mod memory;
+pub mod pot_file;
+pub mod sqlite;
+
+use std::sync::{Arc, LazyLock};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Notify;
+
+pub use memory::MemoryStorage;
+pub use pot_file::PotFileStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::{
+    config::{BackendKind, Config},
+    service::{
+        crypto::Cipher,
+        data_dir::DataDir,
+        schema::{self, FileKind},
+    },
+    services,
+};
+
+/// Global variable holding the backend selected in `orpheus.toml`.
+pub static STORAGE: LazyLock<Arc<dyn StorageBackend>> = LazyLock::new(|| {
+    open(
+        &services::Config
+            .try_read() // startup, nothing else is writing to the config yet
+            .unwrap(),
+        &services::DataDir,
+        services::CipherService.clone(),
+    )
+});
+
+/// Opens the backend selected by `config` in `data_dir`, sealing data with
+/// `cipher` if given.
+pub fn open(
+    config: &Config,
+    data_dir: &DataDir,
+    cipher: Option<Arc<Cipher>>,
+) -> Arc<dyn StorageBackend> {
+    match config.storage().backend() {
+        BackendKind::Pot => Arc::new(PotFileStorage::open(
+            &data_dir.db(),
+            config.server().account_backups(),
+            cipher,
+        )),
+        BackendKind::Sqlite => Arc::new(
+            SqliteStorage::open(&data_dir.sqlite(), cipher)
+                .expect("Failed to open SQLite database!"),
+        ),
+        BackendKind::Memory => Arc::new(MemoryStorage::default()),
+    }
+}
+
+/// The collections of records a backend keeps, each mapping string keys to records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Table {
+    /// account records keyed by username
+    Accounts,
+    /// logged in sessions keyed by token
+    Sessions,
+    /// metadata of the scanned music library
+    Library,
+    /// per-user collections too large to live in the account record
+    UserData,
+}
+
+impl Table {
+    pub const ALL: [Table; 4] = [
+        Table::Accounts,
+        Table::Sessions,
+        Table::Library,
+        Table::UserData,
+    ];
+
+    /// Name of the table in file names and SQL.
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::Accounts => "accounts",
+            Table::Sessions => "sessions",
+            Table::Library => "library",
+            Table::UserData => "user_data",
+        }
+    }
+}
+
+/// Every record of every table, see [StorageBackend::snapshot].
+pub type Snapshot = Vec<(Table, Vec<(String, Vec<u8>)>)>;
+
+/// A durable key-value store of encoded records, grouped into [Table]s.
+/// Every write must be durable by the time it returns.
+pub trait StorageBackend: Send + Sync {
+    /// Returns every record in `table`, in no particular order.
+    fn load(&self, table: Table) -> Result<Vec<(String, Vec<u8>)>>;
+
+    /// Inserts or replaces the record under `key`.
+    fn put(&self, table: Table, key: &str, value: Vec<u8>) -> Result<()>;
+
+    /// Removes the record under `key`, if there is one.
+    fn delete(&self, table: Table, key: &str) -> Result<()>;
+
+    /// Does any housekeeping that's cheaper to batch, like compacting logs.
+    fn flush(&self) -> Result<()>;
+
+    /// Every record of every table as of a single point in time, for backups.
+    /// Backends that can change between loading two tables must override this.
+    fn snapshot(&self) -> Result<Snapshot> {
+        Table::ALL
+            .into_iter()
+            .map(|table| Ok((table, self.load(table)?)))
+            .collect()
+    }
+
+    /// Whether [StorageBackend::flush] has anything to do.
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    /// Notified whenever the backend becomes dirty, see the `persistence`
+    /// module. Backends that are never dirty don't need one.
+    fn changes(&self) -> Option<&Notify> {
+        None
+    }
+}
+
+// typed helpers, kept off the trait so it stays object safe
+impl dyn StorageBackend {
+    /// Loads and decodes every record in `table`, migrating old ones as needed.
+    pub fn load_records<T: DeserializeOwned>(&self, table: Table) -> Result<Vec<(String, T)>> {
+        self.load(table)?
+            .into_iter()
+            .map(|(key, value)| Ok((key, schema::decode(FileKind::Record(table), &value)?)))
+            .collect()
+    }
+
+    /// Encodes `record` and stores it under `key`.
+    pub fn put_record(&self, table: Table, key: &str, record: &impl Serialize) -> Result<()> {
+        self.put(table, key, schema::encode(FileKind::Record(table), record)?)
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+
+use super::{Snapshot, StorageBackend, Table};
+
+/// A backend that keeps everything in memory and forgets it on exit,
+/// meant for tests and throwaway servers.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<HashMap<Table, BTreeMap<String, Vec<u8>>>>,
+}
+
+impl StorageBackend for MemoryStorage {
+    fn load(&self, table: Table) -> Result<Vec<(String, Vec<u8>)>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .get(&table)
+            .map(|records| records.clone().into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    fn put(&self, table: Table, key: &str, value: Vec<u8>) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables
+            .entry(table)
+            .or_default()
+            .insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn delete(&self, table: Table, key: &str) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(records) = tables.get_mut(&table) {
+            records.remove(key);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        let tables = self.tables.lock().unwrap();
+        Ok(Table::ALL
+            .into_iter()
+            .map(|table| {
+                let records = tables.get(&table).cloned().unwrap_or_default();
+                (table, records.into_iter().collect())
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::Notify;
+use tracing::{debug, error, trace, warn};
+
+use super::{Snapshot, StorageBackend, Table};
+use crate::service::{
+    crypto::{self, Cipher},
+    fs::{self, WriteAheadLog},
+    schema::{self, FileKind},
+};
+
+/// Once a table's write-ahead log holds this many entries, the next change
+/// compacts it into a fresh snapshot.
+const COMPACT_THRESHOLD: usize = 1024;
+
+/// Where the snapshot of `table` lives in the backend's directory.
+pub fn table_path(dir: &Path, table: Table) -> PathBuf {
+    dir.join(table.name())
+}
+
+/// A single change to a table as recorded in its write-ahead log.
+#[derive(Serialize, Deserialize)]
+enum LogEntry {
+    /// inserts or replaces the record under the key
+    Put(String, ByteBuf),
+    Delete(String),
+}
+
+/// The original backend: every table is held in memory, changes are appended
+/// to a write-ahead log (`<snapshot>.wal`) as they happen, and the log is
+/// periodically compacted into a pot snapshot with rotated backups. With a
+/// [Cipher], snapshots and log entries are sealed before they hit the disk.
+pub struct PotFileStorage {
+    tables: HashMap<Table, PotTable>,
+    changed: Notify,
+}
+
+struct PotTable {
+    table: Table,
+    path: PathBuf,
+    /// number of previous generations kept next to `path` on every save
+    backups: usize,
+    cipher: Option<Arc<Cipher>>,
+    /// the log also serializes changes, so its order always matches `records`
+    state: Mutex<TableState>,
+}
+
+struct TableState {
+    wal: WriteAheadLog,
+    records: HashMap<String, ByteBuf>,
+}
+
+impl PotFileStorage {
+    // Constructor //
+    /// Loads every table stored in `dir`, see [table_path].
+    pub fn open(dir: &Path, backups: usize, cipher: Option<Arc<Cipher>>) -> Self {
+        let tables = Table::ALL
+            .into_iter()
+            .map(|table| {
+                let path = table_path(dir, table);
+                (table, PotTable::open(table, path, backups, cipher.clone()))
+            })
+            .collect();
+        Self {
+            tables,
+            changed: Notify::new(),
+        }
+    }
+
+    fn table(&self, table: Table) -> &PotTable {
+        &self.tables[&table]
+    }
+}
+
+impl TableState {
+    fn records(&self) -> Vec<(String, Vec<u8>)> {
+        self.records
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_vec()))
+            .collect()
+    }
+}
+
+impl PotTable {
+    /// Loads the snapshot stored at `path` and replays the changes logged since it
+    /// was written. If that file is missing or can't be read, the newest backup
+    /// that can be is used instead.
+    fn open(table: Table, path: PathBuf, backups: usize, cipher: Option<Arc<Cipher>>) -> Self {
+        if let Some(p) = path.parent() {
+            fs::create_private_dir(p) // make all necessary directories to create data file
+                .expect("Failed to create data file path! Double check write permissions.");
+        }
+
+        let candidates: Vec<PathBuf> = std::iter::once(path.clone())
+            .chain((1..=backups).map(|generation| fs::backup_path(&path, generation)))
+            .filter(|p| p.exists())
+            .collect();
+
+        // whether the snapshot at `path` has to be (re)written once loaded
+        let mut stale: bool = true;
+        let mut records: HashMap<String, ByteBuf> = if candidates.is_empty() {
+            HashMap::new() // if there's no file at path (or backups of it), make a new map
+        } else {
+            let (loaded, (records, outdated)) = candidates
+                .iter()
+                .find_map(
+                    |candidate| match Self::read_file(table, candidate, cipher.as_deref()) {
+                        Ok(loaded) => Some((candidate, loaded)),
+                        Err(e) => {
+                            error!(
+                                "Failed to load {} from {}: {e}",
+                                table.name(),
+                                candidate.display()
+                            );
+                            None
+                        }
+                    },
+                )
+                .expect("Failed to load data file or any of its backups!");
+            stale = outdated || *loaded != path;
+            if *loaded != path {
+                warn!(
+                    "Recovered {} from backup {}",
+                    table.name(),
+                    loaded.display()
+                );
+                if path.exists() {
+                    // keep the broken file around for inspection instead of rotating it into the backups
+                    std::fs::rename(&path, fs::with_suffix(&path, ".corrupt"))
+                        .expect("Failed to move corrupt data file aside!");
+                }
+            }
+            records
+        };
+
+        let (wal, entries) = WriteAheadLog::open(
+            FileKind::Log(table),
+            &fs::with_suffix(&path, ".wal"),
+            cipher.clone(),
+        )
+        .expect("Failed to open write-ahead log!");
+        debug!(
+            "Replaying {} logged changes to {}",
+            entries.len(),
+            table.name()
+        );
+        stale |= !entries.is_empty();
+        for entry in entries {
+            match entry {
+                LogEntry::Put(key, value) => records.insert(key, value),
+                LogEntry::Delete(key) => records.remove(&key),
+            };
+        }
+
+        let new = Self {
+            table,
+            path,
+            backups,
+            cipher,
+            state: Mutex::new(TableState { wal, records }),
+        };
+        if stale {
+            // compact the replayed log right away, rewriting old or recovered snapshots too
+            new.compact(&mut new.state.lock().unwrap())
+                .expect("Failed to save table snapshot!");
+        }
+        new
+    }
+
+    /// Reads the snapshot at `path`, along with whether it's in an older format
+    /// or isn't sealed with the current key.
+    fn read_file(
+        table: Table,
+        path: &Path,
+        cipher: Option<&Cipher>,
+    ) -> Result<(HashMap<String, ByteBuf>, bool)> {
+        let kind = FileKind::Snapshot(table);
+        let (contents, reseal) = crypto::unseal(cipher, &kind.aad(""), &std::fs::read(path)?)?;
+        let (version, _) = schema::split_header(&contents);
+        let outdated: bool = reseal || version != kind.current_version();
+        Ok((schema::decode(kind, &contents)?, outdated))
+    }
+
+    /// Saves the entire table to its path, then empties the log. The file
+    /// is replaced atomically, see [fs::atomic_write].
+    fn compact(&self, state: &mut TableState) -> Result<()> {
+        trace!("Saving snapshot {}", self.path.display());
+        let kind = FileKind::Snapshot(self.table);
+        let encoded: Vec<u8> = schema::encode(kind, &state.records)?;
+        let encoded: Vec<u8> = crypto::seal(self.cipher.as_deref(), &kind.aad(""), encoded);
+        fs::atomic_write(&self.path, &encoded, self.backups)?;
+        // only safe once the snapshot is in place, replaying the log onto it is harmless
+        state.wal.truncate()?;
+        Ok(())
+    }
+
+    /// Applies `entry` to the table and durably logs it before returning.
+    /// Compacts the log first if it has grown past [COMPACT_THRESHOLD], so
+    /// if that fails the change isn't made at all.
+    fn commit(&self, entry: LogEntry) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.wal.len() >= COMPACT_THRESHOLD {
+            self.compact(&mut state)?;
+        }
+        state.wal.append(&entry)?;
+        match entry {
+            LogEntry::Put(key, value) => state.records.insert(key, value),
+            LogEntry::Delete(key) => state.records.remove(&key),
+        };
+        Ok(())
+    }
+}
+
+impl StorageBackend for PotFileStorage {
+    fn load(&self, table: Table) -> Result<Vec<(String, Vec<u8>)>> {
+        let state = self.table(table).state.lock().unwrap();
+        Ok(state.records())
+    }
+
+    fn put(&self, table: Table, key: &str, value: Vec<u8>) -> Result<()> {
+        self.table(table)
+            .commit(LogEntry::Put(key.to_owned(), ByteBuf::from(value)))?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn delete(&self, table: Table, key: &str) -> Result<()> {
+        self.table(table).commit(LogEntry::Delete(key.to_owned()))?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for table in self.tables.values() {
+            let mut state = table.state.lock().unwrap();
+            if !state.wal.is_empty() {
+                table.compact(&mut state)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        // hold every table still at once, always locking them in the same order
+        let states: Vec<(Table, MutexGuard<TableState>)> = Table::ALL
+            .into_iter()
+            .map(|table| (table, self.table(table).state.lock().unwrap()))
+            .collect();
+        Ok(states
+            .iter()
+            .map(|(table, state)| (*table, state.records()))
+            .collect())
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.tables
+            .values()
+            .any(|table| !table.state.lock().unwrap().wal.is_empty())
+    }
+
+    fn changes(&self) -> Option<&Notify> {
+        Some(&self.changed)
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use rusqlite::{params, Connection};
+
+use super::{Snapshot, StorageBackend, Table};
+use crate::service::{
+    crypto::{self, Cipher},
+    fs,
+    schema::FileKind,
+};
+
+/// A backend keeping every table in a single embedded SQLite database.
+/// SQLite is compiled into the binary, so no system library is needed.
+/// With a [Cipher], record values are sealed one by one, bound to their table
+/// and key; keys stay readable.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl SqliteStorage {
+    // Constructor //
+    pub fn open(path: &Path, cipher: Option<Arc<Cipher>>) -> Result<Self> {
+        if let Some(p) = path.parent() {
+            fs::create_private_dir(p)?;
+        }
+        // SQLite gives its journal files the permissions of the database
+        fs::private_options()
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let connection = Connection::open(path)?;
+        // WAL journaling with full syncs makes every committed write durable
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        for table in Table::ALL {
+            connection.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY NOT NULL, value BLOB NOT NULL) WITHOUT ROWID",
+                    table.name()
+                ),
+                [],
+            )?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+            cipher,
+        })
+    }
+
+    /// Loads `table` through a connection that's already locked.
+    fn load_with(&self, connection: &Connection, table: Table) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut statement =
+            connection.prepare_cached(&format!("SELECT key, value FROM {}", table.name()))?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, Vec<u8>)>>>()?;
+        rows.into_iter()
+            .map(|(key, value)| {
+                let aad: Vec<u8> = FileKind::Record(table).aad(&key);
+                let (value, _) = crypto::unseal(self.cipher.as_deref(), &aad, &value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn load(&self, table: Table) -> Result<Vec<(String, Vec<u8>)>> {
+        let connection = self.connection.lock().unwrap();
+        self.load_with(&connection, table)
+    }
+
+    fn put(&self, table: Table, key: &str, value: Vec<u8>) -> Result<()> {
+        let aad: Vec<u8> = FileKind::Record(table).aad(key);
+        let value: Vec<u8> = crypto::seal(self.cipher.as_deref(), &aad, value);
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(&format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                table.name()
+            ))?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+
+    fn delete(&self, table: Table, key: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(&format!("DELETE FROM {} WHERE key = ?1", table.name()))?
+            .execute(params![key])?;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        // the only connection stays locked throughout, so nothing can change in between
+        let connection = self.connection.lock().unwrap();
+        Table::ALL
+            .into_iter()
+            .map(|table| Ok((table, self.load_with(&connection, table)?)))
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! # HTTPS
+//! With a `[server.tls]` section in the config, the server speaks HTTPS on
+//! its bind address instead of plain HTTP, so passwords sent to `/api/v1/login`
+//! never cross the network in the clear.
+//!
+//! The certificate and key are re-read whenever either file changes on disk,
+//! so renewals (by certbot and the like) apply without a restart. New
+//! connections get the new certificate, while open ones, like a stream in
+//! progress, carry on with the one they were made with. A certificate that
+//! can't be loaded is logged and the current one kept.
+
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, error, info};
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a client gets to finish the TLS handshake before it's dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the certificate chain and key named in `config`, checking that they
+/// belong together.
+pub fn load_certified_key(config: &TlsConfig) -> Result<CertifiedKey> {
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_file_iter(config.cert_file())
+        .and_then(|certs| certs.collect::<Result<_, _>>())
+        .with_context(|| format!("failed to read certificates from {}", config.cert_file()))?;
+    if certs.is_empty() {
+        anyhow::bail!("{} holds no certificates", config.cert_file());
+    }
+    let key = PrivateKeyDer::from_pem_file(config.key_file())
+        .with_context(|| format!("failed to read private key from {}", config.key_file()))?;
+    CertifiedKey::from_der(certs, key, &provider()).with_context(|| {
+        format!(
+            "{} doesn't go with {}",
+            config.key_file(),
+            config.cert_file()
+        )
+    })
+}
+
+fn provider() -> CryptoProvider {
+    ring::default_provider()
+}
+
+/// Hands out whichever certificate was loaded last, see the module docs.
+#[derive(Debug)]
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    // Constructor //
+    pub fn new(key: CertifiedKey) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(key)),
+        }
+    }
+
+    // Methods //
+    /// Serves `key` to every connection made from now on.
+    pub fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(key);
+    }
+
+    /// Checks `config`'s files for changes every [WATCH_INTERVAL], loading
+    /// them again when they do. Meant to be spawned once as a background task.
+    pub async fn watch(self: Arc<Self>, config: TlsConfig) {
+        let mut last_modified = modified(&config);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now_modified = modified(&config);
+            if now_modified == last_modified {
+                continue;
+            }
+            last_modified = now_modified;
+            match load_certified_key(&config) {
+                Ok(key) => {
+                    self.replace(key);
+                    info!("Reloaded TLS certificate from {}", config.cert_file());
+                }
+                Err(e) => error!("Keeping the current TLS certificate, {e:#}"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// When the certificate and key were last modified, if it can be told.
+fn modified(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+    [config.cert_file(), config.key_file()].map(|path| {
+        std::fs::metadata(Path::new(path))
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+}
+
+/// Accepts TLS connections for [axum::serve], with the certificate from a
+/// [CertResolver]. Handshakes happen in the background, so a slow client
+/// can't hold up everyone else.
+pub struct TlsListener {
+    tcp: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: JoinSet<Option<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+
+impl TlsListener {
+    // Constructor //
+    pub fn new(tcp: TcpListener, certs: Arc<CertResolver>) -> Result<Self> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(certs);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            tcp,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            handshakes: JoinSet::new(),
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                accepted = self.tcp.accept() => match accepted {
+                    Ok((stream, address)) => {
+                        let acceptor = self.acceptor.clone();
+                        self.handshakes.spawn(async move {
+                            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                                Ok(Ok(stream)) => Some((stream, address)),
+                                Ok(Err(e)) => {
+                                    debug!("TLS handshake with {address} failed: {e}");
+                                    None
+                                }
+                                Err(_) => {
+                                    debug!("TLS handshake with {address} timed out");
+                                    None
+                                }
+                            }
+                        });
+                    }
+                    // likely out of file descriptors, give it a moment
+                    Err(e) => {
+                        error!("Failed to accept connection: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                },
+                // anything that isn't a connection must still match, or the
+                // branch would be disabled until the next TCP connection
+                Some(handshake) = self.handshakes.join_next() => match handshake {
+                    Ok(Some(connection)) => return connection,
+                    Ok(None) => continue, // already logged
+                    Err(e) => {
+                        error!("TLS handshake task failed: {e}");
+                        continue;
+                    }
+                },
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.tcp.local_addr()
+    }
+}
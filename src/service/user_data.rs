@@ -0,0 +1,141 @@
+//! # User Data
+//! Per-user collections that grow with use, like playlists, starred items
+//! and the play queue, kept in the user data table with one record per
+//! username. They live apart from the account record so logging in doesn't
+//! have to load them.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use papaya::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    service::storage::{StorageBackend, Table},
+    services,
+};
+
+/// Global variable holding the singleton instance of [UserDataManager].
+pub static USER_DATA: LazyLock<UserDataManager> =
+    LazyLock::new(|| UserDataManager::from_storage(services::StorageService.clone()));
+
+/// Everything kept for one user.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserData {
+    #[serde(default)]
+    pub playlists: Vec<Playlist>,
+    /// ids of starred tracks, albums and artists, with the unix timestamp
+    /// they were starred at
+    #[serde(default)]
+    pub starred: BTreeMap<String, i64>,
+    /// what the user is listening to, so another of their devices can pick
+    /// up where they left off
+    #[serde(default)]
+    pub play_queue: Option<PlayQueue>,
+}
+
+/// An ordered list of tracks made by a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Playlist {
+    /// unique across every user
+    pub id: String,
+    pub name: String,
+    pub comment: Option<String>,
+    /// whether other users can see it
+    pub public: bool,
+    /// unix timestamps
+    pub created: i64,
+    pub changed: i64,
+    /// track ids, a track can appear more than once
+    pub tracks: Vec<String>,
+}
+
+impl Playlist {
+    pub fn new(name: String) -> Self {
+        let now: i64 = Utc::now().timestamp();
+        Self {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            name,
+            comment: None,
+            public: false,
+            created: now,
+            changed: now,
+            tracks: Vec::new(),
+        }
+    }
+}
+
+/// The tracks queued up on the device a user last saved it from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlayQueue {
+    /// track ids, in play order
+    pub tracks: Vec<String>,
+    /// the track playing, and how far into it in milliseconds
+    pub current: Option<String>,
+    pub position_ms: i64,
+    /// unix timestamp of when it was saved, and the client that saved it
+    pub changed: i64,
+    pub changed_by: Option<String>,
+}
+
+/// Keeps every user's [UserData] in memory and writes changes through to
+/// the storage backend.
+pub struct UserDataManager {
+    storage: Arc<dyn StorageBackend>,
+    users: HashMap<String, Arc<UserData>>,
+    /// serializes changes, so concurrent updates of one user don't race
+    write_lock: Mutex<()>,
+}
+
+impl UserDataManager {
+    // Constructor //
+    /// Loads the user data kept in `storage`.
+    pub fn from_storage(storage: Arc<dyn StorageBackend>) -> Self {
+        let users: HashMap<String, Arc<UserData>> = HashMap::new();
+        let stored: Vec<(String, UserData)> = storage
+            .load_records(Table::UserData)
+            .expect("Failed to load user data!");
+        for (username, data) in stored {
+            users.pin().insert(username, Arc::new(data));
+        }
+        Self {
+            storage,
+            users,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    // Methods //
+    /// The data of `username`, empty if nothing was saved for them yet.
+    pub fn get(&self, username: &str) -> Arc<UserData> {
+        self.users.pin().get(username).cloned().unwrap_or_default()
+    }
+
+    /// Every user with saved data, in no particular order.
+    pub fn all(&self) -> Vec<(String, Arc<UserData>)> {
+        self.users
+            .pin()
+            .iter()
+            .map(|(username, data)| (username.clone(), data.clone()))
+            .collect()
+    }
+
+    /// Applies `f` to a copy of the data of `username`, then saves and swaps
+    /// in the result. Nothing is saved if `f` fails.
+    pub fn update<T>(
+        &self,
+        username: &str,
+        f: impl FnOnce(&mut UserData) -> Result<T>,
+    ) -> Result<T> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut data: UserData = self.get(username).as_ref().clone();
+        let result: T = f(&mut data)?;
+        self.storage.put_record(Table::UserData, username, &data)?;
+        self.users.pin().insert(username.to_owned(), Arc::new(data));
+        Ok(result)
+    }
+}
@@ -0,0 +1,54 @@
+//! Runs the `orpheus` binary the way an admin would.
+
+use std::{path::PathBuf, process::Command, sync::Arc};
+
+use orpheus::{
+    service::{accounts::AccountsManager, storage::MemoryStorage},
+    types::ExportFormat,
+};
+
+#[test]
+fn test_account_export_to_stdout() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("orpheus-cli-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("orpheus.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[server]\ndata_dir = {:?}\nbind_address = \"127.0.0.1:0\"\n",
+            dir.join("data")
+        ),
+    )
+    .unwrap();
+    let accounts = AccountsManager::from_storage(Arc::new(MemoryStorage::default()));
+    accounts
+        .register("alice".into(), "password".into(), true)
+        .unwrap();
+    let file = dir.join("accounts.json");
+    std::fs::write(
+        &file,
+        ExportFormat::Json.encode(&accounts.export()).unwrap(),
+    )
+    .unwrap();
+
+    let orpheus = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_orpheus"))
+            .arg("--config")
+            .arg(&config)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(orpheus(&["account", "import", file.to_str().unwrap()])
+        .status
+        .success());
+    let output = orpheus(&["account", "export"]);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // logs go to stderr, so stdout is exactly the export and can be imported again
+    assert!(output.status.success());
+    let export = ExportFormat::Json
+        .decode(std::str::from_utf8(&output.stdout).unwrap())
+        .unwrap();
+    assert_eq!(export.accounts, accounts.export().accounts);
+}